@@ -0,0 +1,480 @@
+//! Automatic choice between DartMinHash, TreeMinHash and ERS.
+//!
+//! `DatasetProfile` summarizes a sample of the dataset: dimension, nonzeros,
+//! weight sums, weight skew and, if known, the ERS caps. `AutoSketcher` picks
+//! one sketcher from the profile following the guidance of the README:
+//!
+//! - ERS when caps are available, the data is dense (at least
+//!   [`DENSE_SPARSITY`] of the dimension is nonzero on average) and the caps
+//!   are tight enough that one draw is accepted with probability at least
+//!   [`MIN_ERS_ACCEPTANCE`];
+//! - TreeMinHash for large weight sums or heavily skewed weights;
+//! - DartMinHash otherwise.
+//!
+//! Every `AutoSketch` records the algorithm, hash family and a fingerprint of
+//! the sketcher that produced it, since sketches of different algorithms,
+//! families or seeds cannot be compared.
+
+use rand_core::RngCore;
+
+use crate::darthash::Dart;
+use crate::dartminhash::DartMinHash;
+use crate::hash_utils::{DefaultFamily, HashFamily, HashKind};
+use crate::persist::SketcherState;
+use crate::rejsmp::{CapPolicy, Densification, ErsWmh};
+use crate::rng_utils::{mt_from_seed_full, siphash24};
+use crate::similarity::jaccard_estimate_from_minhashes;
+use crate::treeminhash::TreeMinHash;
+
+/// Mean fraction of nonzero dimensions from which ERS beats the dart and
+/// tree sketchers (d / D of about 5%, see the README).
+pub const DENSE_SPARSITY: f64 = 0.05;
+
+/// Smallest expected acceptance rate `mass / M` of a single ERS draw. Below
+/// it, loose caps make ERS draw too often per bucket.
+pub const MIN_ERS_ACCEPTANCE: f64 = 0.01;
+
+/// Mean weight sum from which TreeMinHash is preferred, e.g. for raw counts.
+pub const LARGE_WEIGHT_SUM: f64 = 1e6;
+
+/// Ratio of the largest weight to the mean nonzero weight from which
+/// TreeMinHash is preferred.
+pub const HIGH_WEIGHT_SKEW: f64 = 1e6;
+
+/// Probability that an ERS bucket needs densification, see
+/// `ErsWmh::sketch_auto`.
+const ERS_EMPTY_PROB: f64 = 1e-3;
+
+/// Statistics of a dataset sample, filled one vector at a time.
+#[derive(Clone, Debug)]
+pub struct DatasetProfile {
+    dimension: u64,
+    vectors: u64,
+    nonzeros: u64,
+    weight_sum: f64,
+    max_weight_sum: f64,
+    max_weight: f64,
+    caps: Option<Vec<(u64, f64)>>,
+}
+
+impl DatasetProfile {
+    /// `dimension`: size D of the id universe.
+    pub fn new(dimension: u64) -> Self {
+        assert!(dimension > 0, "dimension must be positive");
+        Self {
+            dimension,
+            vectors: 0,
+            nonzeros: 0,
+            weight_sum: 0.0,
+            max_weight_sum: 0.0,
+            max_weight: 0.0,
+            caps: None,
+        }
+    }
+
+    pub fn from_samples(dimension: u64, samples: &[Vec<(u64, f64)>]) -> Self {
+        let mut profile = Self::new(dimension);
+        for x in samples {
+            profile.add(x);
+        }
+        profile
+    }
+
+    /// Make ERS available with these `(id, cap)` pairs, e.g. from
+    /// `CapsBuilder::sparse_caps`.
+    pub fn with_caps(mut self, caps: Vec<(u64, f64)>) -> Self {
+        self.caps = Some(caps);
+        self
+    }
+
+    /// Update the statistics with one vector. Non-positive weights are ignored.
+    pub fn add(&mut self, x: &[(u64, f64)]) {
+        let mut sum = 0.0;
+        for &(_, w) in x {
+            if w <= 0.0 || !w.is_finite() {
+                continue;
+            }
+            self.nonzeros += 1;
+            sum += w;
+            self.max_weight = self.max_weight.max(w);
+        }
+        self.vectors += 1;
+        self.weight_sum += sum;
+        self.max_weight_sum = self.max_weight_sum.max(sum);
+    }
+
+    #[inline]
+    pub fn dimension(&self) -> u64 {
+        self.dimension
+    }
+
+    #[inline]
+    pub fn vectors(&self) -> u64 {
+        self.vectors
+    }
+
+    #[inline]
+    pub fn caps(&self) -> Option<&[(u64, f64)]> {
+        self.caps.as_deref()
+    }
+
+    /// Mean number of nonzeros per vector.
+    pub fn mean_nonzeros(&self) -> f64 {
+        if self.vectors == 0 {
+            0.0
+        } else {
+            self.nonzeros as f64 / self.vectors as f64
+        }
+    }
+
+    /// Mean nonzeros over the dimension, d / D.
+    pub fn sparsity(&self) -> f64 {
+        self.mean_nonzeros() / self.dimension as f64
+    }
+
+    /// Mean weight sum per vector.
+    pub fn mean_weight_sum(&self) -> f64 {
+        if self.vectors == 0 {
+            0.0
+        } else {
+            self.weight_sum / self.vectors as f64
+        }
+    }
+
+    #[inline]
+    pub fn max_weight_sum(&self) -> f64 {
+        self.max_weight_sum
+    }
+
+    /// Largest weight over the mean nonzero weight (0 without nonzeros).
+    pub fn weight_skew(&self) -> f64 {
+        if self.nonzeros == 0 {
+            0.0
+        } else {
+            self.max_weight / (self.weight_sum / self.nonzeros as f64)
+        }
+    }
+
+    /// Expected acceptance rate of one ERS draw for an average vector,
+    /// `mean weight sum / M`, or `None` without caps.
+    pub fn ers_acceptance(&self) -> Option<f64> {
+        let m_total: f64 = self.caps()?.iter().map(|&(_, m)| m).sum();
+        Some(if m_total > 0.0 {
+            self.mean_weight_sum() / m_total
+        } else {
+            0.0
+        })
+    }
+}
+
+/// Sketching algorithm chosen by `AutoSketcher`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    DartMinHash,
+    TreeMinHash,
+    ErsWmh,
+}
+
+/// How a sketch was produced. Two sketches are comparable only if their
+/// metadata is equal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SketchMetadata {
+    pub algorithm: Algorithm,
+    pub hash_family: HashKind,
+    pub k: u64,
+    /// ERS sketched the input with its TreeMinHash fallback, because a
+    /// weight exceeded its cap.
+    pub fallback: bool,
+    /// Hash of the sketcher state, see `AutoSketcher::fingerprint`.
+    pub fingerprint: u64,
+}
+
+/// A sketch together with the metadata of its algorithm.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AutoSketch {
+    pub metadata: SketchMetadata,
+    pub minhashes: Vec<Dart>,
+}
+
+impl AutoSketch {
+    /// Weighted Jaccard estimate, or `None` if the metadata differs: the
+    /// sketches come from different sketchers, or only one of them is an ERS
+    /// fallback sketch.
+    pub fn jaccard_estimate(&self, other: &AutoSketch) -> Option<f64> {
+        (self.metadata == other.metadata)
+            .then(|| jaccard_estimate_from_minhashes(&self.minhashes, &other.minhashes))
+    }
+}
+
+enum Sketcher<F: HashFamily> {
+    Dart(Box<DartMinHash<F>>),
+    Tree(TreeMinHash<F>),
+    Ers(ErsWmh<F>),
+}
+
+/// Sketcher picked and configured from a `DatasetProfile`.
+pub struct AutoSketcher<F: HashFamily = DefaultFamily> {
+    k: u64,
+    algorithm: Algorithm,
+    hash_family: HashKind,
+    fingerprint: u64,
+    sketcher: Sketcher<F>,
+}
+
+impl AutoSketcher {
+    /// The algorithm `new` picks for `profile`.
+    pub fn choose(profile: &DatasetProfile) -> Algorithm {
+        let dense = profile.sparsity() >= DENSE_SPARSITY;
+        let tight_caps = profile
+            .ers_acceptance()
+            .is_some_and(|acceptance| acceptance >= MIN_ERS_ACCEPTANCE);
+        if dense && tight_caps {
+            Algorithm::ErsWmh
+        } else if profile.mean_weight_sum() >= LARGE_WEIGHT_SUM
+            || profile.weight_skew() >= HIGH_WEIGHT_SKEW
+        {
+            Algorithm::TreeMinHash
+        } else {
+            Algorithm::DartMinHash
+        }
+    }
+
+    pub fn new<R: RngCore + ?Sized>(rng: &mut R, profile: &DatasetProfile, k: u64) -> Self {
+        Self::with_algorithm(rng, profile, k, Self::choose(profile))
+    }
+
+    /// Like `new`, drawing the chosen sketcher from `mt_from_seed_full(seed)`.
+    pub fn from_seed(seed: u64, profile: &DatasetProfile, k: u64) -> Self {
+        Self::new(&mut mt_from_seed_full(seed), profile, k)
+    }
+
+    /// Build a given algorithm, configured from `profile`. ERS requires caps.
+    ///
+    /// ERS chooses L per input with empty-bucket probability 1e-3, uses
+    /// optimal densification and sketches inputs above their caps with a
+    /// TreeMinHash fallback.
+    pub fn with_algorithm<R: RngCore + ?Sized>(
+        rng: &mut R,
+        profile: &DatasetProfile,
+        k: u64,
+        algorithm: Algorithm,
+    ) -> Self {
+        Self::with_algorithm_and_family(rng, profile, k, algorithm, DefaultFamily::default())
+    }
+}
+
+impl<F: HashFamily> AutoSketcher<F> {
+    /// Like `new`, drawing the hash functions from `family`.
+    pub fn with_family<R: RngCore + ?Sized>(
+        rng: &mut R,
+        profile: &DatasetProfile,
+        k: u64,
+        family: F,
+    ) -> Self {
+        let algorithm = AutoSketcher::choose(profile);
+        Self::with_algorithm_and_family(rng, profile, k, algorithm, family)
+    }
+
+    pub fn with_algorithm_and_family<R: RngCore + ?Sized>(
+        rng: &mut R,
+        profile: &DatasetProfile,
+        k: u64,
+        algorithm: Algorithm,
+        family: F,
+    ) -> Self {
+        let hash_family = family.kind();
+        let sketcher = match algorithm {
+            Algorithm::DartMinHash => {
+                Sketcher::Dart(Box::new(DartMinHash::with_family(rng, k, family)))
+            }
+            Algorithm::TreeMinHash => Sketcher::Tree(TreeMinHash::with_family(rng, k, family)),
+            Algorithm::ErsWmh => {
+                let caps = profile.caps().expect("ERS requires caps in the profile");
+                let ers = ErsWmh::new_sparse_with_family(rng, caps, k, family.clone());
+                let fallback = TreeMinHash::with_family(rng, k, family);
+                Sketcher::Ers(
+                    ers.with_densification(Densification::Optimal)
+                        .with_cap_policy(CapPolicy::Fallback(Box::new(fallback))),
+                )
+            }
+        };
+        let state = match &sketcher {
+            Sketcher::Dart(dmh) => dmh.to_bytes(),
+            Sketcher::Tree(tmh) => tmh.to_bytes(),
+            Sketcher::Ers(ers) => ers.to_bytes(),
+        };
+        Self {
+            k,
+            algorithm,
+            hash_family,
+            fingerprint: siphash24(&[0; 16], &state),
+            sketcher,
+        }
+    }
+
+    #[inline]
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    #[inline]
+    pub fn hash_family(&self) -> HashKind {
+        self.hash_family
+    }
+
+    #[inline]
+    pub fn k(&self) -> u64 {
+        self.k
+    }
+
+    /// SipHash-2-4 of the `SketcherState` of the chosen sketcher. Sketchers
+    /// built from different seeds, caps or families differ in it.
+    #[inline]
+    pub fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+
+    pub fn sketch(&self, x: &[(u64, f64)]) -> AutoSketch {
+        let (minhashes, fallback) = match &self.sketcher {
+            Sketcher::Dart(dmh) => (dmh.sketch(x), false),
+            Sketcher::Tree(tmh) => (tmh.sketch(x), false),
+            Sketcher::Ers(ers) => {
+                let (minhashes, stats) = ers.sketch_auto_with_stats(x, ERS_EMPTY_PROB);
+                (minhashes, stats.fallback)
+            }
+        };
+        AutoSketch {
+            metadata: SketchMetadata {
+                algorithm: self.algorithm,
+                hash_family: self.hash_family,
+                k: self.k,
+                fallback,
+                fingerprint: self.fingerprint,
+            },
+            minhashes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{
+        auto_sketcher::{Algorithm, AutoSketcher, DatasetProfile},
+        caps::CapsBuilder,
+        hash_utils::{HashKind, MixedTab, SimpleTab},
+        rng_utils::mt_from_seed,
+        similarity::jaccard_similarity,
+        test_utils::{assert_estimate_close, generate_weighted_set_in},
+    };
+
+    /// Scale the weights of the odd ids of x by `rel`. Stays within caps
+    /// built from x.
+    fn scale_odd_ids(x: &[(u64, f64)], rel: f64) -> Vec<(u64, f64)> {
+        x.iter()
+            .map(|&(id, w)| (id, if id % 2 == 1 { w * rel } else { w }))
+            .collect()
+    }
+
+    #[test]
+    fn auto_sketcher_picks_by_profile() {
+        let mut data_rng = mt_from_seed(4545);
+        let k = 512u64;
+
+        // (dimension, nonzeros, weight sum, caps)
+        let cases = [
+            (1u64 << 32, 1_000, 100.0, false, Algorithm::DartMinHash),
+            (1 << 32, 1_000, 1e9, false, Algorithm::TreeMinHash),
+            (2_000, 400, 100.0, true, Algorithm::ErsWmh),
+            // Caps alone do not make sparse data a case for ERS.
+            (1 << 32, 1_000, 100.0, true, Algorithm::DartMinHash),
+        ];
+        for (i, &(d, l0, l1, with_caps, expected)) in cases.iter().enumerate() {
+            let samples: Vec<_> = (0..20)
+                .map(|_| generate_weighted_set_in(d, l0, l1, &mut data_rng))
+                .collect();
+            let mut profile = DatasetProfile::from_samples(d, &samples);
+            if with_caps {
+                let mut caps = CapsBuilder::new();
+                samples.iter().for_each(|x| caps.add(x));
+                profile = profile.with_caps(caps.sparse_caps());
+            }
+            assert_eq!(AutoSketcher::choose(&profile), expected, "{profile:?}");
+
+            let auto = AutoSketcher::new(&mut mt_from_seed(0xa070_0000 + i as u64), &profile, k);
+            assert_eq!(auto.algorithm(), expected);
+
+            // Estimates stay accurate whichever algorithm was chosen.
+            let x = &samples[0];
+            for rel in [0.9, 0.5, 0.1] {
+                let y = scale_odd_ids(x, rel);
+                let (sx, sy) = (auto.sketch(x), auto.sketch(&y));
+                assert_eq!(sx.metadata.algorithm, expected);
+                assert!(!sx.metadata.fallback && !sy.metadata.fallback);
+                assert_eq!(sx.metadata.k, k);
+                let j = jaccard_similarity(x, &y);
+                let est = sx.jaccard_estimate(&sy).unwrap();
+                assert_estimate_close(j, est, k, format!("{expected:?}"));
+            }
+        }
+    }
+
+    #[test]
+    fn auto_sketch_records_ers_fallback() {
+        let mut data_rng = mt_from_seed(4546);
+        let d = 1_000u64;
+        let samples: Vec<_> = (0..10)
+            .map(|_| generate_weighted_set_in(d, 300, 50.0, &mut data_rng))
+            .collect();
+        let mut caps = CapsBuilder::new();
+        samples.iter().for_each(|x| caps.add(x));
+        let profile = DatasetProfile::from_samples(d, &samples).with_caps(caps.sparse_caps());
+        let auto = AutoSketcher::new(&mut mt_from_seed(0xa070_0010), &profile, 128);
+        assert_eq!(auto.algorithm(), Algorithm::ErsWmh);
+
+        let sketch = auto.sketch(&samples[0]);
+        assert!(!sketch.metadata.fallback);
+        let mut over = samples[0].clone();
+        over[0].1 *= 1e3;
+        let over_sketch = auto.sketch(&over);
+        assert!(over_sketch.metadata.fallback);
+        // Drift into the fallback is data, not a bug: no estimate, no panic.
+        assert_eq!(sketch.jaccard_estimate(&over_sketch), None);
+        assert!(over_sketch.jaccard_estimate(&over_sketch).is_some());
+    }
+
+    #[test]
+    fn auto_sketches_of_different_algorithms_do_not_compare() {
+        let x = vec![(1, 1.0), (2, 2.0)];
+        let profile = DatasetProfile::from_samples(1 << 20, std::slice::from_ref(&x));
+        let mut rng = mt_from_seed(0xa070_0020);
+        let dart = AutoSketcher::with_algorithm(&mut rng, &profile, 64, Algorithm::DartMinHash);
+        let tree = AutoSketcher::with_algorithm(&mut rng, &profile, 64, Algorithm::TreeMinHash);
+        assert_eq!(dart.sketch(&x).jaccard_estimate(&tree.sketch(&x)), None);
+    }
+
+    #[test]
+    fn auto_sketches_of_different_hash_families_do_not_compare() {
+        let x = vec![(1, 1.0), (2, 2.0)];
+        let profile = DatasetProfile::from_samples(1 << 20, std::slice::from_ref(&x));
+        let simple = AutoSketcher::with_family(&mut mt_from_seed(1), &profile, 64, SimpleTab);
+        let mixed = AutoSketcher::with_family(&mut mt_from_seed(1), &profile, 64, MixedTab);
+        assert_eq!(simple.algorithm(), mixed.algorithm());
+        assert_eq!(mixed.sketch(&x).metadata.hash_family, HashKind::MixedTab);
+        assert_eq!(simple.sketch(&x).jaccard_estimate(&mixed.sketch(&x)), None);
+    }
+
+    #[test]
+    fn auto_sketches_of_different_seeds_do_not_compare() {
+        let x = vec![(1, 1.0), (2, 2.0)];
+        let profile = DatasetProfile::from_samples(1 << 20, std::slice::from_ref(&x));
+        let a = AutoSketcher::from_seed(1, &profile, 64);
+        let b = AutoSketcher::from_seed(2, &profile, 64);
+        assert_ne!(a.fingerprint(), b.fingerprint());
+        assert_eq!(a.sketch(&x).jaccard_estimate(&b.sketch(&x)), None);
+
+        let a2 = AutoSketcher::from_seed(1, &profile, 64);
+        assert_eq!(a2.fingerprint(), a.fingerprint());
+        assert_eq!(a.sketch(&x).jaccard_estimate(&a2.sketch(&x)), Some(1.0));
+    }
+}
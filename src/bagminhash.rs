@@ -0,0 +1,378 @@
+//! BagMinHash for weighted Jaccard sketching.
+//!
+//! Rust port of Otmar Ertl's BagMinHash (KDD 2018), sharing the weight-interval
+//! tree and tabulation random streams of [`crate::treeminhash`]:
+//!
+//! - every feature `(id, w)` owns a Poisson process of points on
+//!   `[0, w) x [0, inf)`; the weight axis is split into exponentially spaced
+//!   intervals arranged in a binary tree, so only intervals below `w` are ever
+//!   expanded;
+//! - every point is assigned a uniformly random register and the register keeps
+//!   the point with the smallest time;
+//! - points are generated in increasing time order through a priority queue and
+//!   the search stops as soon as the next point is larger than the current
+//!   maximum over all registers (tracked by a small segment tree).
+//!
+//! No weight caps are needed. Sketches are `Vec<(u64, f64)>` of
+//! `(fingerprint, time)`, where the fingerprint hashes the accepted sample
+//! `(feature_id, time)` exactly as TreeMinHash does.
+
+use rand_core::RngCore;
+
+use crate::dartminhash::MinHashSketch;
+use crate::hash_utils::*;
+use crate::rng_utils::mt_from_seed_full;
+use crate::scratch::{Scratch, reset_sketch};
+use crate::treeminhash::{Node, TabStream, pre_calculate_tree};
+use std::cmp::Ordering;
+
+/// Tracks the maximum over k register values with O(log k) updates.
+///
+/// Leaves live at `values[k..2k]`; every internal node stores the max of its two
+/// children, so `values[1]` is the maximum over all registers.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MaxValueTracker {
+    k: usize,
+    values: Vec<f64>,
+}
+
+impl MaxValueTracker {
+    /// Reset to `k` empty registers, reusing the allocation.
+    pub(crate) fn reset(&mut self, k: usize) {
+        assert!(k > 0, "k must be positive");
+        self.k = k;
+        self.values.clear();
+        self.values.resize(2 * k, f64::INFINITY);
+    }
+
+    /// Lower register `idx` to `value` and propagate the new maxima upward.
+    #[inline]
+    pub(crate) fn update(&mut self, idx: usize, value: f64) {
+        let mut pos = self.k + idx;
+        self.values[pos] = value;
+        while pos > 1 {
+            let parent = pos >> 1;
+            let m = self.values[2 * parent].max(self.values[2 * parent + 1]);
+            if self.values[parent] == m {
+                break;
+            }
+            self.values[parent] = m;
+            pos = parent;
+        }
+    }
+
+    #[inline]
+    pub(crate) fn max(&self) -> f64 {
+        self.values[1]
+    }
+}
+
+/// One pending point of a tree node: the node's process has its next point at
+/// `point`, and its random stream `(id, stream_id)` continues at `counter`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PendingPoint {
+    point: f64,
+    node_idx: u32,
+    stream_id: u32,
+    counter: u64,
+}
+
+impl PartialEq for PendingPoint {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for PendingPoint {}
+
+impl PartialOrd for PendingPoint {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PendingPoint {
+    // Reversed so `BinaryHeap` pops the smallest point first.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .point
+            .total_cmp(&self.point)
+            .then_with(|| other.node_idx.cmp(&self.node_idx))
+    }
+}
+
+/// BagMinHash sketcher for weighted Jaccard similarity.
+pub struct BagMinHash<F: HashFamily = DefaultFamily> {
+    k: u32,
+    family: F,
+    h0: F::H64,
+    h1: F::H64,
+    sample_id_hasher: F::H64,
+    sample_point_hasher: F::H64,
+    tree: Vec<Node>,
+    num_non_leaf_nodes: u32,
+}
+
+impl BagMinHash {
+    /// Build with MT19937-seeded tabulation hash tables.
+    ///
+    /// Uses the same tree defaults as `TreeMinHash::new_mt`: `max = f64::MAX`
+    /// and `factor = 0.5`.
+    pub fn new<R: RngCore + ?Sized>(rng: &mut R, k: u64) -> Self {
+        Self::with_params(rng, k, f64::MAX, 0.5)
+    }
+
+    /// Like `new`, with the tables drawn from `mt_from_seed_full(seed)`.
+    pub fn from_seed(seed: u64, k: u64) -> Self {
+        Self::new(&mut mt_from_seed_full(seed), k)
+    }
+
+    pub fn with_params<R: RngCore + ?Sized>(rng: &mut R, k: u64, max: f64, factor: f64) -> Self {
+        Self::with_params_and_family(rng, k, max, factor, DefaultFamily::default())
+    }
+}
+
+impl<F: HashFamily> BagMinHash<F> {
+    /// Like `new`, drawing the hash functions from `family`.
+    pub fn with_family<R: RngCore + ?Sized>(rng: &mut R, k: u64, family: F) -> Self {
+        Self::with_params_and_family(rng, k, f64::MAX, 0.5, family)
+    }
+
+    pub fn with_params_and_family<R: RngCore + ?Sized>(
+        rng: &mut R,
+        k: u64,
+        max: f64,
+        factor: f64,
+        family: F,
+    ) -> Self {
+        assert!(k > 0 && k <= (u32::MAX as u64), "k must fit into u32");
+
+        let tree = pre_calculate_tree(factor, max);
+        let num_non_leaf_nodes = (tree.len() - tree.len().div_ceil(2)) as u32;
+
+        let h0 = family.hash64_from_rng(rng);
+        let h1 = family.hash64_from_rng(rng);
+        let sample_id_hasher = family.hash64_from_rng(rng);
+        let sample_point_hasher = family.hash64_from_rng(rng);
+
+        Self {
+            k: k as u32,
+            family,
+            h0,
+            h1,
+            sample_id_hasher,
+            sample_point_hasher,
+            tree,
+            num_non_leaf_nodes,
+        }
+    }
+
+    #[inline]
+    pub fn hash_family(&self) -> HashKind {
+        self.family.kind()
+    }
+
+    /// Return k weighted MinHash slots of `(fingerprint, time)`.
+    pub fn sketch(&self, x: &[(u64, f64)]) -> MinHashSketch {
+        let mut result = Vec::with_capacity(self.k as usize);
+        self.sketch_into(x, &mut Scratch::new(), &mut result);
+        result
+    }
+
+    /// Like `sketch`, writing into `result` and reusing the buffers of
+    /// `scratch`, see [`crate::scratch`].
+    pub fn sketch_into(&self, x: &[(u64, f64)], scratch: &mut Scratch, result: &mut MinHashSketch) {
+        reset_sketch(result, self.k as usize);
+        let tracker = &mut scratch.tracker;
+        tracker.reset(self.k as usize);
+        let heap = &mut scratch.pending;
+        heap.clear();
+        heap.reserve(self.num_non_leaf_nodes as usize);
+
+        for &(id, w) in x {
+            if w <= 0.0 || !w.is_finite() {
+                continue;
+            }
+
+            let mut rng = self.rng(id, 0, 0);
+            let root_point = rng.exponential1() * self.tree[0].inv_rate;
+            if root_point < tracker.max() {
+                heap.push(PendingPoint {
+                    point: root_point,
+                    node_idx: 0,
+                    stream_id: 0,
+                    counter: rng.counter(),
+                });
+            }
+
+            while let Some(pending) = heap.pop() {
+                let limit = tracker.max();
+                if pending.point >= limit {
+                    // Every remaining point of this feature is even larger.
+                    heap.clear();
+                    break;
+                }
+
+                let point = pending.point;
+                let node_idx = pending.node_idx;
+                let node = self.tree[node_idx as usize];
+                let mut rng = self.rng(id, pending.stream_id, pending.counter);
+
+                if node_idx < self.num_non_leaf_nodes {
+                    // Split the node's process: the current point goes to one
+                    // child, the sibling gets its own first point.
+                    let inherit_to_left = rng.bernoulli(node.ratio);
+                    let child_idx = 2 * node_idx + 2 - (inherit_to_left as u32);
+                    let sibling_idx = 2 * node_idx + 1 + (inherit_to_left as u32);
+
+                    let sibling_node = self.tree[sibling_idx as usize];
+                    let sibling_point = point + rng.exponential1() * sibling_node.inv_rate;
+
+                    if self.tree[child_idx as usize].lower_bound < w {
+                        heap.push(PendingPoint {
+                            point,
+                            node_idx: child_idx,
+                            stream_id: pending.stream_id,
+                            counter: rng.counter(),
+                        });
+                    }
+                    if sibling_point < limit && sibling_node.lower_bound < w {
+                        heap.push(PendingPoint {
+                            point: sibling_point,
+                            node_idx: sibling_idx,
+                            stream_id: sibling_idx,
+                            counter: 0,
+                        });
+                    }
+                } else {
+                    // Leaf: the point lands in a uniformly random register and is
+                    // kept if its weight coordinate falls below w. Both draws are
+                    // always consumed so streams stay aligned across inputs.
+                    let acceptance_probability = (w - node.lower_bound) * node.inv_rate;
+                    let idx = rng.uniform_index(self.k) as usize;
+                    let accepted = rng.uniform_open01() < acceptance_probability;
+                    if accepted && point < result[idx].1 {
+                        result[idx] = (self.sample_fingerprint(id, point), point);
+                        tracker.update(idx, point);
+                    }
+
+                    let next_point = point + rng.exponential1() * node.inv_rate;
+                    if next_point < tracker.max() {
+                        heap.push(PendingPoint {
+                            point: next_point,
+                            node_idx,
+                            stream_id: pending.stream_id,
+                            counter: rng.counter(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Return a one-bit BagMinHash sketch compatible with
+    /// `onebit_minhash_jaccard_estimate`.
+    pub fn onebit_minhash(&self, x: &[(u64, f64)]) -> Vec<bool> {
+        self.sketch(x)
+            .into_iter()
+            .map(|(fingerprint, _)| (fingerprint & 1) == 1)
+            .collect()
+    }
+
+    #[inline]
+    fn sample_fingerprint(&self, id: u64, point: f64) -> u64 {
+        self.sample_id_hasher.hash(id) ^ self.sample_point_hasher.hash(point.to_bits())
+    }
+
+    #[inline]
+    fn rng(&self, id: u64, stream_id: u32, counter: u64) -> TabStream<'_, F::H64> {
+        TabStream::resume(&self.h0, &self.h1, id, stream_id as u64, counter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{
+        bagminhash::{BagMinHash, MaxValueTracker},
+        rng_utils::mt_from_seed,
+        similarity::{jaccard_estimate_from_minhashes, jaccard_similarity},
+        test_utils::{assert_estimate_close, generate_similar_weighted_set, generate_weighted_set},
+    };
+
+    #[test]
+    fn bagminhash_approximates_weighted_jaccard() {
+        let mut data_rng = mt_from_seed(1337);
+        let mut hash_rng = mt_from_seed(0xba90_0001);
+
+        let l0 = 50_000;
+        let l1 = 10_000.0;
+        let k = 4096;
+
+        let bmh = BagMinHash::new(&mut hash_rng, k);
+        let x = generate_weighted_set(l0, l1, &mut data_rng);
+        assert_eq!(x.len(), l0 as usize);
+
+        let targets = [
+            0.99, 0.96, 0.93, 0.9, 0.85, 0.8, 0.75, 0.7, 0.65, 0.6, 0.55, 0.5, 0.4, 0.3, 0.2, 0.1,
+            0.05, 0.01,
+        ];
+
+        for &rel in &targets {
+            let y = generate_similar_weighted_set(&x, rel, &mut data_rng);
+            let j_true = jaccard_similarity(&x, &y);
+
+            let sk_x = bmh.sketch(&x);
+            let sk_y = bmh.sketch(&y);
+            assert_eq!(sk_x.len(), k as usize);
+            assert_eq!(sk_y.len(), k as usize);
+
+            let j_est = jaccard_estimate_from_minhashes(&sk_x, &sk_y);
+            assert_estimate_close(j_true, j_est, k, format!("BMH: rel_overlap={rel}"));
+        }
+    }
+
+    #[test]
+    fn bagminhash_is_deterministic_and_handles_empty_input() {
+        let x = vec![(1, 0.25), (3, 0.75), (10, 1.5), (42, 0.125)];
+        let mut rng1 = mt_from_seed(7);
+        let mut rng2 = mt_from_seed(7);
+        let bmh1 = BagMinHash::new(&mut rng1, 256);
+        let bmh2 = BagMinHash::new(&mut rng2, 256);
+        let sk = bmh1.sketch(&x);
+        assert_eq!(sk, bmh2.sketch(&x));
+        assert!(sk.iter().all(|&(_, r)| r.is_finite()));
+
+        let empty = bmh1.sketch(&[(1, 0.0), (2, -1.0)]);
+        assert!(empty.iter().all(|&(id, r)| id == 0 && r.is_infinite()));
+    }
+
+    #[test]
+    fn bagminhash_distinguishes_shared_id_different_weight_samples() {
+        let mut rng = mt_from_seed(19);
+        let bmh = BagMinHash::new(&mut rng, 4096);
+
+        let x = vec![(1, 10.0)];
+        let y = vec![(1, 5.0)];
+        let j_est = jaccard_estimate_from_minhashes(&bmh.sketch(&x), &bmh.sketch(&y));
+        assert!(
+            (j_est - 0.5).abs() <= 0.05,
+            "single shared id with unequal weights: est={j_est:.6}"
+        );
+    }
+
+    #[test]
+    fn max_value_tracker_follows_register_updates() {
+        let mut tracker = MaxValueTracker::default();
+        tracker.reset(5);
+        assert!(tracker.max().is_infinite());
+        for (i, v) in [3.0, 1.0, 4.0, 1.5, 2.0].into_iter().enumerate() {
+            tracker.update(i, v);
+        }
+        assert_eq!(tracker.max(), 4.0);
+        tracker.update(2, 0.5);
+        assert_eq!(tracker.max(), 3.0);
+        tracker.reset(3);
+        assert!(tracker.max().is_infinite());
+    }
+}
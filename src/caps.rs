@@ -0,0 +1,269 @@
+//! Streaming construction of tight ERS caps.
+//!
+//! ERS needs per-dimension caps `m_i >= x_i(s)` for every vector `s` it will
+//! sketch, and is most efficient when they are tight: `m_i = max_s x_i(s)`.
+//! `CapsBuilder` consumes vectors one at a time, tracks these maxima over
+//! arbitrary `u64` ids, and can be filled on several threads and merged.
+//! An optional relative margin inflates the caps, e.g. to leave room for
+//! vectors that arrive after the index is built.
+//!
+//! The builder also records the total mass of the vectors it has seen, which
+//! gives the expected acceptance rate `mass / M` of a single ERS draw.
+
+use std::collections::HashMap;
+
+use rand_core::RngCore;
+
+use crate::rejsmp::{ErsWmh, RedGreenIndex};
+
+/// Summary of the caps and of the vectors seen so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CapsStats {
+    /// Number of vectors added.
+    pub vectors: u64,
+    /// Number of ids with a positive cap.
+    pub dims: usize,
+    /// Total cap mass M = Σ m_i (after the margin).
+    pub m_total: f64,
+    /// Mean mass Σ_i x_i(s) of the added vectors.
+    pub mean_mass: f64,
+    /// Smallest mass among the added vectors (0 if none).
+    pub min_mass: f64,
+    /// Expected acceptance rate of one draw for an average vector,
+    /// `mean_mass / M`.
+    pub expected_acceptance: f64,
+}
+
+/// Tracks `m_i = max_s x_i(s)` over a stream of vectors.
+#[derive(Clone, Debug, Default)]
+pub struct CapsBuilder {
+    caps: HashMap<u64, f64>,
+    margin: f64,
+    vectors: u64,
+    mass_sum: f64,
+    min_mass: f64,
+}
+
+impl CapsBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inflate every cap by the relative `margin`: `m_i * (1 + margin)`.
+    pub fn with_margin(mut self, margin: f64) -> Self {
+        assert!(
+            margin >= 0.0 && margin.is_finite(),
+            "margin must be finite and non-negative"
+        );
+        self.margin = margin;
+        self
+    }
+
+    #[inline]
+    pub fn margin(&self) -> f64 {
+        self.margin
+    }
+
+    /// Update the caps with one vector. Non-positive weights are ignored.
+    pub fn add(&mut self, x: &[(u64, f64)]) {
+        let mut mass = 0.0;
+        for &(id, w) in x {
+            if w <= 0.0 || !w.is_finite() {
+                continue;
+            }
+            mass += w;
+            let m = self.caps.entry(id).or_insert(0.0);
+            if w > *m {
+                *m = w;
+            }
+        }
+        self.min_mass = if self.vectors == 0 {
+            mass
+        } else {
+            self.min_mass.min(mass)
+        };
+        self.vectors += 1;
+        self.mass_sum += mass;
+    }
+
+    /// Combine with a builder filled from another part of the dataset.
+    /// The margin of `self` is kept.
+    pub fn merge(&mut self, other: &CapsBuilder) {
+        for (&id, &w) in &other.caps {
+            let m = self.caps.entry(id).or_insert(0.0);
+            if w > *m {
+                *m = w;
+            }
+        }
+        if other.vectors > 0 {
+            self.min_mass = if self.vectors == 0 {
+                other.min_mass
+            } else {
+                self.min_mass.min(other.min_mass)
+            };
+        }
+        self.vectors += other.vectors;
+        self.mass_sum += other.mass_sum;
+    }
+
+    /// Caps with the margin applied, sorted by id.
+    pub fn sparse_caps(&self) -> Vec<(u64, f64)> {
+        let scale = 1.0 + self.margin;
+        let mut caps: Vec<(u64, f64)> = self.caps.iter().map(|(&id, &m)| (id, m * scale)).collect();
+        caps.sort_by_key(|&(id, _)| id);
+        caps
+    }
+
+    /// Caps with the margin applied as a dense vector over ids `[0, d)`.
+    pub fn dense_caps(&self, d: usize) -> Vec<f64> {
+        let scale = 1.0 + self.margin;
+        let mut caps = vec![0.0f64; d];
+        for (&id, &m) in &self.caps {
+            assert!((id as usize) < d, "id {id} out of range for d = {d}");
+            caps[id as usize] = m * scale;
+        }
+        caps
+    }
+
+    pub fn stats(&self) -> CapsStats {
+        // Summed in id order, exactly like the index built from these caps.
+        let m_total = self.sparse_caps().iter().map(|&(_, m)| m).sum::<f64>();
+        let mean_mass = if self.vectors > 0 {
+            self.mass_sum / self.vectors as f64
+        } else {
+            0.0
+        };
+        CapsStats {
+            vectors: self.vectors,
+            dims: self.caps.len(),
+            m_total,
+            mean_mass,
+            min_mass: self.min_mass,
+            expected_acceptance: if m_total > 0.0 {
+                mean_mass / m_total
+            } else {
+                0.0
+            },
+        }
+    }
+
+    /// Index over the sparse caps, see [`RedGreenIndex::from_sparse_caps`].
+    pub fn build_index(&self) -> RedGreenIndex {
+        RedGreenIndex::from_sparse_caps(&self.sparse_caps())
+    }
+
+    /// Index over dense caps for ids in `[0, d)`.
+    pub fn build_dense_index(&self, d: usize) -> RedGreenIndex {
+        RedGreenIndex::from_caps(&self.dense_caps(d))
+    }
+
+    /// ERS sketcher over the sparse caps.
+    pub fn build_ers<R: RngCore + ?Sized>(&self, rng: &mut R, k: u64) -> ErsWmh {
+        ErsWmh::from_index(rng, self.build_index(), k)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand_core::RngCore;
+
+    use crate::{
+        caps::CapsBuilder,
+        rejsmp::ErsWmh,
+        rng_utils::{MtRng, mt_from_seed},
+        similarity::jaccard_similarity,
+        test_utils::{assert_estimate_close, uniform01},
+    };
+
+    /// Random sparse vector with `nnz` ids in [0, d) and weights in (0, scale).
+    fn random_vector(d: u64, nnz: usize, scale: f64, rng: &mut MtRng) -> Vec<(u64, f64)> {
+        let mut x: Vec<(u64, f64)> = (0..nnz)
+            .map(|_| (rng.next_u64() % d, scale * uniform01(rng)))
+            .collect();
+        x.sort_by_key(|&(id, _)| id);
+        x.dedup_by_key(|p| p.0);
+        x
+    }
+
+    #[test]
+    fn caps_builder_tracks_maxima_and_merges() {
+        let mut rng = mt_from_seed(99);
+        let d = 1_000u64;
+        let data: Vec<Vec<(u64, f64)>> = (0..50)
+            .map(|_| random_vector(d, 100, 10.0, &mut rng))
+            .collect();
+
+        let mut all = CapsBuilder::new();
+        let (mut left, mut right) = (CapsBuilder::new(), CapsBuilder::new());
+        for (s, x) in data.iter().enumerate() {
+            all.add(x);
+            if s % 2 == 0 {
+                left.add(x);
+            } else {
+                right.add(x);
+            }
+        }
+        left.merge(&right);
+        assert_eq!(all.sparse_caps(), left.sparse_caps());
+        assert_eq!(all.stats().vectors, 50);
+        assert_eq!(left.stats().vectors, 50);
+        assert!((all.stats().mean_mass - left.stats().mean_mass).abs() < 1e-9);
+        assert_eq!(all.stats().min_mass, left.stats().min_mass);
+
+        // Caps dominate every vector and are tight.
+        let caps = all.dense_caps(d as usize);
+        for x in &data {
+            assert!(x.iter().all(|&(id, w)| w <= caps[id as usize]));
+        }
+        for (id, &m) in caps.iter().enumerate() {
+            if m > 0.0 {
+                assert!(
+                    data.iter()
+                        .any(|x| x.iter().any(|&(i, w)| i == id as u64 && w == m))
+                );
+            }
+        }
+
+        let stats = all.stats();
+        assert_eq!(stats.dims, caps.iter().filter(|&&m| m > 0.0).count());
+        assert!((stats.m_total - caps.iter().sum::<f64>()).abs() < 1e-9);
+        assert!((stats.expected_acceptance - stats.mean_mass / stats.m_total).abs() < 1e-15);
+
+        let inflated = all.clone().with_margin(0.5);
+        assert!((inflated.stats().m_total - 1.5 * stats.m_total).abs() < 1e-9);
+        assert!(inflated.stats().expected_acceptance < stats.expected_acceptance);
+        assert_eq!(all.build_dense_index(d as usize).m_total(), stats.m_total);
+    }
+
+    #[test]
+    fn caps_builder_builds_ers() {
+        let mut data_rng = mt_from_seed(100);
+        let mut hash_rng = mt_from_seed(0xca95_0001);
+        let k = 1024;
+
+        // Hashed 64-bit ids, so only sparse caps are possible.
+        let x = random_vector(u64::MAX, 5_000, 1.0, &mut data_rng);
+        let y: Vec<(u64, f64)> = x
+            .iter()
+            .map(|&(id, w)| (id, w * (0.5 + uniform01(&mut data_rng))))
+            .collect();
+
+        let mut builder = CapsBuilder::new().with_margin(0.1);
+        builder.add(&x);
+        builder.add(&y);
+        let ers = builder.build_ers(&mut hash_rng, k);
+        assert_eq!(ers.index().m_total(), builder.stats().m_total);
+
+        let j_true = jaccard_similarity(&x, &y);
+        let sk_x = ers.sketch_auto(&x, 1e-3);
+        let sk_y = ers.sketch_auto(&y, 1e-3);
+        let hits = sk_x.iter().zip(&sk_y).filter(|(a, b)| a.0 == b.0).count();
+        let j_est = hits as f64 / k as f64;
+        assert_estimate_close(j_true, j_est, k, "ERS over builder caps");
+
+        // Same sketcher as building from the exported caps.
+        let mut hash_rng = mt_from_seed(0xca95_0001);
+        let direct = ErsWmh::new_sparse(&mut hash_rng, &builder.sparse_caps(), k);
+        assert_eq!(sk_x, direct.sketch_auto(&x, 1e-3));
+    }
+}
@@ -0,0 +1,326 @@
+//! Consistent Weighted Sampling (CWS) family for weighted Jaccard sketching.
+//!
+//! Implements:
+//!   - ICWS (Ioffe 2010): Improved Consistent Weighted Sampling. Each hash
+//!     position `j` picks `argmin_i a_{ij}` and reports the sample `(i, t_{ij})`.
+//!   - 0-bit CWS (Li 2015): same sampling as ICWS, but only the feature id `i`
+//!     is kept in the fingerprint. Slightly biased, but usually very close to
+//!     the weighted Jaccard and cheaper to store.
+//!   - PCWS (Wu et al. 2017): Practical CWS, which replaces one of the two
+//!     Gamma(2,1) draws of ICWS by a single uniform.
+//!
+//! All three run through one [`Cws`] sketcher, selected by [`CwsVariant`].
+//! They cost O(k * nnz) per sketch, so they are meant as reference baselines
+//! for the faster sketchers in this crate.
+//!
+//! Randomness is provided by hashing `(feature_id, j, draw)` with the
+//! sketcher's `HashFamily`: simple tabulation by default and mixed tabulation
+//! under the `mixed_tab` feature. Sketches are `Vec<(u64, f64)>` of
+//! `(fingerprint, a_{ij})`, so the usual `jaccard_estimate_from_minhashes`
+//! applies.
+
+use rand_core::RngCore;
+
+use crate::dartminhash::MinHashSketch;
+use crate::hash_utils::*;
+use crate::rng_utils::mt_from_seed_full;
+use crate::scratch::{Scratch, reset_sketch};
+
+/// Draw indices inside one `(feature, j)` cell.
+const DRAW_R1: u64 = 0;
+const DRAW_R2: u64 = 1;
+const DRAW_C1: u64 = 2;
+const DRAW_C2: u64 = 3;
+const DRAW_BETA: u64 = 4;
+
+/// Hash functions shared by all CWS variants.
+struct CwsTables<F: HashFamily> {
+    family: F,
+    // feature id → 64-bit key
+    t_id: F::H64,
+    // (feature key, j, draw) → uniform
+    t_u: F::H64,
+    // sample → fingerprint
+    t_fp: F::H64,
+}
+
+impl<F: HashFamily> CwsTables<F> {
+    fn new<R: RngCore + ?Sized>(rng: &mut R, family: F) -> Self {
+        let t_id = family.hash64_from_rng(rng);
+        let t_u = family.hash64_from_rng(rng);
+        let t_fp = family.hash64_from_rng(rng);
+        Self {
+            family,
+            t_id,
+            t_u,
+            t_fp,
+        }
+    }
+
+    #[inline]
+    fn id_key(&self, id: u64) -> u64 {
+        self.t_id.hash(id)
+    }
+
+    /// Uniform strictly inside (0, 1) for `(feature key, j, draw)`.
+    #[inline]
+    fn uniform_open01(&self, id_key: u64, j: u64, draw: u64) -> f64 {
+        const DEN: f64 = (1u64 << 53) as f64;
+        // Spread both j and the draw index over all key bytes; keys that only
+        // differ in one byte would give strongly dependent tabulation values.
+        let key = id_key
+            ^ j.wrapping_mul(0x9e37_79b9_7f4a_7c15)
+            ^ (draw + 1).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        let v = self.t_u.hash(key) >> 11;
+        ((v as f64) + 0.5) / DEN
+    }
+
+    /// Gamma(2, 1) as `-ln(u1 * u2)`.
+    #[inline]
+    fn gamma2(&self, id_key: u64, j: u64, d1: u64, d2: u64) -> f64 {
+        -(self.uniform_open01(id_key, j, d1).ln() + self.uniform_open01(id_key, j, d2).ln())
+    }
+
+    #[inline]
+    fn fingerprint_id(&self, id_key: u64) -> u64 {
+        self.t_fp.hash(id_key)
+    }
+
+    #[inline]
+    fn fingerprint_sample(&self, id_key: u64, t: i64) -> u64 {
+        self.t_fp
+            .hash(id_key ^ (t as u64).wrapping_mul(0x94d0_49bb_1331_11eb))
+    }
+}
+
+/// Run the CWS argmin loop shared by ICWS, 0-bit CWS and PCWS.
+///
+/// `draw(id_key, j, ln_w)` returns `(t, a)` for one `(feature, j)` cell and
+/// `fingerprint(id_key, t)` maps the winning sample to the sketch id.
+fn cws_sketch<H, D, P>(
+    k: usize,
+    x: &[(u64, f64)],
+    tables: &CwsTables<H>,
+    draw: D,
+    fingerprint: P,
+    best: &mut MinHashSketch,
+) where
+    H: HashFamily,
+    D: Fn(u64, u64, f64) -> (i64, f64),
+    P: Fn(u64, i64) -> u64,
+{
+    reset_sketch(best, k);
+    for &(id, w) in x {
+        if w <= 0.0 || !w.is_finite() {
+            continue;
+        }
+        let id_key = tables.id_key(id);
+        let ln_w = w.ln();
+        for (j, slot) in best.iter_mut().enumerate() {
+            let (t, a) = draw(id_key, j as u64, ln_w);
+            if a < slot.1 {
+                *slot = (fingerprint(id_key, t), a);
+            }
+        }
+    }
+}
+
+/// Which CWS scheme a [`Cws`] sketcher runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CwsVariant {
+    /// Improved Consistent Weighted Sampling (Ioffe 2010); slots hold
+    /// `(fingerprint(i, t), a)`.
+    Icws,
+    /// 0-bit CWS (Li 2015): the ICWS sampling, but the fingerprint drops `t`,
+    /// so two sketches collide whenever they select the same feature.
+    ZeroBit,
+    /// Practical Consistent Weighted Sampling (Wu et al. 2017).
+    Pcws,
+}
+
+/// Consistent Weighted Sampling sketcher running one [`CwsVariant`].
+///
+/// Sketches are only comparable between sketchers of the same variant.
+pub struct Cws<F: HashFamily = DefaultFamily> {
+    k: u64,
+    variant: CwsVariant,
+    tables: CwsTables<F>,
+}
+
+impl Cws {
+    pub fn new<R: RngCore + ?Sized>(rng: &mut R, k: u64, variant: CwsVariant) -> Self {
+        Self::with_family(rng, k, variant, DefaultFamily::default())
+    }
+
+    /// Sketcher built from an MT19937 seeded with `mt_from_seed_full(seed)`.
+    pub fn from_seed(seed: u64, k: u64, variant: CwsVariant) -> Self {
+        Self::new(&mut mt_from_seed_full(seed), k, variant)
+    }
+}
+
+impl<F: HashFamily> Cws<F> {
+    /// Like `new`, drawing the hash functions from `family`.
+    pub fn with_family<R: RngCore + ?Sized>(
+        rng: &mut R,
+        k: u64,
+        variant: CwsVariant,
+        family: F,
+    ) -> Self {
+        Self {
+            k,
+            variant,
+            tables: CwsTables::new(rng, family),
+        }
+    }
+
+    #[inline]
+    pub fn variant(&self) -> CwsVariant {
+        self.variant
+    }
+
+    #[inline]
+    pub fn hash_family(&self) -> HashKind {
+        self.tables.family.kind()
+    }
+
+    /// Return k slots of `(fingerprint, a)`.
+    pub fn sketch(&self, x: &[(u64, f64)]) -> MinHashSketch {
+        let mut result = Vec::with_capacity(self.k as usize);
+        self.sketch_into(x, &mut Scratch::new(), &mut result);
+        result
+    }
+
+    /// Like `sketch`, writing into `result`. CWS needs no other memory, so
+    /// `scratch` is unused; it keeps the signature of the other sketchers.
+    pub fn sketch_into(
+        &self,
+        x: &[(u64, f64)],
+        _scratch: &mut Scratch,
+        result: &mut MinHashSketch,
+    ) {
+        let k = self.k as usize;
+        let tables = &self.tables;
+        match self.variant {
+            CwsVariant::Icws => cws_sketch(
+                k,
+                x,
+                tables,
+                |id_key, j, ln_w| icws_draw(tables, id_key, j, ln_w),
+                |id_key, t| tables.fingerprint_sample(id_key, t),
+                result,
+            ),
+            CwsVariant::ZeroBit => cws_sketch(
+                k,
+                x,
+                tables,
+                |id_key, j, ln_w| icws_draw(tables, id_key, j, ln_w),
+                |id_key, _| tables.fingerprint_id(id_key),
+                result,
+            ),
+            CwsVariant::Pcws => cws_sketch(
+                k,
+                x,
+                tables,
+                |id_key, j, ln_w| pcws_draw(tables, id_key, j, ln_w),
+                |id_key, t| tables.fingerprint_sample(id_key, t),
+                result,
+            ),
+        }
+    }
+}
+
+/// ICWS cell: r, c ~ Gamma(2,1), beta ~ U(0,1),
+/// t = floor(ln w / r + beta), y = exp(r (t - beta)), a = c / (y e^r).
+#[inline]
+fn icws_draw<F: HashFamily>(tables: &CwsTables<F>, id_key: u64, j: u64, ln_w: f64) -> (i64, f64) {
+    let r = tables.gamma2(id_key, j, DRAW_R1, DRAW_R2);
+    let c = tables.gamma2(id_key, j, DRAW_C1, DRAW_C2);
+    let beta = tables.uniform_open01(id_key, j, DRAW_BETA);
+    let t = (ln_w / r + beta).floor();
+    let ln_y = r * (t - beta);
+    let a = c * (-(ln_y + r)).exp();
+    (t as i64, a)
+}
+
+/// PCWS cell: gamma = -ln(u1 u2), beta, x ~ U(0,1),
+/// t = floor(ln w / gamma + beta), y = exp(gamma (t - beta)), a = -ln(x) u1 / y.
+#[inline]
+fn pcws_draw<F: HashFamily>(tables: &CwsTables<F>, id_key: u64, j: u64, ln_w: f64) -> (i64, f64) {
+    let u1 = tables.uniform_open01(id_key, j, DRAW_R1);
+    let u2 = tables.uniform_open01(id_key, j, DRAW_R2);
+    let x = tables.uniform_open01(id_key, j, DRAW_C1);
+    let beta = tables.uniform_open01(id_key, j, DRAW_BETA);
+    let gamma = -(u1.ln() + u2.ln());
+    let t = (ln_w / gamma + beta).floor();
+    let ln_y = gamma * (t - beta);
+    let a = -x.ln() * u1 * (-ln_y).exp();
+    (t as i64, a)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{
+        rng_utils::mt_from_seed,
+        similarity::{jaccard_estimate_from_minhashes, jaccard_similarity},
+        test_utils::{assert_estimate_close, generate_similar_weighted_set, generate_weighted_set},
+    };
+
+    /// Check one CWS variant on the usual overlap sweep. The sets are kept
+    /// small because CWS costs O(k * nnz).
+    fn check_cws_accuracy<S: Fn(&[(u64, f64)]) -> MinHashSketch>(name: &str, k: u64, sketch: S) {
+        let mut data_rng = mt_from_seed(1337);
+        let x = generate_weighted_set(1_000, 1_000.0, &mut data_rng);
+
+        let targets = [
+            0.99, 0.9, 0.8, 0.7, 0.6, 0.5, 0.4, 0.3, 0.2, 0.1, 0.05, 0.01,
+        ];
+        let sk_x = sketch(&x);
+        assert_eq!(sk_x.len(), k as usize);
+
+        for &rel in &targets {
+            let y = generate_similar_weighted_set(&x, rel, &mut data_rng);
+            let j_true = jaccard_similarity(&x, &y);
+            let sk_y = sketch(&y);
+            let j_est = jaccard_estimate_from_minhashes(&sk_x, &sk_y);
+
+            assert_estimate_close(j_true, j_est, k, format!("{name}: rel_overlap={rel}"));
+        }
+    }
+
+    #[test]
+    fn icws_approximates_weighted_jaccard() {
+        let mut hash_rng = mt_from_seed(0xc0c5_0001);
+        let k = 1024;
+        let icws = Cws::new(&mut hash_rng, k, CwsVariant::Icws);
+        check_cws_accuracy("ICWS", k, |x| icws.sketch(x));
+    }
+
+    #[test]
+    fn zerobit_cws_approximates_weighted_jaccard() {
+        let mut hash_rng = mt_from_seed(0xc0c5_0002);
+        let k = 1024;
+        let cws = Cws::new(&mut hash_rng, k, CwsVariant::ZeroBit);
+        check_cws_accuracy("0-bit CWS", k, |x| cws.sketch(x));
+    }
+
+    #[test]
+    fn pcws_approximates_weighted_jaccard() {
+        let mut hash_rng = mt_from_seed(0xc0c5_0003);
+        let k = 1024;
+        let pcws = Cws::new(&mut hash_rng, k, CwsVariant::Pcws);
+        check_cws_accuracy("PCWS", k, |x| pcws.sketch(x));
+    }
+
+    #[test]
+    fn icws_is_deterministic_and_handles_empty_input() {
+        let mut rng = mt_from_seed(5);
+        let icws = Cws::new(&mut rng, 64, CwsVariant::Icws);
+        let x = vec![(1, 0.5), (7, 2.0), (9, 1.25)];
+        assert_eq!(icws.sketch(&x), icws.sketch(&x));
+
+        let empty = icws.sketch(&[(3, 0.0)]);
+        assert!(empty.iter().all(|&(id, a)| id == 0 && a.is_infinite()));
+    }
+}
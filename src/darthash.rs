@@ -1,102 +1,152 @@
 //! DartHash: produces darts (id, rank) from a weighted vector.
 
-use std::f64::INFINITY;
+use std::io;
+
+use rand_core::RngCore;
 
 use crate::hash_utils::*;
-use crate::rng_utils::MtRng;
+use crate::persist::{
+    SketcherState, StateReader, StateWriter, TableState, invalid, read_family, write_family,
+};
+use crate::rng_utils::{MtRng, mt_from_seed_full};
 
-#[cfg(feature = "mixed_tab")]
-type Tab32Dart = tab_hash::Tab32Mixed;
-#[cfg(not(feature = "mixed_tab"))]
-type Tab32Dart = tab_hash::Tab32Simple;
+// 32-bit hash of a 64-bit area index. Indices below 2^32 hash as before; the
+// high half only enters for the huge grids of extreme weights.
+#[inline]
+fn hash_index<H: Hash32>(table: &H, v: u64) -> u32 {
+    let lo = table.hash(v as u32);
+    let hi = (v >> 32) as u32;
+    if hi == 0 {
+        lo
+    } else {
+        lo ^ table.hash(hi.rotate_left(16) ^ 0x9e37_79b9)
+    }
+}
 
-#[cfg(feature = "mixed_tab")]
-type Tab64Dart = tab_hash::Tab64Mixed;
-#[cfg(not(feature = "mixed_tab"))]
-type Tab64Dart = tab_hash::Tab64Simple;
+// A single dart = (hashed_id, rank)
+pub type Dart = (u64, f64);
 
-#[cfg(feature = "mixed_tab")]
-fn tab32_dart_from_rng(rng: &mut MtRng) -> Tab32Dart {
-    mixed_tab32_from_rng(rng)
+pub struct DartHash<F: HashFamily = DefaultFamily> {
+    t: u64,
+    family: F,
+    // 32-bit hashers
+    t_nu: F::H32,
+    t_rho: F::H32,
+    t_w: F::H32,
+    t_r: F::H32,
+    // 64-bit hashers
+    t_i: F::H64,
+    t_p: F::H64,
+    t_q: F::H64,
+    f_h: F::H64,
+    m_h: F::H64,
+    // Precomputed poisson CDF
+    poisson_cdf: Vec<f64>,
 }
 
-#[cfg(not(feature = "mixed_tab"))]
-fn tab32_dart_from_rng(rng: &mut MtRng) -> Tab32Dart {
-    tab32_from_rng(rng)
+// Exact 2^e for any exponent, 0 or infinity outside the f64 range.
+// Replaces lookup tables, which bounded the region indices nu and rho.
+#[inline]
+fn exp2i(e: i32) -> f64 {
+    if e > 1023 {
+        f64::INFINITY
+    } else if e >= -1022 {
+        f64::from_bits(((e + 1023) as u64) << 52)
+    } else if e >= -1074 {
+        f64::from_bits(1u64 << (e + 1074))
+    } else {
+        0.0
+    }
 }
 
-#[cfg(feature = "mixed_tab")]
-fn tab64_dart_from_rng(rng: &mut MtRng) -> Tab64Dart {
-    mixed_tab64_from_rng(rng)
-}
+// Totals below 2^-1000 (about 9e-302) are scaled up: the rank bound
+// theta / total would overflow once theta grows.
+const MIN_TOTAL_WEIGHT: f64 = 9.332636185032189e-302;
 
-#[cfg(not(feature = "mixed_tab"))]
-fn tab64_dart_from_rng(rng: &mut MtRng) -> Tab64Dart {
-    tab64_from_rng(rng)
+// w * 2^e in two steps, so that e may exceed the exponent range of one factor.
+#[inline]
+fn scale_weight(w: f64, e: i32) -> f64 {
+    if e == 0 {
+        w
+    } else {
+        w * exp2i(e / 2) * exp2i(e - e / 2)
+    }
 }
 
-// A single dart = (hashed_id, rank)
-pub type Dart = (u64, f64);
-
-pub struct DartHash {
-    t: u64,
-    // 32-bit tabulation hashers
-    t_nu: Tab32Dart,
-    t_rho: Tab32Dart,
-    t_w: Tab32Dart,
-    t_r: Tab32Dart,
-    // 64-bit tabulation hashers
-    t_i: Tab64Dart,
-    t_p: Tab64Dart,
-    t_q: Tab64Dart,
-    f_h: Tab64Dart,
-    m_h: Tab64Dart,
-    // Precomputed powers of 2 and poisson CDF
-    powers_of_two: Vec<f64>,
-    neg_powers_of_two: Vec<f64>,
-    poisson_cdf: Vec<f64>,
+// Power-of-two exponent that brings the usable (positive, finite) weights of x
+// into the range darts can be thrown in, and the total weight after scaling.
+// Inputs in range keep exponent 0, so their darts do not change. Otherwise the
+// total is scaled into [1, 2): a common factor leaves the weighted Jaccard
+// unchanged, and t * x_i and theta / total stay finite.
+fn weight_scale(x: &[(u64, f64)], t: u64) -> (i32, f64) {
+    let usable = || {
+        x.iter()
+            .map(|&(_, w)| w)
+            .filter(|w| *w > 0.0 && w.is_finite())
+    };
+    let (max_w, total) = usable().fold((0.0f64, 0.0), |(m, s), w| (m.max(w), s + w));
+    if max_w == 0.0 {
+        return (0, 0.0);
+    }
+    if total.is_finite() && (t as f64 * max_w).is_finite() && total >= MIN_TOTAL_WEIGHT {
+        return (0, total);
+    }
+    // The sum relative to the largest weight is in [1, nnz] and cannot overflow.
+    let e_max = max_w.log2().floor() as i32;
+    let rel: f64 = usable().map(|w| scale_weight(w, -e_max)).sum();
+    let e = -(e_max + rel.log2().floor() as i32);
+    (e, usable().map(|w| scale_weight(w, e)).sum())
 }
 
 impl DartHash {
     // t: expected number of darts (usually k ln k + 2k)
+    pub fn new<R: RngCore + ?Sized>(rng: &mut R, t: u64) -> Self {
+        Self::with_family(rng, t, DefaultFamily::default())
+    }
+
+    // `new` with an MT19937 rng.
     pub fn new_mt(rng: &mut MtRng, t: u64) -> Self {
-        let t_nu = tab32_dart_from_rng(rng);
-        let t_rho = tab32_dart_from_rng(rng);
-        let t_w = tab32_dart_from_rng(rng);
-        let t_r = tab32_dart_from_rng(rng);
-
-        let t_i = tab64_dart_from_rng(rng);
-        let t_p = tab64_dart_from_rng(rng);
-        let t_q = tab64_dart_from_rng(rng);
-        let f_h = tab64_dart_from_rng(rng);
-        let m_h = tab64_dart_from_rng(rng);
-
-        // Precompute 2^k up to ~1000
-        let mut pow2 = Vec::with_capacity(1000);
-        let mut p = 1.0;
-        for _ in 0..1000 {
-            pow2.push(p);
-            p *= 2.0;
-        }
-        let mut neg_pow2 = Vec::with_capacity(1000);
-        let mut q = 1.0;
-        for _ in 0..1000 {
-            neg_pow2.push(q);
-            q *= 0.5;
-        }
+        Self::new(rng, t)
+    }
 
-        // Poisson(1) CDF up to 100
-        let mut poisson_cdf = Vec::with_capacity(100);
+    // `new` with the MT19937 rng of `mt_from_seed_full(seed)`, so a seed
+    // alone reproduces the sketcher.
+    pub fn from_seed(seed: u64, t: u64) -> Self {
+        Self::new(&mut mt_from_seed_full(seed), t)
+    }
+}
+
+impl<F: HashFamily> DartHash<F> {
+    // Like `new`, drawing the hash functions from `family`.
+    pub fn with_family<R: RngCore + ?Sized>(rng: &mut R, t: u64, family: F) -> Self {
+        let t_nu = family.hash32_from_rng(rng);
+        let t_rho = family.hash32_from_rng(rng);
+        let t_w = family.hash32_from_rng(rng);
+        let t_r = family.hash32_from_rng(rng);
+
+        let t_i = family.hash64_from_rng(rng);
+        let t_p = family.hash64_from_rng(rng);
+        let t_q = family.hash64_from_rng(rng);
+        let f_h = family.hash64_from_rng(rng);
+        let m_h = family.hash64_from_rng(rng);
+
+        // Poisson(1) CDF until it no longer grows (about 18 entries); the
+        // remaining tail has probability below f64 resolution.
+        let mut poisson_cdf = Vec::new();
         let mut pdf = (-1.0f64).exp();
         let mut cdf = pdf;
         for i in 0..100 {
             poisson_cdf.push(cdf);
-            pdf = pdf / ((i + 1) as f64);
+            pdf /= (i + 1) as f64;
+            if cdf + pdf == cdf {
+                break;
+            }
             cdf += pdf;
         }
 
         Self {
             t,
+            family,
             t_nu,
             t_rho,
             t_w,
@@ -106,62 +156,119 @@ impl DartHash {
             t_q,
             f_h,
             m_h,
-            powers_of_two: pow2,
-            neg_powers_of_two: neg_pow2,
             poisson_cdf,
         }
     }
 
+    // Expected number of darts per unit of total weight and theta.
+    #[inline]
+    pub fn t(&self) -> u64 {
+        self.t
+    }
+
+    #[inline]
+    pub fn hash_family(&self) -> HashKind {
+        self.family.kind()
+    }
+
     // Generate darts for a weighted vector x.
     // x: vector of (feature_id, weight)
     // theta: search parameter (default 1.0)
     pub fn darts(&self, x: &[(u64, f64)], theta: f64) -> Vec<Dart> {
-        let mut darts = Vec::with_capacity((2 * self.t) as usize);
-        let total_w = total_weight(x);
+        let mut darts = Vec::new();
+        self.darts_into(x, theta, &mut darts);
+        darts
+    }
+
+    // Like `darts`, but clears and refills `darts` so its allocation is reused.
+    pub fn darts_into(&self, x: &[(u64, f64)], theta: f64, darts: &mut Vec<Dart>) {
+        self.darts_band_into(x, 0.0, theta, darts);
+    }
+
+    // Generate only the darts of `darts(x, theta_hi)` that are not in
+    // `darts(x, theta_lo)`, i.e. with rank in [theta_lo, theta_hi) / total
+    // weight. Rank bands below theta_lo are skipped, not regenerated, so
+    // widening theta in steps costs the same as one call with the final theta.
+    // Inputs whose weights or total leave the f64 range are scaled by a power
+    // of two first (see `weight_scale`); their darts then only line up with
+    // inputs that received the same factor.
+    pub fn darts_band_into(
+        &self,
+        x: &[(u64, f64)],
+        theta_lo: f64,
+        theta_hi: f64,
+        darts: &mut Vec<Dart>,
+    ) {
+        darts.clear();
+        darts.reserve((2 * self.t) as usize);
+        let (scale, total_w) = weight_scale(x, self.t);
         if total_w == 0.0 {
-            return darts;
+            return;
         }
-        let max_rank = theta / total_w;
+        let min_rank = theta_lo / total_w;
+        let max_rank = theta_hi / total_w;
+        assert!(
+            max_rank.is_finite(),
+            "theta {theta_hi} too large for total weight {total_w:e}"
+        );
         let t_inv = 1.0 / (self.t as f64);
-        let rho_upper = ((1.0 + max_rank).log2().floor()).max(0.0) as u32;
+        let rho_upper = ((1.0 + max_rank).log2().floor()).max(0.0) as i32;
 
         for &(i, xi) in x {
-            if xi <= 0.0 {
+            if xi <= 0.0 || !xi.is_finite() {
                 continue;
             }
+            let xi = scale_weight(xi, scale);
 
             let i_hash = self.t_i.hash(i);
-            let nu_upper = ((1.0 + (self.t as f64) * xi).log2().floor()).max(0.0) as u32;
+            let t_xi = (self.t as f64) * xi;
+            let nu_upper = ((1.0 + t_xi).log2().floor()).max(0.0) as i32;
 
             for nu in 0..=nu_upper {
                 let nu_hash = self.t_nu.hash(nu as u32);
                 for rho in 0..=rho_upper {
+                    let two_rho = exp2i(rho);
+                    // Level rho holds the ranks [2^rho - 1, 2^(rho+1) - 1).
+                    if 2.0 * two_rho - 1.0 <= min_rank {
+                        continue;
+                    }
                     let region_hash = (nu_hash as u64) ^ (self.t_rho.hash(rho as u32) as u64);
 
-                    let two_nu = self.powers_of_two[nu as usize];
-                    let two_rho = self.powers_of_two[rho as usize];
+                    let two_nu = exp2i(nu);
                     let w_base = (two_nu - 1.0) * t_inv;
                     let r_base = two_rho - 1.0;
 
-                    let delta_nu = two_nu * t_inv * self.neg_powers_of_two[rho as usize];
-                    let delta_rho = two_rho * self.neg_powers_of_two[nu as usize];
+                    let delta_nu = two_nu * t_inv * exp2i(-rho);
+                    let delta_rho = two_rho * exp2i(-nu);
 
+                    // The region has 2^rho columns and 2^nu rows of area 1/t.
+                    // The loops stop at xi and max_rank long before the 64-bit
+                    // bounds, so no area is cut off.
                     let mut w0 = w_base;
-                    let w_max = if rho < 32 { 1u32 << rho } else { 1u32 << 31 };
+                    let w_max = if rho < 64 { 1u64 << rho } else { u64::MAX };
                     for w in 0..w_max {
                         if xi < w0 {
                             break;
                         }
-                        let w_hash = self.t_w.hash(w);
-                        let mut r0 = r_base;
-                        let r_max = if nu < 32 { 1u32 << nu } else { 1u32 << 31 };
+                        let w_hash = hash_index(&self.t_w, w);
+                        let r_max = if nu < 64 { 1u64 << nu } else { u64::MAX };
+                        // First area that can reach min_rank, one early
+                        // against rounding.
+                        let r_start = if min_rank > r_base {
+                            (((min_rank - r_base) / delta_rho) as u64)
+                                .saturating_sub(1)
+                                .min(r_max)
+                        } else {
+                            0
+                        };
+                        let mut r0 = r_base + (r_start as f64) * delta_rho;
 
-                        for r in 0..r_max {
+                        for r in r_start..r_max {
                             if max_rank < r0 {
                                 break;
                             }
 
-                            let area_hash = (w_hash as u64) ^ (self.t_r.hash(r) as u64);
+                            let area_hash = (w_hash as u64) ^ (hash_index(&self.t_r, r) as u64);
                             let z = i_hash ^ region_hash ^ area_hash;
 
                             // Poisson draw via CDF table
@@ -190,7 +297,7 @@ impl DartHash {
                                 let weight = w0 + delta_nu * u_w;
                                 let rank = r0 + delta_rho * u_r;
 
-                                if weight < xi && rank < max_rank {
+                                if weight < xi && min_rank <= rank && rank < max_rank {
                                     darts.push((self.f_h.hash(z_q), rank));
                                 }
                                 q_idx += 1;
@@ -203,13 +310,12 @@ impl DartHash {
                 }
             }
         }
-        darts
     }
 
     // Convert darts to k buckets, keep min rank in each
     pub fn minhash(&self, x: &[(u64, f64)], k: u64) -> Vec<Dart> {
         let darts = self.darts(x, 1.0);
-        let mut mh = vec![(0u64, INFINITY); k as usize];
+        let mut mh = vec![(0u64, f64::INFINITY); k as usize];
         for &(id, rank) in &darts {
             let j = (self.m_h.hash(id) % k) as usize;
             if rank < mh[j].1 {
@@ -224,3 +330,62 @@ impl DartHash {
         mh.into_iter().map(|(id, _)| (id & 1) == 1).collect()
     }
 }
+
+// Tables and the Poisson CDF, which is stored rather than recomputed so that
+// a different libm cannot change the darts.
+impl<F: HashFamily> SketcherState for DartHash<F> {
+    const TAG: u8 = 1;
+
+    fn write_body(&self, w: &mut StateWriter) {
+        write_family(w, self.family.kind());
+        w.put_u64(self.t);
+        for h in [&self.t_nu, &self.t_rho, &self.t_w, &self.t_r] {
+            h.write_state(w);
+        }
+        for h in [&self.t_i, &self.t_p, &self.t_q, &self.f_h, &self.m_h] {
+            h.write_state(w);
+        }
+        w.put_f64s(&self.poisson_cdf);
+    }
+
+    fn read_body(r: &mut StateReader) -> io::Result<Self> {
+        let family = read_family::<F>(r)?;
+        let t = r.get_u64()?;
+        let t_nu = F::H32::read_state(r)?;
+        let t_rho = F::H32::read_state(r)?;
+        let t_w = F::H32::read_state(r)?;
+        let t_r = F::H32::read_state(r)?;
+        let t_i = F::H64::read_state(r)?;
+        let t_p = F::H64::read_state(r)?;
+        let t_q = F::H64::read_state(r)?;
+        let f_h = F::H64::read_state(r)?;
+        let m_h = F::H64::read_state(r)?;
+        let poisson_cdf = r.get_f64s()?;
+        if t == 0 || poisson_cdf.is_empty() {
+            return Err(invalid("DartHash: t and the Poisson CDF must be non-empty"));
+        }
+        // A CDF outside (0, 1] or one that decreases would skew the dart
+        // counts without any error later on.
+        let cdf_valid = poisson_cdf.iter().all(|&p| p > 0.0 && p <= 1.0)
+            && poisson_cdf.windows(2).all(|w| w[0] <= w[1]);
+        if !cdf_valid {
+            return Err(invalid(
+                "DartHash: Poisson CDF must be non-decreasing and within (0, 1]",
+            ));
+        }
+        Ok(Self {
+            t,
+            family,
+            t_nu,
+            t_rho,
+            t_w,
+            t_r,
+            t_i,
+            t_p,
+            t_q,
+            f_h,
+            m_h,
+            poisson_cdf,
+        })
+    }
+}
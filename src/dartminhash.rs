@@ -1,67 +1,274 @@
 //! DartMinHash: DartHash + repeatedly throws darts until all buckets filled.
 
-use crate::darthash::{Dart, DartHash};
-use crate::hash_utils::*;
-use crate::rng_utils::MtRng;
-use std::f64::INFINITY;
+use std::io;
 
-#[cfg(feature = "mixed_tab")]
-type Tab64Bucket = tab_hash::Tab64Mixed;
-#[cfg(not(feature = "mixed_tab"))]
-type Tab64Bucket = tab_hash::Tab64Simple;
+use rand_core::RngCore;
 
-#[cfg(feature = "mixed_tab")]
-fn tab64_bucket_from_rng(rng: &mut MtRng) -> Tab64Bucket {
-    mixed_tab64_from_rng(rng)
-}
+use crate::darthash::{Dart, DartHash};
+use crate::hash_utils::*;
+use crate::persist::{
+    SketcherState, StateReader, StateWriter, TableState, invalid, read_family, write_family,
+};
+use crate::rng_utils::{MtRng, mt_from_seed_full};
+use crate::scratch::{Scratch, reset_sketch};
 
-#[cfg(not(feature = "mixed_tab"))]
-fn tab64_bucket_from_rng(rng: &mut MtRng) -> Tab64Bucket {
-    tab64_from_rng(rng)
-}
+// Default factor by which theta grows while buckets are empty. Rounds only
+// generate their new rank band, so the total work follows the final theta;
+// growing geometrically bounds the number of rounds by O(log theta).
+const THETA_GROWTH: f64 = 1.5;
 
 // Sketch = k slots of (id, rank)
 pub type MinHashSketch = Vec<Dart>;
 
-pub struct DartMinHash {
+// Work done for one sketch.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DartMinHashStats {
+    // Number of dart rounds (1 if the first theta filled every bucket).
+    pub rounds: u32,
+    // Darts generated over all rounds.
+    pub darts: u64,
+    // Theta of the last round.
+    pub theta: f64,
+}
+
+// Parameters of `DartMinHash`, with the defaults of `DartMinHash::new_mt`:
+// t = k ln k + 2k darts per unit theta, initial theta 1 and growth 1.5.
+// Larger t or initial theta make a second round rarer at the cost of more
+// darts per sketch; smaller values save time and memory on inputs that fill
+// the buckets easily.
+#[derive(Clone, Copy, Debug)]
+pub struct DartMinHashBuilder {
     k: u64,
-    bucket_hasher: Tab64Bucket,
-    dart_hash: DartHash,
+    t: Option<u64>,
+    initial_theta: f64,
+    theta_growth: f64,
 }
 
-impl DartMinHash {
-    // t = k*ln(k) + 2k
-    pub fn new_mt(rng: &mut MtRng, k: u64) -> Self {
-        let t = ((k as f64) * (k as f64).ln() + 2.0 * (k as f64)).ceil() as u64;
-        let bucket_hasher = tab64_bucket_from_rng(rng);
-        let dart_hash = DartHash::new_mt(rng, t);
+impl DartMinHashBuilder {
+    pub fn new(k: u64) -> Self {
+        assert!(k > 0, "k must be positive");
         Self {
             k,
+            t: None,
+            initial_theta: 1.0,
+            theta_growth: THETA_GROWTH,
+        }
+    }
+
+    // Expected darts per unit of theta (default k ln k + 2k).
+    pub fn with_t(mut self, t: u64) -> Self {
+        assert!(t > 0, "t must be positive");
+        self.t = Some(t);
+        self
+    }
+
+    // Theta of the first round.
+    pub fn with_initial_theta(mut self, theta: f64) -> Self {
+        assert!(
+            theta > 0.0 && theta.is_finite(),
+            "initial theta must be finite and positive"
+        );
+        self.initial_theta = theta;
+        self
+    }
+
+    // Factor applied to theta after a round that left buckets empty.
+    pub fn with_theta_growth(mut self, growth: f64) -> Self {
+        assert!(
+            growth > 1.0 && growth.is_finite(),
+            "theta growth must be finite and greater than 1"
+        );
+        self.theta_growth = growth;
+        self
+    }
+
+    pub fn build<R: RngCore + ?Sized>(self, rng: &mut R) -> DartMinHash {
+        self.build_with_family(rng, DefaultFamily::default())
+    }
+
+    // Like `build`, drawing the hash functions from `family`.
+    pub fn build_with_family<F: HashFamily, R: RngCore + ?Sized>(
+        self,
+        rng: &mut R,
+        family: F,
+    ) -> DartMinHash<F> {
+        let k = self.k as f64;
+        let t = self
+            .t
+            .unwrap_or_else(|| (k * k.ln() + 2.0 * k).ceil() as u64);
+        let bucket_hasher = family.hash64_from_rng(rng);
+        let dart_hash = DartHash::with_family(rng, t, family);
+        DartMinHash {
+            k: self.k,
             bucket_hasher,
             dart_hash,
+            initial_theta: self.initial_theta,
+            theta_growth: self.theta_growth,
         }
     }
+}
+
+pub struct DartMinHash<F: HashFamily = DefaultFamily> {
+    k: u64,
+    bucket_hasher: F::H64,
+    dart_hash: DartHash<F>,
+    initial_theta: f64,
+    theta_growth: f64,
+}
+
+impl DartMinHash {
+    // t = k*ln(k) + 2k
+    pub fn new<R: RngCore + ?Sized>(rng: &mut R, k: u64) -> Self {
+        Self::builder(k).build(rng)
+    }
+
+    // `new` with an MT19937 rng.
+    pub fn new_mt(rng: &mut MtRng, k: u64) -> Self {
+        Self::new(rng, k)
+    }
+
+    // `new` with the MT19937 rng of `mt_from_seed_full(seed)`, so a seed
+    // alone reproduces the sketcher.
+    pub fn from_seed(seed: u64, k: u64) -> Self {
+        Self::new(&mut mt_from_seed_full(seed), k)
+    }
+
+    pub fn builder(k: u64) -> DartMinHashBuilder {
+        DartMinHashBuilder::new(k)
+    }
+}
+
+impl<F: HashFamily> DartMinHash<F> {
+    // Like `new`, drawing the hash functions from `family`.
+    pub fn with_family<R: RngCore + ?Sized>(rng: &mut R, k: u64, family: F) -> Self {
+        DartMinHashBuilder::new(k).build_with_family(rng, family)
+    }
+
+    #[inline]
+    pub fn hash_family(&self) -> HashKind {
+        self.dart_hash.hash_family()
+    }
+
+    #[inline]
+    pub fn k(&self) -> u64 {
+        self.k
+    }
+
+    // Expected darts per unit of theta, see `DartHash::t`.
+    #[inline]
+    pub fn t(&self) -> u64 {
+        self.dart_hash.t()
+    }
+
+    #[inline]
+    pub fn initial_theta(&self) -> f64 {
+        self.initial_theta
+    }
+
+    #[inline]
+    pub fn theta_growth(&self) -> f64 {
+        self.theta_growth
+    }
 
     // Returns k minhash darts. Ensures every bucket got something by increasing theta if needed.
+    // Inputs without weight give k empty slots.
     pub fn sketch(&self, x: &[(u64, f64)]) -> MinHashSketch {
-        let mut minhashes = vec![(0u64, INFINITY); self.k as usize];
-        let mut theta = 1.0;
+        let mut minhashes = Vec::with_capacity(self.k as usize);
+        self.sketch_into(x, &mut Scratch::new(), &mut minhashes);
+        minhashes
+    }
+
+    // Like `sketch`, tagged with the hash family.
+    pub fn sketch_tagged(&self, x: &[(u64, f64)]) -> FamilySketch {
+        FamilySketch {
+            hash_family: self.hash_family(),
+            minhashes: self.sketch(x),
+        }
+    }
+
+    // Like `sketch`, also reporting the rounds and darts it needed.
+    pub fn sketch_with_stats(&self, x: &[(u64, f64)]) -> (MinHashSketch, DartMinHashStats) {
+        let mut minhashes = Vec::with_capacity(self.k as usize);
+        let stats = self.sketch_into(x, &mut Scratch::new(), &mut minhashes);
+        (minhashes, stats)
+    }
+
+    // Like `sketch_with_stats`, but writes into `minhashes` and reuses the
+    // dart and flag buffers of `scratch`, so steady-state calls do not
+    // allocate.
+    pub fn sketch_into(
+        &self,
+        x: &[(u64, f64)],
+        scratch: &mut Scratch,
+        minhashes: &mut MinHashSketch,
+    ) -> DartMinHashStats {
+        reset_sketch(minhashes, self.k as usize);
+        let mut stats = DartMinHashStats::default();
+        // Without usable weight no dart is ever thrown.
+        if !x.iter().any(|&(_, w)| w > 0.0 && w.is_finite()) {
+            return stats;
+        }
+        scratch.filled.clear();
+        scratch.filled.resize(self.k as usize, false);
+        let mut theta_lo = 0.0;
+        let mut theta = self.initial_theta;
         loop {
-            let mut filled = vec![false; self.k as usize];
-            let darts = self.dart_hash.darts(x, theta);
-            for &(id, rank) in &darts {
+            // Darts below theta_lo were thrown in earlier rounds and cannot
+            // change filled buckets, so only the new rank band is generated.
+            self.dart_hash
+                .darts_band_into(x, theta_lo, theta, &mut scratch.darts);
+            stats.rounds += 1;
+            stats.darts += scratch.darts.len() as u64;
+            stats.theta = theta;
+            for &(id, rank) in &scratch.darts {
                 let j = (self.bucket_hasher.hash(id) % self.k) as usize;
-                filled[j] = true;
+                scratch.filled[j] = true;
                 if rank < minhashes[j].1 {
                     minhashes[j] = (id, rank);
                 }
             }
-            if filled.iter().all(|&b| b) {
-                break;
+            if scratch.filled.iter().all(|&b| b) {
+                return stats;
             }
-            theta += 0.5;
+            theta_lo = theta;
+            theta *= self.theta_growth;
         }
-        minhashes
+    }
+}
+
+impl<F: HashFamily> SketcherState for DartMinHash<F> {
+    const TAG: u8 = 2;
+
+    fn write_body(&self, w: &mut StateWriter) {
+        write_family(w, self.hash_family());
+        w.put_u64(self.k);
+        w.put_f64(self.initial_theta);
+        w.put_f64(self.theta_growth);
+        self.bucket_hasher.write_state(w);
+        self.dart_hash.write_body(w);
+    }
+
+    fn read_body(r: &mut StateReader) -> io::Result<Self> {
+        read_family::<F>(r)?;
+        let k = r.get_u64()?;
+        let initial_theta = r.get_f64()?;
+        let theta_growth = r.get_f64()?;
+        if k == 0
+            || !(initial_theta > 0.0 && initial_theta.is_finite())
+            || theta_growth.is_nan()
+            || theta_growth <= 1.0
+        {
+            return Err(invalid("DartMinHash: invalid k or theta parameters"));
+        }
+        let bucket_hasher = F::H64::read_state(r)?;
+        let dart_hash = DartHash::read_body(r)?;
+        Ok(Self {
+            k,
+            bucket_hasher,
+            dart_hash,
+            initial_theta,
+            theta_growth,
+        })
     }
 }
 
@@ -71,9 +278,11 @@ mod tests {
     use std::collections::HashSet;
 
     use crate::{
-        dartminhash::DartMinHash,
-        rng_utils::{MtRng, mt_from_seed},
+        dartminhash::{DartMinHash, DartMinHashStats},
+        hash_utils::{DoubleTab, FastMix, HashFamily, MixedTab, SimpleTab, TwistedTab},
+        rng_utils::{MtRng, mt_from_seed, mt_from_seed_full},
         similarity::{jaccard_estimate_from_minhashes, jaccard_similarity},
+        test_utils::assert_estimate_close,
         treeminhash::TreeMinHash,
     };
     /// Uniform(0,1) using the same MT19937 rng.
@@ -85,6 +294,7 @@ mod tests {
     /// Pick L0 distinct random indices (u64)
     ///  Draw L0-1 uniform(0,1), sort, use the gaps * L1 as weights
     /// Returns sorted by id.
+    #[allow(clippy::explicit_counter_loop)]
     fn generate_weighted_set(l0: u64, l1: f64, rng: &mut MtRng) -> Vec<(u64, f64)> {
         let mut elements = HashSet::with_capacity(l0 as usize);
         while elements.len() < l0 as usize {
@@ -239,14 +449,21 @@ mod tests {
     }
 
     /// Empirical DartMinHash accuracy sweep across several independent data and
-    /// hasher seeds. Run twice to compare hash families:
+    /// hasher seeds, once per hash family on the same weighted sets:
     ///
-    ///     cargo test --release --no-default-features dartminhash_multi_seed_accuracy_sweep -- --ignored --nocapture
-    ///     cargo test --release --features mixed_tab dartminhash_multi_seed_accuracy_sweep -- --ignored --nocapture
+    ///     cargo test --release dartminhash_multi_seed_accuracy_sweep -- --ignored --nocapture
     #[test]
     #[ignore]
     fn dartminhash_multi_seed_accuracy_sweep() {
-        let seeds = [7, 19, 42, 1_337, 2_025, 86_753_09];
+        multi_seed_accuracy_sweep(SimpleTab);
+        multi_seed_accuracy_sweep(MixedTab);
+        multi_seed_accuracy_sweep(TwistedTab);
+        multi_seed_accuracy_sweep(DoubleTab);
+        multi_seed_accuracy_sweep(FastMix);
+    }
+
+    fn multi_seed_accuracy_sweep<F: HashFamily>(family: F) {
+        let seeds = [7, 19, 42, 1_337, 2_025, 8_675_309];
         let targets = [
             0.99, 0.96, 0.93, 0.9, 0.85, 0.8, 0.75, 0.7, 0.65, 0.6, 0.55, 0.5, 0.4, 0.3, 0.2, 0.1,
             0.05, 0.01,
@@ -270,9 +487,9 @@ mod tests {
             let x = generate_weighted_set(l0, l1, &mut data_rng);
 
             // Keep the input data stream independent from hasher construction so
-            // simple and mixed builds compare on exactly the same weighted sets.
+            // all families compare on exactly the same weighted sets.
             let mut hash_rng = mt_from_seed(seed ^ 0x9e37_79b9_7f4a_7c15);
-            let dm = DartMinHash::new_mt(&mut hash_rng, k);
+            let dm = DartMinHash::with_family(&mut hash_rng, k, family.clone());
             let sk_x = dm.sketch(&x);
 
             for &rel in &targets {
@@ -297,14 +514,10 @@ mod tests {
 
         let mean_abs = sum_abs / cases as f64;
         let rmse = (sum_sq / cases as f64).sqrt();
-        let mode = if cfg!(feature = "mixed_tab") {
-            "mixed_tab"
-        } else {
-            "simple_tab"
-        };
+        let mode = family.kind();
 
         println!(
-            "DMH_MULTI_SEED mode={mode} seeds={} targets={} cases={cases} k={k} l0={l0} mean_abs={mean_abs:.8} rmse={rmse:.8} max_abs={max_abs:.8} worst_seed={worst_seed} worst_rel={worst_rel:.3} worst_true={worst_true:.8} worst_est={worst_est:.8}",
+            "DMH_MULTI_SEED mode={mode:?} seeds={} targets={} cases={cases} k={k} l0={l0} mean_abs={mean_abs:.8} rmse={rmse:.8} max_abs={max_abs:.8} worst_seed={worst_seed} worst_rel={worst_rel:.3} worst_true={worst_true:.8} worst_est={worst_est:.8}",
             seeds.len(),
             targets.len()
         );
@@ -377,6 +590,236 @@ mod tests {
         // clearly under identical input size, total mass, sketch size, and seed.
     }
 
+    fn check_estimate(what: &str, dm: &DartMinHash, x: &[(u64, f64)], y: &[(u64, f64)]) {
+        let j_true = jaccard_similarity(x, y);
+        let j_est = jaccard_estimate_from_minhashes(&dm.sketch(x), &dm.sketch(y));
+        assert_estimate_close(j_true, j_est, dm.k(), what);
+    }
+
+    #[test]
+    fn dartminhash_extreme_weights() {
+        let mut data_rng = mt_from_seed(31337);
+        let mut hash_rng = mt_from_seed(0xd417_0003);
+        let dm = DartMinHash::new_mt(&mut hash_rng, 512);
+
+        // Total weights far outside the range of the old power tables.
+        for &scale in &[1e-300, 1e-150, 1e-12, 1e12, 1e150, 1e300] {
+            let x = generate_weighted_set(500, scale, &mut data_rng);
+            for &rel in &[0.9, 0.5, 0.1] {
+                let y = generate_similar_weighted_set(&x, rel, &mut data_rng);
+                check_estimate(&format!("scale={scale:e}, rel={rel}"), &dm, &x, &y);
+            }
+        }
+
+        // Weights spanning 1e-300..1e300 within one vector; y rescales every
+        // weight by a factor in [0.5, 1.5).
+        let x: Vec<(u64, f64)> = generate_weighted_set(500, 1.0, &mut data_rng)
+            .into_iter()
+            .map(|(id, _)| (id, 10f64.powf(600.0 * uniform01(&mut data_rng) - 300.0)))
+            .collect();
+        let y: Vec<(u64, f64)> = x
+            .iter()
+            .map(|&(id, w)| (id, w * (0.5 + uniform01(&mut data_rng))))
+            .collect();
+        check_estimate("log-uniform 1e-300..1e300", &dm, &x, &y);
+
+        // Weights around f64::MAX / t, where t * x_i overflows, and weights
+        // whose sum overflows. Both sets get the same power-of-two factor, so
+        // the Jaccard similarity is that of the unscaled sets.
+        let t_limit = f64::MAX / dm.t() as f64;
+        let x = generate_weighted_set(500, 4.0, &mut data_rng);
+        let y = generate_similar_weighted_set(&x, 0.6, &mut data_rng);
+        let j_true = jaccard_similarity(&x, &y);
+        let max_w = x.iter().map(|&(_, w)| w).fold(0.0, f64::max);
+        let near_limit = 2f64.powi((t_limit / max_w).log2().floor() as i32);
+        for (what, factor) in [
+            ("t * x_i overflow", 2.0 * near_limit),
+            ("sum overflow", 2f64.powi(1023)),
+        ] {
+            let scale = |v: &[(u64, f64)]| -> Vec<(u64, f64)> {
+                v.iter().map(|&(id, w)| (id, w * factor)).collect()
+            };
+            let (xs, ys) = (scale(&x), scale(&y));
+            let sk_x = dm.sketch(&xs);
+            assert!(sk_x.iter().all(|&(_, r)| r.is_finite()), "{what}");
+            let j_est = jaccard_estimate_from_minhashes(&sk_x, &dm.sketch(&ys));
+            assert_estimate_close(j_true, j_est, dm.k(), what);
+        }
+        assert!(
+            x.iter()
+                .map(|&(_, w)| w * 2f64.powi(1023))
+                .sum::<f64>()
+                .is_infinite()
+        );
+        assert!(
+            x.iter()
+                .any(|&(_, w)| (w * 2.0 * near_limit * dm.t() as f64).is_infinite())
+        );
+    }
+
+    #[test]
+    fn dartminhash_rank_bands_match_full_recomputation() {
+        let mut data_rng = mt_from_seed(4242);
+        let mut hash_rng = mt_from_seed(0xba2d_0001);
+        let k = 256;
+        // Far fewer darts per round than `new_mt` uses, so that filling all
+        // buckets takes several rounds.
+        let dmh = DartMinHash::builder(k).with_t(k / 2).build(&mut hash_rng);
+
+        // Small and skewed inputs are the ones that need several rounds.
+        let mut skewed = generate_weighted_set(50, 1.0, &mut data_rng);
+        skewed[0].1 = 1e4;
+        let inputs = [
+            vec![(7, 1.0)],
+            vec![(1, 1e-3), (2, 2.5)],
+            skewed,
+            generate_weighted_set(1_000, 30.0, &mut data_rng),
+        ];
+
+        for x in &inputs {
+            // Reference: the old loop, regenerating all darts every round
+            // (with the same theta schedule).
+            let mut theta = 1.0;
+            let mut rounds = 1;
+            let (reference, darts) = loop {
+                let mut mh = vec![(0u64, f64::INFINITY); k as usize];
+                let all = dmh.dart_hash.darts(x, theta);
+                for &(id, rank) in &all {
+                    let j = (dmh.bucket_hasher.hash(id) % k) as usize;
+                    if rank < mh[j].1 {
+                        mh[j] = (id, rank);
+                    }
+                }
+                if mh.iter().all(|&(_, r)| r.is_finite()) {
+                    break (mh, all.len() as u64);
+                }
+                theta *= dmh.theta_growth();
+                rounds += 1;
+            };
+            assert!(rounds > 1);
+            let (sketch, stats) = dmh.sketch_with_stats(x);
+            assert_eq!(sketch, reference);
+            assert_eq!(
+                stats,
+                DartMinHashStats {
+                    rounds,
+                    darts,
+                    theta
+                }
+            );
+
+            // The bands partition the darts of the final theta.
+            let mut banded = Vec::new();
+            let mut band = Vec::new();
+            let mut lo = 0.0;
+            let mut hi = 1.0;
+            while lo < theta {
+                dmh.dart_hash.darts_band_into(x, lo, hi, &mut band);
+                banded.extend_from_slice(&band);
+                lo = hi;
+                hi *= dmh.theta_growth();
+            }
+            let mut full = dmh.dart_hash.darts(x, theta);
+            let key = |d: &(u64, f64)| (d.0, d.1.to_bits());
+            banded.sort_by_key(key);
+            full.sort_by_key(key);
+            assert_eq!(banded, full);
+        }
+
+        let (empty, stats) = dmh.sketch_with_stats(&[]);
+        assert!(empty.iter().all(|&(_, r)| r.is_infinite()));
+        assert_eq!(stats.rounds, 0);
+    }
+
+    #[test]
+    fn dartminhash_builder_parameters() {
+        let k = 256;
+        let x = generate_weighted_set(300, 7.0, &mut mt_from_seed(77));
+
+        // Defaults reproduce `new_mt`.
+        let dm = DartMinHash::new_mt(&mut mt_from_seed(0xd417_0004), k);
+        let built = DartMinHash::builder(k).build(&mut mt_from_seed(0xd417_0004));
+        assert_eq!(built.t(), dm.t());
+        assert_eq!((built.initial_theta(), built.theta_growth()), (1.0, 1.5));
+        assert_eq!(built.sketch(&x), dm.sketch(&x));
+
+        // A larger initial theta fills every bucket in one round; the sketch
+        // is the same, since it only depends on the darts below each minimum.
+        let eager = DartMinHash::builder(k)
+            .with_t(k)
+            .with_initial_theta(20.0)
+            .build(&mut mt_from_seed(0xd417_0005));
+        let lazy = DartMinHash::builder(k)
+            .with_t(k)
+            .with_initial_theta(0.25)
+            .with_theta_growth(2.0)
+            .build(&mut mt_from_seed(0xd417_0005));
+        let (sk_eager, st_eager) = eager.sketch_with_stats(&x);
+        let (sk_lazy, st_lazy) = lazy.sketch_with_stats(&x);
+        assert_eq!(sk_eager, sk_lazy);
+        assert_eq!(st_eager.rounds, 1);
+        assert!(st_lazy.rounds > 1);
+        assert!(st_lazy.darts < st_eager.darts);
+    }
+
+    /// SplitMix64, standing in for a caller's own generator.
+    struct SplitMix64(u64);
+
+    impl RngCore for SplitMix64 {
+        fn next_u64(&mut self) -> u64 {
+            self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = self.0;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        }
+
+        fn next_u32(&mut self) -> u32 {
+            (self.next_u64() >> 32) as u32
+        }
+
+        fn fill_bytes(&mut self, dst: &mut [u8]) {
+            for chunk in dst.chunks_mut(8) {
+                let bytes = self.next_u64().to_le_bytes();
+                chunk.copy_from_slice(&bytes[..chunk.len()]);
+            }
+        }
+    }
+
+    #[test]
+    fn dartminhash_generic_rng_constructors() {
+        let k = 256;
+        let mut data_rng = mt_from_seed(48_001);
+        let x = generate_weighted_set(300, 5.0, &mut data_rng);
+        let y = generate_similar_weighted_set(&x, 0.6, &mut data_rng);
+
+        // `new` and a trait object draw the same tables as `new_mt`.
+        let dm = DartMinHash::new_mt(&mut mt_from_seed(48), k);
+        let generic = DartMinHash::new(&mut mt_from_seed(48), k);
+        let dyn_rng: &mut dyn RngCore = &mut mt_from_seed(48);
+        let dynamic = DartMinHash::new(dyn_rng, k);
+        for other in [&generic, &dynamic] {
+            assert_eq!(other.sketch(&x), dm.sketch(&x));
+        }
+        // `from_seed` seeds the whole MT19937 state.
+        let seeded = DartMinHash::from_seed(48, k);
+        assert_eq!(
+            seeded.sketch(&x),
+            DartMinHash::new(&mut mt_from_seed_full(48), k).sketch(&x)
+        );
+        assert_ne!(seeded.sketch(&x), dm.sketch(&x));
+
+        // Any other generator is just as reproducible and as accurate.
+        let a = DartMinHash::new(&mut SplitMix64(48), k);
+        let b = DartMinHash::new(&mut SplitMix64(48), k);
+        assert_eq!(a.sketch(&x), b.sketch(&x));
+        assert_ne!(a.sketch(&x), dm.sketch(&x));
+
+        let j = jaccard_similarity(&x, &y);
+        let est = jaccard_estimate_from_minhashes(&a.sketch(&x), &a.sketch(&y));
+        assert_estimate_close(j, est, k, "SplitMix64");
+    }
+
     #[test]
     fn conversions_match() {
         let x_w = 10.0;
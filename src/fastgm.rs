@@ -0,0 +1,254 @@
+//! FastGM: fast Gumbel-Max sketching for dense weighted vectors.
+//!
+//! A Gumbel-Max sketch keeps, for every register `j`, the feature maximizing
+//! `ln w_i - ln E_{ij}` (equivalently minimizing `E_{ij} / w_i`) with
+//! `E_{ij} ~ Exp(1)`. Two sketches collide with probability equal to the
+//! probability Jaccard J_P, see [`crate::similarity::probability_jaccard`], so
+//! this is a cap-free alternative to ERS whose cost does not grow with the
+//! weights.
+//!
+//! Following FastGM (Zhang et al.), each feature emits the arrivals of a Poisson
+//! process of rate `w_i` in increasing time order; every arrival is assigned a
+//! uniformly random register. Arrivals of all features are swept in rounds of a
+//! doubling time threshold until every register is filled, after which each
+//! feature is only advanced while its next arrival is below the current maximum
+//! register value. The work is O(nnz + k log k) per sketch rather than
+//! O(nnz * k), which makes it attractive for dense inputs.
+//!
+//! Randomness uses the tabulation streams of [`crate::treeminhash`], keyed by
+//! feature id, so the result does not depend on the sweep order.
+
+use rand_core::RngCore;
+
+use crate::dartminhash::MinHashSketch;
+use crate::hash_utils::*;
+use crate::rng_utils::mt_from_seed_full;
+use crate::scratch::{Scratch, reset_sketch};
+use crate::treeminhash::TabStream;
+
+/// Arrival state of one feature: its next arrival time and where its random
+/// stream continues.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct FeatureArrivals {
+    id: u64,
+    inv_w: f64,
+    point: f64,
+    counter: u64,
+}
+
+/// FastGM sketcher for probability Jaccard similarity.
+pub struct FastGm<F: HashFamily = DefaultFamily> {
+    k: u32,
+    family: F,
+    h0: F::H64,
+    h1: F::H64,
+    id_hasher: F::H64,
+}
+
+impl FastGm {
+    pub fn new<R: RngCore + ?Sized>(rng: &mut R, k: u64) -> Self {
+        Self::with_family(rng, k, DefaultFamily::default())
+    }
+
+    /// Like `new`; the same seed gives the same FastGM sketches.
+    pub fn from_seed(seed: u64, k: u64) -> Self {
+        Self::new(&mut mt_from_seed_full(seed), k)
+    }
+}
+
+impl<F: HashFamily> FastGm<F> {
+    /// Like `new`, drawing the hash functions from `family`.
+    pub fn with_family<R: RngCore + ?Sized>(rng: &mut R, k: u64, family: F) -> Self {
+        assert!(k > 0 && k <= (u32::MAX as u64), "k must fit into u32");
+        let h0 = family.hash64_from_rng(rng);
+        let h1 = family.hash64_from_rng(rng);
+        let id_hasher = family.hash64_from_rng(rng);
+        Self {
+            k: k as u32,
+            family,
+            h0,
+            h1,
+            id_hasher,
+        }
+    }
+
+    #[inline]
+    pub fn hash_family(&self) -> HashKind {
+        self.family.kind()
+    }
+
+    /// Return k slots of `(fingerprint(id), arrival time)`.
+    ///
+    /// Counting fingerprint collisions with `jaccard_estimate_from_minhashes`
+    /// estimates J_P (not the weighted Jaccard J_W).
+    pub fn sketch(&self, x: &[(u64, f64)]) -> MinHashSketch {
+        let mut result = Vec::with_capacity(self.k as usize);
+        self.sketch_into(x, &mut Scratch::new(), &mut result);
+        result
+    }
+
+    /// Like `sketch`, writing into `result` and reusing the buffers of
+    /// `scratch`, see [`crate::scratch`].
+    pub fn sketch_into(&self, x: &[(u64, f64)], scratch: &mut Scratch, result: &mut MinHashSketch) {
+        let k = self.k as usize;
+        reset_sketch(result, k);
+
+        let features = &mut scratch.features;
+        features.clear();
+        let mut max_w = 0.0f64;
+        for &(id, w) in x {
+            if w <= 0.0 || !w.is_finite() {
+                continue;
+            }
+            let mut rng = TabStream::new(&self.h0, &self.h1, id, 0);
+            let inv_w = 1.0 / w;
+            features.push(FeatureArrivals {
+                id,
+                inv_w,
+                point: rng.exponential1() * inv_w,
+                counter: rng.counter(),
+            });
+            max_w = max_w.max(w);
+        }
+        if features.is_empty() {
+            return;
+        }
+        // The sum of large finite weights can overflow, so it is taken
+        // relative to the largest weight.
+        let rel_sum: f64 = features.iter().map(|f| 1.0 / (f.inv_w * max_w)).sum();
+
+        let tracker = &mut scratch.tracker;
+        tracker.reset(k);
+        let mut filled = 0usize;
+        // About one arrival per register on average; doubled each round.
+        let mut threshold = ((k as f64) / max_w / rel_sum).max(f64::MIN_POSITIVE);
+        loop {
+            for f in features.iter_mut() {
+                // Once every register is filled the running maximum is a
+                // tighter bound than the round threshold.
+                let bound = threshold.min(tracker.max());
+                if f.point >= bound {
+                    continue;
+                }
+                let fingerprint = self.id_hasher.hash(f.id);
+                let mut rng = TabStream::resume(&self.h0, &self.h1, f.id, 0, f.counter);
+                while f.point < threshold.min(tracker.max()) {
+                    let idx = rng.uniform_index(self.k) as usize;
+                    if f.point < result[idx].1 {
+                        if result[idx].1.is_infinite() {
+                            filled += 1;
+                        }
+                        result[idx] = (fingerprint, f.point);
+                        tracker.update(idx, f.point);
+                    }
+                    f.point += rng.exponential1() * f.inv_w;
+                }
+                f.counter = rng.counter();
+            }
+
+            // An infinite threshold has swept every finite arrival; only
+            // features too light to ever arrive are left.
+            if filled == k || threshold.is_infinite() {
+                return;
+            }
+            threshold *= 2.0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{
+        fastgm::FastGm,
+        rng_utils::mt_from_seed,
+        similarity::{jaccard_estimate_from_minhashes, probability_jaccard},
+        test_utils::{
+            assert_estimate_close, generate_perturbed_distribution, generate_weighted_set,
+            uniform01,
+        },
+    };
+
+    #[test]
+    fn fastgm_approximates_probability_jaccard() {
+        let mut data_rng = mt_from_seed(1337);
+        let mut hash_rng = mt_from_seed(0xfa57_0001);
+
+        // J_P is computed exactly in O(n^2), so keep the sets moderate.
+        let l0 = 2_000;
+        let l1 = 1_000.0;
+        let k = 4096;
+
+        let gm = FastGm::new(&mut hash_rng, k);
+        let x = generate_weighted_set(l0, l1, &mut data_rng);
+        let sk_x = gm.sketch(&x);
+        assert_eq!(sk_x.len(), k as usize);
+
+        let cases = [
+            (0.0, 0.0),
+            (0.1, 0.0),
+            (0.5, 0.1),
+            (0.9, 0.3),
+            (0.9, 0.6),
+            (0.9, 0.9),
+        ];
+        for &(spread, drop) in &cases {
+            let y = generate_perturbed_distribution(&x, spread, drop, &mut data_rng);
+            let j_true = probability_jaccard(&x, &y);
+            let sk_y = gm.sketch(&y);
+            let j_est = jaccard_estimate_from_minhashes(&sk_x, &sk_y);
+            assert_estimate_close(
+                j_true,
+                j_est,
+                k,
+                format!("FastGM: spread={spread}, drop={drop}"),
+            );
+        }
+    }
+
+    #[test]
+    fn fastgm_dense_large_weights_fills_all_registers() {
+        let mut data_rng = mt_from_seed(2025);
+        let mut hash_rng = mt_from_seed(0xfa57_0002);
+
+        // Dense vector over 0..D with very large weights; no caps are needed.
+        let d = 200_000u64;
+        let k = 1024;
+        let x: Vec<(u64, f64)> = (0..d)
+            .map(|id| (id, 1.0e9 * uniform01(&mut data_rng)))
+            .collect();
+        let x_scaled: Vec<(u64, f64)> = x.iter().map(|&(id, w)| (id, 4.0 * w)).collect();
+
+        let gm = FastGm::new(&mut hash_rng, k);
+        let sk = gm.sketch(&x);
+        assert!(sk.iter().all(|&(_, v)| v.is_finite()));
+        assert_eq!(sk, gm.sketch(&x));
+        // Scaling does not change the distribution, so the sketch must agree.
+        let j_est = jaccard_estimate_from_minhashes(&sk, &gm.sketch(&x_scaled));
+        assert_eq!(j_est, 1.0);
+
+        let empty = gm.sketch(&[(1, 0.0)]);
+        assert!(empty.iter().all(|&(id, v)| id == 0 && v.is_infinite()));
+    }
+
+    #[test]
+    fn fastgm_weight_sum_beyond_f64_max() {
+        let mut data_rng = mt_from_seed(2029);
+        let mut hash_rng = mt_from_seed(0xfa57_0003);
+
+        // Weights up to ~4.5e307: finite, but their sum overflows.
+        let k = 256;
+        let x: Vec<(u64, f64)> = (0..1_000u64)
+            .map(|id| (id, 1.0 + uniform01(&mut data_rng)))
+            .collect();
+        let huge: Vec<(u64, f64)> = x.iter().map(|&(id, w)| (id, w * 2f64.powi(1021))).collect();
+        assert!(huge.iter().map(|&(_, w)| w).sum::<f64>().is_infinite());
+
+        let gm = FastGm::new(&mut hash_rng, k);
+        let sk = gm.sketch(&huge);
+        assert!(sk.iter().all(|&(_, v)| v.is_finite()));
+        // A power-of-two scale leaves the race between features unchanged.
+        let ids = |sk: &[(u64, f64)]| sk.iter().map(|&(id, _)| id).collect::<Vec<_>>();
+        assert_eq!(ids(&sk), ids(&gm.sketch(&x)));
+    }
+}
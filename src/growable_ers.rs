@@ -0,0 +1,335 @@
+//! Rejection sampling over a growable, block-wise partitioned line.
+//!
+//! `ErsWmh` lays all caps out on one line `[0, M)` with one alias table, so
+//! appending a dimension or raising a cap moves every interval and changes
+//! every sketch. Here the caps are split into blocks that are never modified:
+//! raising the caps of some ids (from 0 for new ids) appends a block holding
+//! only the extension segments `[old_cap, new_cap)` of those ids.
+//!
+//! For every hash position j, each block emits a Poisson process of points in
+//! time with rate equal to its mass, each point uniform over the block's
+//! segments. The sketch keeps the earliest green point (offset below the
+//! input weight of its id) over all blocks, with its time as rank. This is
+//! plain rejection sampling (no densification) over the union of all blocks,
+//! so collisions estimate the weighted Jaccard similarity.
+//!
+//! Adding a block only adds points in segments above the caps that existed
+//! before, which are never green for an input within those caps. Sketches
+//! computed before growing therefore equal the sketches computed after, and
+//! remain comparable with sketches of inputs that need the new caps.
+//!
+//! Weights above the current cap of their id are clipped to it, as in ERS.
+
+use std::collections::HashMap;
+
+use rand_core::RngCore;
+
+use crate::dartminhash::MinHashSketch;
+use crate::hash_utils::*;
+use crate::rejsmp::RedGreenIndex;
+use crate::rng_utils::mt_from_seed_full;
+use crate::scratch::{Scratch, reset_sketch};
+use crate::treeminhash::TabStream;
+
+/// Stream key of slot `j` in block `b`. Both indices are small, so they are
+/// spread over all bytes: keys differing in a single byte would give
+/// tabulation streams that are correlated across slots.
+#[inline]
+fn stream_key(j: usize, b: usize) -> u64 {
+    (j as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+        ^ (b as u64 + 1).wrapping_mul(0xd6e8_feb8_6659_fd93)
+}
+
+/// Piece `[lo, hi)` of the cap range of one id.
+#[derive(Clone, Copy, Debug)]
+struct Segment {
+    id: u64,
+    lo: f64,
+    hi: f64,
+}
+
+/// Immutable group of segments added by one cap update.
+#[derive(Clone)]
+struct CapBlock {
+    segments: Vec<Segment>,
+    // alias sampling of a segment proportional to its length
+    index: RedGreenIndex,
+}
+
+/// Rejection sampling sketcher whose caps can grow without invalidating
+/// existing sketches.
+pub struct GrowableErs<F: HashFamily = DefaultFamily> {
+    k: u32,
+    family: F,
+    h0: F::H64,
+    h1: F::H64,
+    t_u: F::H64,
+    id_hasher: F::H64,
+    point_hasher: F::H64,
+    caps: HashMap<u64, f64>,
+    blocks: Vec<CapBlock>,
+}
+
+impl GrowableErs {
+    /// Sketcher without caps; add them with `raise_caps`.
+    pub fn new<R: RngCore + ?Sized>(rng: &mut R, k: u64) -> Self {
+        Self::with_family(rng, k, DefaultFamily::default())
+    }
+
+    /// Like `new`, seeded with `mt_from_seed_full(seed)`.
+    pub fn from_seed(seed: u64, k: u64) -> Self {
+        Self::new(&mut mt_from_seed_full(seed), k)
+    }
+
+    /// Sketcher with initial `(id, cap)` pairs as its first block.
+    pub fn new_sparse<R: RngCore + ?Sized>(rng: &mut R, caps: &[(u64, f64)], k: u64) -> Self {
+        Self::new_sparse_with_family(rng, caps, k, DefaultFamily::default())
+    }
+}
+
+impl<F: HashFamily> GrowableErs<F> {
+    /// Like `new`, drawing the hash functions from `family`.
+    pub fn with_family<R: RngCore + ?Sized>(rng: &mut R, k: u64, family: F) -> Self {
+        assert!(k > 0 && k <= (u32::MAX as u64), "k must fit into u32");
+        let h0 = family.hash64_from_rng(rng);
+        let h1 = family.hash64_from_rng(rng);
+        let t_u = family.hash64_from_rng(rng);
+        let id_hasher = family.hash64_from_rng(rng);
+        let point_hasher = family.hash64_from_rng(rng);
+        Self {
+            k: k as u32,
+            family,
+            h0,
+            h1,
+            t_u,
+            id_hasher,
+            point_hasher,
+            caps: HashMap::new(),
+            blocks: Vec::new(),
+        }
+    }
+
+    /// Like `new_sparse`, drawing the hash functions from `family`.
+    pub fn new_sparse_with_family<R: RngCore + ?Sized>(
+        rng: &mut R,
+        caps: &[(u64, f64)],
+        k: u64,
+        family: F,
+    ) -> Self {
+        let mut ers = Self::with_family(rng, k, family);
+        ers.raise_caps(caps);
+        ers
+    }
+
+    #[inline]
+    pub fn hash_family(&self) -> HashKind {
+        self.family.kind()
+    }
+
+    /// Raise the caps of the given ids to at least the given values; ids
+    /// without a cap are appended as new dimensions. Caps never shrink.
+    /// Returns whether a new block was added (false if nothing grew).
+    pub fn raise_caps(&mut self, caps: &[(u64, f64)]) -> bool {
+        let mut raised: HashMap<u64, f64> = HashMap::new();
+        for &(id, cap) in caps {
+            if cap <= 0.0 || !cap.is_finite() {
+                continue;
+            }
+            let current = self.caps.get(&id).copied().unwrap_or(0.0);
+            if cap > current {
+                let m = raised.entry(id).or_insert(cap);
+                *m = m.max(cap);
+            }
+        }
+        if raised.is_empty() {
+            return false;
+        }
+
+        let mut segments: Vec<Segment> = raised
+            .into_iter()
+            .map(|(id, hi)| Segment {
+                id,
+                lo: self.caps.get(&id).copied().unwrap_or(0.0),
+                hi,
+            })
+            .collect();
+        // Fixed order so the block does not depend on the input order.
+        segments.sort_by_key(|s| s.id);
+        for s in &segments {
+            self.caps.insert(s.id, s.hi);
+        }
+        let lengths: Vec<f64> = segments.iter().map(|s| s.hi - s.lo).collect();
+        self.blocks.push(CapBlock {
+            segments,
+            index: RedGreenIndex::from_caps(&lengths),
+        });
+        true
+    }
+
+    #[inline]
+    pub fn num_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Current cap of `id` (0 if it has none).
+    #[inline]
+    pub fn cap_of(&self, id: u64) -> f64 {
+        self.caps.get(&id).copied().unwrap_or(0.0)
+    }
+
+    /// Total mass M of all blocks.
+    pub fn m_total(&self) -> f64 {
+        self.blocks.iter().map(|b| b.index.m_total()).sum()
+    }
+
+    /// Return k slots of `(fingerprint, time)` of the first green point.
+    ///
+    /// The expected number of points per slot is M / Σ_i min(x_i, m_i).
+    pub fn sketch(&self, x: &[(u64, f64)]) -> MinHashSketch {
+        let mut result = Vec::with_capacity(self.k as usize);
+        self.sketch_into(x, &mut Scratch::new(), &mut result);
+        result
+    }
+
+    /// Like `sketch`, writing into `result` and reusing the buffers of
+    /// `scratch`, see [`crate::scratch`].
+    pub fn sketch_into(&self, x: &[(u64, f64)], scratch: &mut Scratch, result: &mut MinHashSketch) {
+        reset_sketch(result, self.k as usize);
+
+        // Sorted by id and weight, so duplicate ids resolve independently of
+        // the input order.
+        let weights = &mut scratch.sorted;
+        weights.clear();
+        weights.extend(x.iter().copied().filter(|&(_, w)| w > 0.0));
+        weights.sort_unstable_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+        weights.dedup_by_key(|p| p.0);
+        let covered: f64 = weights.iter().map(|&(id, w)| w.min(self.cap_of(id))).sum();
+        if covered.is_nan() || covered <= 0.0 {
+            return;
+        }
+        let weight_of = |id: u64| match weights.binary_search_by_key(&id, |&(i, _)| i) {
+            Ok(p) => weights[p].1,
+            Err(_) => 0.0,
+        };
+
+        // (next arrival time, stream counter) per block
+        let arrivals = &mut scratch.arrivals;
+        for (j, slot) in result.iter_mut().enumerate() {
+            arrivals.clear();
+            for (b, block) in self.blocks.iter().enumerate() {
+                let mut rng = TabStream::new(&self.h0, &self.h1, stream_key(j, b), b as u64);
+                let time = rng.exponential1() / block.index.m_total();
+                arrivals.push((time, rng.counter()));
+            }
+
+            loop {
+                // Blocks are few, so a linear scan beats a heap here.
+                let (b, &(time, counter)) = arrivals
+                    .iter()
+                    .enumerate()
+                    .min_by(|a, b| a.1.0.total_cmp(&b.1.0))
+                    .unwrap();
+                let block = &self.blocks[b];
+                let mut rng =
+                    TabStream::resume(&self.h0, &self.h1, stream_key(j, b), b as u64, counter);
+                let (s, off) = block
+                    .index
+                    .sample_interval_and_offset(&self.t_u, rng.next_u64());
+                let segment = block.segments[s];
+                let point = segment.lo + off;
+                if point < weight_of(segment.id) {
+                    *slot = (self.point_fingerprint(segment.id, point), time);
+                    break;
+                }
+                let next = time + rng.exponential1() / block.index.m_total();
+                arrivals[b] = (next, rng.counter());
+            }
+        }
+    }
+
+    #[inline]
+    fn point_fingerprint(&self, id: u64, point: f64) -> u64 {
+        self.id_hasher.hash(id) ^ self.point_hasher.hash(point.to_bits())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use rand_core::RngCore;
+
+    use crate::{
+        growable_ers::GrowableErs,
+        rng_utils::{MtRng, mt_from_seed},
+        similarity::{jaccard_estimate_from_minhashes, jaccard_similarity},
+        test_utils::{assert_estimate_close, generate_weighted_set, uniform01},
+    };
+
+    /// Keep a fraction of x's ids with rescaled weights and add new ids, so y
+    /// needs both raised caps and new dimensions.
+    fn generate_drifted_set(x: &[(u64, f64)], keep: f64, rng: &mut MtRng) -> Vec<(u64, f64)> {
+        let mut y = Vec::with_capacity(x.len());
+        for &(id, w) in x {
+            if uniform01(rng) < keep {
+                y.push((id, w * (0.5 + uniform01(rng))));
+            } else {
+                y.push((rng.next_u64(), w));
+            }
+        }
+        y.sort_by_key(|p| p.0);
+        y.dedup_by_key(|p| p.0);
+        y
+    }
+
+    #[test]
+    fn growable_ers_keeps_old_sketches_valid() {
+        let mut data_rng = mt_from_seed(1337);
+        let mut hash_rng = mt_from_seed(0x960e_0001);
+        let k = 2048;
+
+        let x = generate_weighted_set(2_000, 100.0, &mut data_rng);
+        let mut ers = GrowableErs::new_sparse(&mut hash_rng, &x, k);
+        assert_eq!(ers.num_blocks(), 1);
+        let sk_x_old = ers.sketch(&x);
+        assert!(sk_x_old.iter().all(|&(_, t)| t.is_finite()));
+
+        for (round, &keep) in [0.9, 0.6, 0.3].iter().enumerate() {
+            let y = generate_drifted_set(&x, keep, &mut data_rng);
+            // Grow the index for y: new ids and raised caps of shared ids.
+            assert!(ers.raise_caps(&y));
+            assert_eq!(ers.num_blocks(), round + 2);
+            assert!(!ers.raise_caps(&y));
+
+            // Old sketches are unchanged by the new blocks ...
+            assert_eq!(ers.sketch(&x), sk_x_old);
+
+            // ... and compare correctly with sketches that need the new caps.
+            let j_true = jaccard_similarity(&x, &y);
+            let j_est = jaccard_estimate_from_minhashes(&sk_x_old, &ers.sketch(&y));
+            assert_estimate_close(j_true, j_est, k, format!("keep={keep}"));
+        }
+    }
+
+    #[test]
+    fn growable_ers_caps_and_clipping() {
+        let mut hash_rng = mt_from_seed(0x960e_0002);
+        let mut ers = GrowableErs::new(&mut hash_rng, 256);
+        assert!(
+            ers.sketch(&[(1, 1.0)])
+                .iter()
+                .all(|&(_, t)| t.is_infinite())
+        );
+
+        assert!(ers.raise_caps(&[(1, 1.0), (2, 2.0), (1, 0.5)]));
+        assert!(!ers.raise_caps(&[(1, 0.75), (2, 2.0)]));
+        assert!(ers.raise_caps(&[(2, 3.0), (5, 1.0)]));
+        assert_eq!(ers.cap_of(1), 1.0);
+        assert_eq!(ers.cap_of(2), 3.0);
+        assert_eq!(ers.cap_of(7), 0.0);
+        assert!((ers.m_total() - 5.0).abs() < 1e-12);
+
+        // Weights above the cap are clipped, ids without a cap ignored.
+        let x = vec![(1, 1.0), (2, 3.0)];
+        assert_eq!(ers.sketch(&x), ers.sketch(&[(1, 5.0), (2, 3.0), (9, 4.0)]));
+    }
+}
@@ -1,7 +1,10 @@
+use std::io;
+
 use rand_core::RngCore;
-#[cfg(feature = "mixed_tab")]
-use tab_hash::{Tab32Mixed, Tab64Mixed};
-use tab_hash::{Tab32Simple, Tab64Simple};
+use tab_hash::{Tab32Mixed, Tab32Simple, Tab64Mixed, Tab64Simple};
+
+use crate::persist::{StateReader, StateWriter, TableState, expect_len};
+use crate::similarity::jaccard_estimate_from_minhashes;
 
 // Convert a u64 to a uniform double in [0,1)
 #[inline]
@@ -18,66 +21,857 @@ pub fn to_units(x: u64) -> (f64, f64) {
     ((hi as f64) / 4294967295.0, (lo as f64) / 4294967295.0)
 }
 
-// Simple wrapper to create a Tab32Simple table seeded by an RNG
-pub fn tab32_from_rng<R: RngCore>(rng: &mut R) -> Tab32Simple {
+// Table of a Tab32Simple, drawn from an RNG.
+fn simple_tab32_table<R: RngCore + ?Sized>(rng: &mut R) -> Vec<Vec<u32>> {
     let mut table = vec![vec![0u32; 256]; 4];
-    for i in 0..4 {
-        for j in 0..256 {
-            table[i][j] = rng.next_u32();
-        }
+    for v in table.iter_mut().flatten() {
+        *v = rng.next_u32();
     }
-    Tab32Simple::from_vec(table)
+    table
 }
 
-// Wrapper to create a Tab64Simple table seeded by an RNG
-pub fn tab64_from_rng<R: RngCore>(rng: &mut R) -> Tab64Simple {
+// Simple wrapper to create a Tab32Simple table seeded by an RNG
+pub fn tab32_from_rng<R: RngCore + ?Sized>(rng: &mut R) -> Tab32Simple {
+    Tab32Simple::from_vec(simple_tab32_table(rng))
+}
+
+// Table of a Tab64Simple, drawn from an RNG.
+fn simple_tab64_table<R: RngCore + ?Sized>(rng: &mut R) -> Vec<Vec<u64>> {
     let mut table = vec![vec![0u64; 256]; 8];
-    for i in 0..8 {
-        for j in 0..256 {
-            table[i][j] = rng.next_u64();
-        }
+    for v in table.iter_mut().flatten() {
+        *v = rng.next_u64();
     }
-    Tab64Simple::from_vec(table)
+    table
 }
 
-// Wrapper to create a Tab32Mixed table seeded by an RNG.
-#[cfg(feature = "mixed_tab")]
-pub fn mixed_tab32_from_rng<R: RngCore>(rng: &mut R) -> Tab32Mixed {
+// Wrapper to create a Tab64Simple table seeded by an RNG
+pub fn tab64_from_rng<R: RngCore + ?Sized>(rng: &mut R) -> Tab64Simple {
+    Tab64Simple::from_vec(simple_tab64_table(rng))
+}
+
+// Tables of a Tab32Mixed, drawn from an RNG.
+fn mixed_tab32_tables<R: RngCore + ?Sized>(rng: &mut R) -> (Vec<Vec<u64>>, Vec<Vec<u32>>) {
     let mut first_table = vec![vec![0u64; 256]; 4];
     let mut second_table = vec![vec![0u32; 256]; 4];
 
-    for i in 0..4 {
-        for j in 0..256 {
-            first_table[i][j] = rng.next_u64();
-            second_table[i][j] = rng.next_u32();
-        }
+    let cells = first_table.iter_mut().flatten();
+    for (first, second) in cells.zip(second_table.iter_mut().flatten()) {
+        *first = rng.next_u64();
+        *second = rng.next_u32();
     }
 
+    (first_table, second_table)
+}
+
+// Wrapper to create a Tab32Mixed table seeded by an RNG.
+pub fn mixed_tab32_from_rng<R: RngCore + ?Sized>(rng: &mut R) -> Tab32Mixed {
+    let (first_table, second_table) = mixed_tab32_tables(rng);
     Tab32Mixed::from_vec(first_table, second_table)
 }
 
-// Wrapper to create a Tab64Mixed table seeded by an RNG.
-#[cfg(feature = "mixed_tab")]
-pub fn mixed_tab64_from_rng<R: RngCore>(rng: &mut R) -> Tab64Mixed {
+// Tables of a Tab64Mixed, drawn from an RNG.
+fn mixed_tab64_tables<R: RngCore + ?Sized>(rng: &mut R) -> (Vec<Vec<u128>>, Vec<Vec<u64>>) {
     let mut first_table = vec![vec![0u128; 256]; 8];
     let mut second_table = vec![vec![0u64; 256]; 4];
 
-    for i in 0..8 {
-        for j in 0..256 {
-            first_table[i][j] = ((rng.next_u64() as u128) << 64) | (rng.next_u64() as u128);
+    for v in first_table.iter_mut().flatten() {
+        *v = ((rng.next_u64() as u128) << 64) | (rng.next_u64() as u128);
+    }
+    for v in second_table.iter_mut().flatten() {
+        *v = rng.next_u64();
+    }
+
+    (first_table, second_table)
+}
+
+// Wrapper to create a Tab64Mixed table seeded by an RNG.
+pub fn mixed_tab64_from_rng<R: RngCore + ?Sized>(rng: &mut R) -> Tab64Mixed {
+    let (first_table, second_table) = mixed_tab64_tables(rng);
+    Tab64Mixed::from_vec(first_table, second_table)
+}
+
+// 32-bit hash function drawn from a `HashFamily`.
+pub trait Hash32 {
+    fn hash(&self, key: u32) -> u32;
+}
+
+// 64-bit hash function drawn from a `HashFamily`.
+pub trait Hash64 {
+    fn hash(&self, key: u64) -> u64;
+}
+
+impl Hash32 for Tab32Simple {
+    #[inline]
+    fn hash(&self, key: u32) -> u32 {
+        Tab32Simple::hash(self, key)
+    }
+}
+
+impl Hash64 for Tab64Simple {
+    #[inline]
+    fn hash(&self, key: u64) -> u64 {
+        Tab64Simple::hash(self, key)
+    }
+}
+
+impl Hash32 for Tab32Mixed {
+    #[inline]
+    fn hash(&self, key: u32) -> u32 {
+        Tab32Mixed::hash(self, key)
+    }
+}
+
+impl Hash64 for Tab64Mixed {
+    #[inline]
+    fn hash(&self, key: u64) -> u64 {
+        Tab64Mixed::hash(self, key)
+    }
+}
+
+// Identifies a hash family, so sketches can record which one produced them.
+// Sketches are only comparable if they use the same family (and seed).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HashKind {
+    SimpleTab,
+    MixedTab,
+    TwistedTab,
+    DoubleTab,
+    FastMix,
+}
+
+// A MinHash sketch together with the family of the sketcher that produced it,
+// from the `sketch_tagged` methods. The family can't tell seeds apart, so
+// comparable sketches still need the same sketcher.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FamilySketch {
+    pub hash_family: HashKind,
+    pub minhashes: Vec<(u64, f64)>,
+}
+
+impl FamilySketch {
+    // Weighted Jaccard estimate, or `None` for sketches of different families
+    // or sizes.
+    pub fn jaccard_estimate(&self, other: &FamilySketch) -> Option<f64> {
+        let comparable =
+            self.hash_family == other.hash_family && self.minhashes.len() == other.minhashes.len();
+        comparable.then(|| jaccard_estimate_from_minhashes(&self.minhashes, &other.minhashes))
+    }
+}
+
+// Source of the hash functions of a sketcher. Every sketcher is generic over
+// its family and draws all its hash functions from the RNG through it, so one
+// binary can hold sketchers of different families.
+pub trait HashFamily: Clone + Default {
+    type H32: Hash32 + TableState;
+    type H64: Hash64 + TableState;
+
+    fn kind(&self) -> HashKind;
+    fn hash32_from_rng<R: RngCore + ?Sized>(&self, rng: &mut R) -> Self::H32;
+    fn hash64_from_rng<R: RngCore + ?Sized>(&self, rng: &mut R) -> Self::H64;
+}
+
+// Simple tabulation (the default).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SimpleTab;
+
+// Simple tabulation hashers of the `SimpleTab` family: one table row per key
+// byte, lowest byte first, XORed together. They hash from the table they
+// export, since `Tab32Simple` and `Tab64Simple` do not hand theirs back.
+pub struct SimpleTab32 {
+    table: Vec<Vec<u32>>,
+}
+
+impl SimpleTab32 {
+    pub fn from_rng<R: RngCore + ?Sized>(rng: &mut R) -> Self {
+        Self {
+            table: simple_tab32_table(rng),
         }
     }
-    for i in 0..4 {
-        for j in 0..256 {
-            second_table[i][j] = rng.next_u64();
+
+    #[inline]
+    pub fn hash(&self, key: u32) -> u32 {
+        let mut h = 0;
+        for (i, row) in self.table.iter().enumerate() {
+            h ^= row[((key >> (8 * i)) & 0xff) as usize];
         }
+        h
     }
+}
 
-    Tab64Mixed::from_vec(first_table, second_table)
+impl Hash32 for SimpleTab32 {
+    #[inline]
+    fn hash(&self, key: u32) -> u32 {
+        SimpleTab32::hash(self, key)
+    }
+}
+
+pub struct SimpleTab64 {
+    table: Vec<Vec<u64>>,
+}
+
+impl SimpleTab64 {
+    pub fn from_rng<R: RngCore + ?Sized>(rng: &mut R) -> Self {
+        Self {
+            table: simple_tab64_table(rng),
+        }
+    }
+
+    #[inline]
+    pub fn hash(&self, key: u64) -> u64 {
+        let mut h = 0;
+        for (i, row) in self.table.iter().enumerate() {
+            h ^= row[((key >> (8 * i)) & 0xff) as usize];
+        }
+        h
+    }
+}
+
+impl Hash64 for SimpleTab64 {
+    #[inline]
+    fn hash(&self, key: u64) -> u64 {
+        SimpleTab64::hash(self, key)
+    }
+}
+
+impl HashFamily for SimpleTab {
+    type H32 = SimpleTab32;
+    type H64 = SimpleTab64;
+
+    fn kind(&self) -> HashKind {
+        HashKind::SimpleTab
+    }
+
+    fn hash32_from_rng<R: RngCore + ?Sized>(&self, rng: &mut R) -> SimpleTab32 {
+        SimpleTab32::from_rng(rng)
+    }
+
+    fn hash64_from_rng<R: RngCore + ?Sized>(&self, rng: &mut R) -> SimpleTab64 {
+        SimpleTab64::from_rng(rng)
+    }
+}
+
+// Mixed tabulation, with stronger concentration guarantees than simple
+// tabulation at about twice the cost.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MixedTab;
+
+// Mixed tabulation hashers of the `MixedTab` family. `Tab32Mixed` and
+// `Tab64Mixed` do not expose their tables, so these keep a copy for export.
+pub struct MixedTab32 {
+    first: Vec<Vec<u64>>,
+    second: Vec<Vec<u32>>,
+    inner: Tab32Mixed,
+}
+
+impl MixedTab32 {
+    pub fn from_rng<R: RngCore + ?Sized>(rng: &mut R) -> Self {
+        let (first, second) = mixed_tab32_tables(rng);
+        Self::from_tables(first, second)
+    }
+
+    #[inline]
+    pub fn hash(&self, key: u32) -> u32 {
+        self.inner.hash(key)
+    }
+
+    fn from_tables(first: Vec<Vec<u64>>, second: Vec<Vec<u32>>) -> Self {
+        let inner = Tab32Mixed::from_vec(first.clone(), second.clone());
+        Self {
+            first,
+            second,
+            inner,
+        }
+    }
+}
+
+impl Hash32 for MixedTab32 {
+    #[inline]
+    fn hash(&self, key: u32) -> u32 {
+        MixedTab32::hash(self, key)
+    }
+}
+
+pub struct MixedTab64 {
+    first: Vec<Vec<u128>>,
+    second: Vec<Vec<u64>>,
+    inner: Tab64Mixed,
+}
+
+impl MixedTab64 {
+    pub fn from_rng<R: RngCore + ?Sized>(rng: &mut R) -> Self {
+        let (first, second) = mixed_tab64_tables(rng);
+        Self::from_tables(first, second)
+    }
+
+    #[inline]
+    pub fn hash(&self, key: u64) -> u64 {
+        self.inner.hash(key)
+    }
+
+    fn from_tables(first: Vec<Vec<u128>>, second: Vec<Vec<u64>>) -> Self {
+        let inner = Tab64Mixed::from_vec(first.clone(), second.clone());
+        Self {
+            first,
+            second,
+            inner,
+        }
+    }
+}
+
+impl Hash64 for MixedTab64 {
+    #[inline]
+    fn hash(&self, key: u64) -> u64 {
+        MixedTab64::hash(self, key)
+    }
+}
+
+impl HashFamily for MixedTab {
+    type H32 = MixedTab32;
+    type H64 = MixedTab64;
+
+    fn kind(&self) -> HashKind {
+        HashKind::MixedTab
+    }
+
+    fn hash32_from_rng<R: RngCore + ?Sized>(&self, rng: &mut R) -> MixedTab32 {
+        MixedTab32::from_rng(rng)
+    }
+
+    fn hash64_from_rng<R: RngCore + ?Sized>(&self, rng: &mut R) -> MixedTab64 {
+        MixedTab64::from_rng(rng)
+    }
+}
+
+// Keyed multiply-fold hash (two wyhash-style 128-bit multiply rounds). No
+// tables, so it is cache-friendly and cheap to build, but it comes without
+// the independence guarantees of tabulation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FastMix;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mix64 {
+    k0: u64,
+    k1: u64,
+    k2: u64,
+}
+
+impl Mix64 {
+    pub fn from_rng<R: RngCore + ?Sized>(rng: &mut R) -> Self {
+        Self {
+            k0: rng.next_u64(),
+            // odd, so the first multiply is a bijection of the key
+            k1: rng.next_u64() | 1,
+            k2: rng.next_u64(),
+        }
+    }
+}
+
+#[inline]
+fn fold_mul(a: u64, b: u64) -> u64 {
+    let m = (a as u128) * (b as u128);
+    (m as u64) ^ ((m >> 64) as u64)
+}
+
+impl Hash64 for Mix64 {
+    #[inline]
+    fn hash(&self, key: u64) -> u64 {
+        let z = fold_mul(key ^ self.k0, self.k1);
+        fold_mul(z ^ self.k2, 0x9e37_79b9_7f4a_7c15)
+    }
+}
+
+impl Hash32 for Mix64 {
+    #[inline]
+    fn hash(&self, key: u32) -> u32 {
+        (Hash64::hash(self, key as u64) >> 32) as u32
+    }
+}
+
+impl HashFamily for FastMix {
+    type H32 = Mix64;
+    type H64 = Mix64;
+
+    fn kind(&self) -> HashKind {
+        HashKind::FastMix
+    }
+
+    fn hash32_from_rng<R: RngCore + ?Sized>(&self, rng: &mut R) -> Mix64 {
+        Mix64::from_rng(rng)
+    }
+
+    fn hash64_from_rng<R: RngCore + ?Sized>(&self, rng: &mut R) -> Mix64 {
+        Mix64::from_rng(rng)
+    }
+}
+
+// Twisted tabulation (Pătraşcu and Thorup): the first c-1 characters are
+// hashed by simple tabulation, which also yields an 8-bit twister XORed into
+// the last character before its lookup. Chernoff-style concentration at the
+// cost of one extra dependent lookup.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TwistedTab;
+
+// 64-bit twisted tabulation: each entry holds the hash in its high 64 bits and
+// the twister in its low 8 bits.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TwistedTab64 {
+    table: Vec<u128>,
+}
+
+impl TwistedTab64 {
+    pub fn from_rng<R: RngCore + ?Sized>(rng: &mut R) -> Self {
+        let table = (0..8 * 256)
+            .map(|_| ((rng.next_u64() as u128) << 64) | (rng.next_u64() as u128))
+            .collect();
+        Self { table }
+    }
+}
+
+impl Hash64 for TwistedTab64 {
+    #[inline]
+    fn hash(&self, key: u64) -> u64 {
+        let mut h = 0u128;
+        for i in 0..7 {
+            let c = ((key >> (8 * i)) & 0xff) as usize;
+            h ^= self.table[i * 256 + c];
+        }
+        let last = ((key >> 56) as u8 ^ h as u8) as usize;
+        ((h ^ self.table[7 * 256 + last]) >> 64) as u64
+    }
+}
+
+// 32-bit twisted tabulation, with the hash in the high 32 bits of each entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TwistedTab32 {
+    table: Vec<u64>,
+}
+
+impl TwistedTab32 {
+    pub fn from_rng<R: RngCore + ?Sized>(rng: &mut R) -> Self {
+        let table = (0..4 * 256).map(|_| rng.next_u64()).collect();
+        Self { table }
+    }
+}
+
+impl Hash32 for TwistedTab32 {
+    #[inline]
+    fn hash(&self, key: u32) -> u32 {
+        let mut h = 0u64;
+        for i in 0..3 {
+            let c = ((key >> (8 * i)) & 0xff) as usize;
+            h ^= self.table[i * 256 + c];
+        }
+        let last = ((key >> 24) as u8 ^ h as u8) as usize;
+        ((h ^ self.table[3 * 256 + last]) >> 32) as u32
+    }
+}
+
+impl HashFamily for TwistedTab {
+    type H32 = TwistedTab32;
+    type H64 = TwistedTab64;
+
+    fn kind(&self) -> HashKind {
+        HashKind::TwistedTab
+    }
+
+    fn hash32_from_rng<R: RngCore + ?Sized>(&self, rng: &mut R) -> TwistedTab32 {
+        TwistedTab32::from_rng(rng)
+    }
+
+    fn hash64_from_rng<R: RngCore + ?Sized>(&self, rng: &mut R) -> TwistedTab64 {
+        TwistedTab64::from_rng(rng)
+    }
+}
+
+// Number of derived words per first-stage entry of double tabulation: the
+// derived key has 6c characters, which makes the second stage fully random
+// on any fixed set of keys with high probability (Thorup).
+const DOUBLE_TAB_DERIVED: usize = 6;
+
+// Double tabulation: simple tabulation into a derived key of 6c characters,
+// hashed again by simple tabulation. The strongest family here and the most
+// expensive one (56 lookups for a 64-bit key).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DoubleTab;
+
+// 64-bit double tabulation: 8 characters into 6 derived words (48 characters).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DoubleTab64 {
+    first: Vec<[u64; DOUBLE_TAB_DERIVED]>,
+    second: Vec<u64>,
+}
+
+impl DoubleTab64 {
+    pub fn from_rng<R: RngCore + ?Sized>(rng: &mut R) -> Self {
+        let first = (0..8 * 256)
+            .map(|_| std::array::from_fn(|_| rng.next_u64()))
+            .collect();
+        let second = (0..8 * DOUBLE_TAB_DERIVED * 256)
+            .map(|_| rng.next_u64())
+            .collect();
+        Self { first, second }
+    }
+}
+
+impl Hash64 for DoubleTab64 {
+    #[inline]
+    fn hash(&self, key: u64) -> u64 {
+        let mut derived = [0u64; DOUBLE_TAB_DERIVED];
+        for i in 0..8 {
+            let c = ((key >> (8 * i)) & 0xff) as usize;
+            for (d, e) in derived.iter_mut().zip(&self.first[i * 256 + c]) {
+                *d ^= e;
+            }
+        }
+        let mut h = 0u64;
+        for (w, &d) in derived.iter().enumerate() {
+            for b in 0..8 {
+                let c = ((d >> (8 * b)) & 0xff) as usize;
+                h ^= self.second[(w * 8 + b) * 256 + c];
+            }
+        }
+        h
+    }
+}
+
+// 32-bit double tabulation: 4 characters into 6 derived words (24 characters).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DoubleTab32 {
+    first: Vec<[u32; DOUBLE_TAB_DERIVED]>,
+    second: Vec<u32>,
+}
+
+impl DoubleTab32 {
+    pub fn from_rng<R: RngCore + ?Sized>(rng: &mut R) -> Self {
+        let first = (0..4 * 256)
+            .map(|_| std::array::from_fn(|_| rng.next_u32()))
+            .collect();
+        let second = (0..4 * DOUBLE_TAB_DERIVED * 256)
+            .map(|_| rng.next_u32())
+            .collect();
+        Self { first, second }
+    }
+}
+
+impl Hash32 for DoubleTab32 {
+    #[inline]
+    fn hash(&self, key: u32) -> u32 {
+        let mut derived = [0u32; DOUBLE_TAB_DERIVED];
+        for i in 0..4 {
+            let c = ((key >> (8 * i)) & 0xff) as usize;
+            for (d, e) in derived.iter_mut().zip(&self.first[i * 256 + c]) {
+                *d ^= e;
+            }
+        }
+        let mut h = 0u32;
+        for (w, &d) in derived.iter().enumerate() {
+            for b in 0..4 {
+                let c = ((d >> (8 * b)) & 0xff) as usize;
+                h ^= self.second[(w * 4 + b) * 256 + c];
+            }
+        }
+        h
+    }
+}
+
+impl HashFamily for DoubleTab {
+    type H32 = DoubleTab32;
+    type H64 = DoubleTab64;
+
+    fn kind(&self) -> HashKind {
+        HashKind::DoubleTab
+    }
+
+    fn hash32_from_rng<R: RngCore + ?Sized>(&self, rng: &mut R) -> DoubleTab32 {
+        DoubleTab32::from_rng(rng)
+    }
+
+    fn hash64_from_rng<R: RngCore + ?Sized>(&self, rng: &mut R) -> DoubleTab64 {
+        DoubleTab64::from_rng(rng)
+    }
+}
+
+// Export of the hash tables. Tables are written row by row, each as a
+// length-prefixed sequence.
+
+fn write_rows<T: Copy>(w: &mut StateWriter, rows: &[Vec<T>], put: fn(&mut StateWriter, &[T])) {
+    w.put_len(rows.len());
+    for row in rows {
+        put(w, row);
+    }
 }
 
+fn read_rows<'a, T>(
+    r: &mut StateReader<'a>,
+    rows: usize,
+    get: fn(&mut StateReader<'a>) -> io::Result<Vec<T>>,
+    what: &str,
+) -> io::Result<Vec<Vec<T>>> {
+    let n = r.get_len(8)?;
+    let table = (0..n).map(|_| get(r)).collect::<io::Result<Vec<_>>>()?;
+    expect_len(&table, rows, what)?;
+    for row in &table {
+        expect_len(row, 256, what)?;
+    }
+    Ok(table)
+}
+
+impl TableState for SimpleTab32 {
+    fn write_state(&self, w: &mut StateWriter) {
+        write_rows(w, &self.table, StateWriter::put_u32s);
+    }
+
+    fn read_state(r: &mut StateReader) -> io::Result<Self> {
+        let table = read_rows(r, 4, StateReader::get_u32s, "SimpleTab32")?;
+        Ok(Self { table })
+    }
+}
+
+impl TableState for SimpleTab64 {
+    fn write_state(&self, w: &mut StateWriter) {
+        write_rows(w, &self.table, StateWriter::put_u64s);
+    }
+
+    fn read_state(r: &mut StateReader) -> io::Result<Self> {
+        let table = read_rows(r, 8, StateReader::get_u64s, "SimpleTab64")?;
+        Ok(Self { table })
+    }
+}
+
+impl TableState for MixedTab32 {
+    fn write_state(&self, w: &mut StateWriter) {
+        write_rows(w, &self.first, StateWriter::put_u64s);
+        write_rows(w, &self.second, StateWriter::put_u32s);
+    }
+
+    fn read_state(r: &mut StateReader) -> io::Result<Self> {
+        let first = read_rows(r, 4, StateReader::get_u64s, "MixedTab32")?;
+        let second = read_rows(r, 4, StateReader::get_u32s, "MixedTab32")?;
+        Ok(Self::from_tables(first, second))
+    }
+}
+
+impl TableState for MixedTab64 {
+    fn write_state(&self, w: &mut StateWriter) {
+        write_rows(w, &self.first, StateWriter::put_u128s);
+        write_rows(w, &self.second, StateWriter::put_u64s);
+    }
+
+    fn read_state(r: &mut StateReader) -> io::Result<Self> {
+        let first = read_rows(r, 8, StateReader::get_u128s, "MixedTab64")?;
+        let second = read_rows(r, 4, StateReader::get_u64s, "MixedTab64")?;
+        Ok(Self::from_tables(first, second))
+    }
+}
+
+impl TableState for Mix64 {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.put_u64(self.k0);
+        w.put_u64(self.k1);
+        w.put_u64(self.k2);
+    }
+
+    fn read_state(r: &mut StateReader) -> io::Result<Self> {
+        Ok(Self {
+            k0: r.get_u64()?,
+            k1: r.get_u64()?,
+            k2: r.get_u64()?,
+        })
+    }
+}
+
+impl TableState for TwistedTab32 {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.put_u64s(&self.table);
+    }
+
+    fn read_state(r: &mut StateReader) -> io::Result<Self> {
+        let table = r.get_u64s()?;
+        expect_len(&table, 4 * 256, "TwistedTab32")?;
+        Ok(Self { table })
+    }
+}
+
+impl TableState for TwistedTab64 {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.put_u128s(&self.table);
+    }
+
+    fn read_state(r: &mut StateReader) -> io::Result<Self> {
+        let table = r.get_u128s()?;
+        expect_len(&table, 8 * 256, "TwistedTab64")?;
+        Ok(Self { table })
+    }
+}
+
+impl TableState for DoubleTab32 {
+    fn write_state(&self, w: &mut StateWriter) {
+        let first: Vec<u32> = self.first.iter().flatten().copied().collect();
+        w.put_u32s(&first);
+        w.put_u32s(&self.second);
+    }
+
+    fn read_state(r: &mut StateReader) -> io::Result<Self> {
+        let first = r.get_u32s()?;
+        expect_len(&first, 4 * 256 * DOUBLE_TAB_DERIVED, "DoubleTab32")?;
+        let second = r.get_u32s()?;
+        expect_len(&second, 4 * DOUBLE_TAB_DERIVED * 256, "DoubleTab32")?;
+        let first = first
+            .chunks_exact(DOUBLE_TAB_DERIVED)
+            .map(|c| c.try_into().unwrap())
+            .collect();
+        Ok(Self { first, second })
+    }
+}
+
+impl TableState for DoubleTab64 {
+    fn write_state(&self, w: &mut StateWriter) {
+        let first: Vec<u64> = self.first.iter().flatten().copied().collect();
+        w.put_u64s(&first);
+        w.put_u64s(&self.second);
+    }
+
+    fn read_state(r: &mut StateReader) -> io::Result<Self> {
+        let first = r.get_u64s()?;
+        expect_len(&first, 8 * 256 * DOUBLE_TAB_DERIVED, "DoubleTab64")?;
+        let second = r.get_u64s()?;
+        expect_len(&second, 8 * DOUBLE_TAB_DERIVED * 256, "DoubleTab64")?;
+        let first = first
+            .chunks_exact(DOUBLE_TAB_DERIVED)
+            .map(|c| c.try_into().unwrap())
+            .collect();
+        Ok(Self { first, second })
+    }
+}
+
+// Family of `new`, `new_mt` and the other default constructors: mixed tabulation
+// with the `mixed_tab` feature, simple tabulation otherwise.
+#[cfg(feature = "mixed_tab")]
+pub type DefaultFamily = MixedTab;
+#[cfg(not(feature = "mixed_tab"))]
+pub type DefaultFamily = SimpleTab;
+
 // Weighted sum helper
 
 pub fn total_weight(x: &[(u64, f64)]) -> f64 {
     x.iter().map(|(_, w)| w).sum()
 }
+
+#[cfg(test)]
+mod tests {
+    use rand_core::RngCore;
+
+    use crate::{
+        dartminhash::DartMinHash,
+        hash_utils::{
+            DefaultFamily, DoubleTab, FastMix, HashFamily, HashKind, MixedTab, SimpleTab,
+            SimpleTab32, SimpleTab64, TwistedTab, tab32_from_rng, tab64_from_rng,
+        },
+        rejsmp::ErsWmh,
+        rng_utils::mt_from_seed,
+        similarity::{jaccard_estimate_from_minhashes, jaccard_similarity},
+        test_utils::{
+            assert_estimate_close, generate_similar_weighted_set_in, generate_weighted_set_in,
+        },
+        treeminhash::TreeMinHash,
+    };
+
+    fn check_family<F: HashFamily>(family: F, seed: u64) {
+        let mut data_rng = mt_from_seed(seed);
+        let mut hash_rng = mt_from_seed(seed ^ 0x4a5f_0046);
+        let d = 2_000u64;
+        let k = 1_024u64;
+        let kind = family.kind();
+
+        let dmh = DartMinHash::with_family(&mut hash_rng, k, family.clone());
+        let tmh = TreeMinHash::with_family(&mut hash_rng, k, family.clone());
+        assert_eq!((dmh.hash_family(), tmh.hash_family()), (kind, kind));
+        let tagged = |x: &[(u64, f64)], y: &[(u64, f64)]| {
+            let (sx, sy) = (tmh.sketch_tagged(x), tmh.sketch_tagged(y));
+            assert_eq!((sx.hash_family, sy.hash_family), (kind, kind));
+            sx.jaccard_estimate(&sy).unwrap()
+        };
+
+        let x = generate_weighted_set_in(d, 200, 20.0, &mut data_rng);
+        for rel in [0.9, 0.5, 0.1] {
+            let y = generate_similar_weighted_set_in(d, &x, rel, &mut data_rng);
+            let mut caps = vec![0.0; d as usize];
+            for &(id, w) in x.iter().chain(&y) {
+                caps[id as usize] = f64::max(caps[id as usize], w);
+            }
+            let ers = ErsWmh::with_family(&mut hash_rng, &caps, k, family.clone());
+            assert_eq!(ers.hash_family(), kind);
+            assert_eq!(ers.sketch_tagged(&x, Some(1)).hash_family, kind);
+
+            let j = jaccard_similarity(&x, &y);
+            let estimates = [
+                (
+                    "DartMinHash",
+                    jaccard_estimate_from_minhashes(&dmh.sketch(&x), &dmh.sketch(&y)),
+                ),
+                ("TreeMinHash", tagged(&x, &y)),
+                (
+                    "ErsWmh",
+                    jaccard_estimate_from_minhashes(
+                        &ers.sketch_auto(&x, 1e-3),
+                        &ers.sketch_auto(&y, 1e-3),
+                    ),
+                ),
+            ];
+            for (name, est) in estimates {
+                assert_estimate_close(j, est, k, format!("{kind:?} {name}"));
+            }
+        }
+    }
+
+    #[test]
+    fn hash_families_in_one_binary() {
+        check_family(SimpleTab, 46_001);
+        check_family(MixedTab, 46_002);
+        check_family(FastMix, 46_003);
+        check_family(TwistedTab, 47_001);
+        check_family(DoubleTab, 47_002);
+    }
+
+    #[test]
+    fn simple_tab_hashers_match_tab_hash() {
+        let (h32, t32) = (
+            SimpleTab32::from_rng(&mut mt_from_seed(50_001)),
+            tab32_from_rng(&mut mt_from_seed(50_001)),
+        );
+        let (h64, t64) = (
+            SimpleTab64::from_rng(&mut mt_from_seed(50_002)),
+            tab64_from_rng(&mut mt_from_seed(50_002)),
+        );
+        let mut keys = mt_from_seed(50_003);
+        for _ in 0..1_000 {
+            let key = keys.next_u64();
+            assert_eq!(h32.hash(key as u32), t32.hash(key as u32));
+            assert_eq!(h64.hash(key), t64.hash(key));
+        }
+    }
+
+    #[test]
+    fn default_family_matches_new_mt() {
+        let x = generate_weighted_set_in(1 << 20, 300, 5.0, &mut mt_from_seed(46_010));
+        let dmh = DartMinHash::new_mt(&mut mt_from_seed(7), 128);
+        let generic = DartMinHash::with_family(&mut mt_from_seed(7), 128, DefaultFamily::default());
+        assert_eq!(dmh.sketch(&x), generic.sketch(&x));
+        let expected = if cfg!(feature = "mixed_tab") {
+            HashKind::MixedTab
+        } else {
+            HashKind::SimpleTab
+        };
+        assert_eq!(dmh.hash_family(), expected);
+
+        // Same seed, different family: unrelated sketches.
+        let fast = DartMinHash::with_family(&mut mt_from_seed(7), 128, FastMix);
+        let j = jaccard_estimate_from_minhashes(&dmh.sketch(&x), &fast.sketch(&x));
+        assert!(j < 0.1, "{j}");
+        // Tagged sketches refuse the comparison.
+        let tagged = dmh.sketch_tagged(&x);
+        assert_eq!(tagged.minhashes, dmh.sketch(&x));
+        assert_eq!(tagged.jaccard_estimate(&fast.sketch_tagged(&x)), None);
+        assert_eq!(tagged.jaccard_estimate(&tagged), Some(1.0));
+    }
+}
@@ -7,22 +7,59 @@
 //!
 //! Feature universe element = `(u64 id, f64 weight)`
 
+pub mod auto_sketcher;
+pub mod bagminhash;
+pub mod caps;
+pub mod cws;
 pub mod darthash;
 pub mod dartminhash;
+pub mod fastgm;
+pub mod growable_ers;
 pub mod hash_utils;
+pub mod persist;
+pub mod probminhash;
 pub mod rejsmp;
 pub mod rng_utils;
+pub mod scratch;
+pub mod setsketch;
+pub mod simhash;
 pub mod similarity;
+pub mod superminhash;
+#[cfg(test)]
+pub(crate) mod test_utils;
 
+pub use crate::auto_sketcher::{
+    Algorithm, AutoSketch, AutoSketcher, DatasetProfile, SketchMetadata,
+};
+pub use crate::bagminhash::BagMinHash;
+pub use crate::caps::CapsBuilder;
+pub use crate::cws::{Cws, CwsVariant};
 pub use crate::darthash::DartHash;
-pub use crate::dartminhash::DartMinHash;
-pub use crate::rejsmp::ErsWmh;
+pub use crate::dartminhash::{DartMinHash, DartMinHashBuilder, DartMinHashStats};
+pub use crate::fastgm::FastGm;
+pub use crate::growable_ers::GrowableErs;
+pub use crate::hash_utils::{
+    DefaultFamily, DoubleTab, FamilySketch, FastMix, Hash32, Hash64, HashFamily, HashKind,
+    MixedTab, SimpleTab, TwistedTab,
+};
+pub use crate::persist::SketcherState;
+pub use crate::probminhash::ProbMinHash;
+pub use crate::rejsmp::{
+    CapPolicy, CapViolationError, DEFAULT_ATTEMPT_BUDGET, Densification, ErsSketchStats, ErsWmh,
+    RsError,
+};
+pub use crate::rng_utils::SeedDeriver;
+pub use crate::scratch::Scratch;
+pub use crate::setsketch::{CompressedSketch, SetSketchParams, SetSketcher};
+pub use crate::simhash::SimHash;
 pub use crate::similarity::{
-    count_collisions, hamming_distance, intersection, jaccard_estimate_from_minhashes,
-    jaccard_from_l1, jaccard_similarity, l1_from_jaccard, l1_similarity,
-    onebit_minhash_jaccard_estimate, weight,
+    cosine_similarity, count_collisions, hamming_distance, intersection,
+    jaccard_estimate_from_minhashes, jaccard_from_l1, jaccard_similarity, l1_from_jaccard,
+    l1_similarity, onebit_minhash_jaccard_estimate, packed_hamming_distance,
+    probability_jaccard, simhash_cosine_estimate, weight,
 };
+pub use crate::superminhash::SuperMinHash;
 
 pub mod treeminhash;
 
-pub use crate::treeminhash::TreeMinHash;
+pub use crate::treeminhash::{TreeMinHash, TreeMinHashStats};
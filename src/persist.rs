@@ -0,0 +1,466 @@
+//! Binary export and import of sketcher state: hash tables, TreeMinHash
+//! trees and ERS caps.
+//!
+//! A state file holds [`MAGIC`], the [`FORMAT_VERSION`], a sketcher tag, the
+//! hash family and then the sketcher's fields, all little-endian. Floats are
+//! stored as their bit patterns, and derived tables (the DartHash Poisson CDF,
+//! the TreeMinHash tree and limit factors, the ERS alias table) are stored
+//! instead of recomputed. A loaded sketcher therefore produces bit-identical
+//! sketches in any process and with any crate version that reads this format
+//! version, independent of the RNG and seed that built the original.
+//!
+//! [`SketcherState`] provides `to_bytes`, `from_bytes`, `save` and `load`.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::hash_utils::{HashFamily, HashKind};
+
+/// First bytes of every state file.
+pub const MAGIC: [u8; 4] = *b"DMHS";
+
+/// Version of the state layout. Readers reject other versions.
+pub const FORMAT_VERSION: u32 = 1;
+
+pub(crate) fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Little-endian writer of sketcher state.
+#[derive(Clone, Debug, Default)]
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn put_u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn put_u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn put_u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn put_u128(&mut self, v: u128) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn put_f64(&mut self, v: f64) {
+        self.put_u64(v.to_bits());
+    }
+
+    pub fn put_len(&mut self, len: usize) {
+        self.put_u64(len as u64);
+    }
+
+    pub fn put_u32s(&mut self, v: &[u32]) {
+        self.put_len(v.len());
+        v.iter().for_each(|&x| self.put_u32(x));
+    }
+
+    pub fn put_u64s(&mut self, v: &[u64]) {
+        self.put_len(v.len());
+        v.iter().for_each(|&x| self.put_u64(x));
+    }
+
+    pub fn put_u128s(&mut self, v: &[u128]) {
+        self.put_len(v.len());
+        v.iter().for_each(|&x| self.put_u128(x));
+    }
+
+    pub fn put_f64s(&mut self, v: &[f64]) {
+        self.put_len(v.len());
+        v.iter().for_each(|&x| self.put_f64(x));
+    }
+}
+
+/// Little-endian reader of sketcher state. Truncated or inconsistent input
+/// gives `InvalidData` errors, never a panic or an oversized allocation.
+#[derive(Clone, Debug)]
+pub struct StateReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn take<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let end = self.pos + N;
+        let bytes = self
+            .buf
+            .get(self.pos..end)
+            .ok_or_else(|| invalid("truncated sketcher state"))?;
+        self.pos = end;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    pub fn get_u8(&mut self) -> io::Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn get_u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    pub fn get_u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    pub fn get_u128(&mut self) -> io::Result<u128> {
+        Ok(u128::from_le_bytes(self.take()?))
+    }
+
+    pub fn get_f64(&mut self) -> io::Result<f64> {
+        Ok(f64::from_bits(self.get_u64()?))
+    }
+
+    /// Length of a sequence of `elem_size`-byte items, checked against the
+    /// remaining input.
+    pub fn get_len(&mut self, elem_size: usize) -> io::Result<usize> {
+        let len = self.get_u64()?;
+        let remaining = (self.buf.len() - self.pos) as u64;
+        if len.saturating_mul(elem_size as u64) > remaining {
+            return Err(invalid("sequence longer than the sketcher state"));
+        }
+        Ok(len as usize)
+    }
+
+    pub fn get_u32s(&mut self) -> io::Result<Vec<u32>> {
+        let len = self.get_len(4)?;
+        (0..len).map(|_| self.get_u32()).collect()
+    }
+
+    pub fn get_u64s(&mut self) -> io::Result<Vec<u64>> {
+        let len = self.get_len(8)?;
+        (0..len).map(|_| self.get_u64()).collect()
+    }
+
+    pub fn get_u128s(&mut self) -> io::Result<Vec<u128>> {
+        let len = self.get_len(16)?;
+        (0..len).map(|_| self.get_u128()).collect()
+    }
+
+    pub fn get_f64s(&mut self) -> io::Result<Vec<f64>> {
+        let len = self.get_len(8)?;
+        (0..len).map(|_| self.get_f64()).collect()
+    }
+
+    /// Error unless the whole input was read.
+    pub fn finish(&self) -> io::Result<()> {
+        if self.pos == self.buf.len() {
+            Ok(())
+        } else {
+            Err(invalid("trailing bytes after sketcher state"))
+        }
+    }
+}
+
+/// Hash function whose tables can be exported and imported exactly.
+pub trait TableState: Sized {
+    fn write_state(&self, w: &mut StateWriter);
+    fn read_state(r: &mut StateReader) -> io::Result<Self>;
+}
+
+/// Error unless `v` has length `len`.
+pub(crate) fn expect_len<T>(v: &[T], len: usize, what: &str) -> io::Result<()> {
+    if v.len() == len {
+        Ok(())
+    } else {
+        Err(invalid(format!(
+            "{what}: expected {len} entries, found {}",
+            v.len()
+        )))
+    }
+}
+
+// Stable on-disk tags; never reuse or renumber them.
+fn kind_tag(kind: HashKind) -> u8 {
+    match kind {
+        HashKind::SimpleTab => 0,
+        HashKind::MixedTab => 1,
+        HashKind::FastMix => 2,
+        HashKind::TwistedTab => 3,
+        HashKind::DoubleTab => 4,
+    }
+}
+
+/// Write the hash family of a sketcher.
+pub(crate) fn write_family(w: &mut StateWriter, kind: HashKind) {
+    w.put_u8(kind_tag(kind));
+}
+
+/// Read the hash family of a sketcher, which must be `F`.
+pub(crate) fn read_family<F: HashFamily>(r: &mut StateReader) -> io::Result<F> {
+    let family = F::default();
+    let tag = r.get_u8()?;
+    if tag != kind_tag(family.kind()) {
+        return Err(invalid(format!(
+            "state has hash family tag {tag}, expected {:?}",
+            family.kind()
+        )));
+    }
+    Ok(family)
+}
+
+/// Sketchers with exportable state: `DartHash`, `DartMinHash`, `TreeMinHash`
+/// and `ErsWmh`, over any hash family.
+pub trait SketcherState: Sized {
+    /// Stable tag of the sketcher type in the state header.
+    const TAG: u8;
+
+    /// The sketcher's fields, without header.
+    fn write_body(&self, w: &mut StateWriter);
+    fn read_body(r: &mut StateReader) -> io::Result<Self>;
+
+    /// Full state: header and fields.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        MAGIC.iter().for_each(|&b| w.put_u8(b));
+        w.put_u32(FORMAT_VERSION);
+        w.put_u8(Self::TAG);
+        self.write_body(&mut w);
+        w.into_bytes()
+    }
+
+    /// Rebuild a sketcher from `to_bytes` output. Fails on a different
+    /// sketcher type, hash family or format version.
+    fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let mut r = StateReader::new(bytes);
+        if r.take::<4>()? != MAGIC {
+            return Err(invalid("not a sketcher state"));
+        }
+        let version = r.get_u32()?;
+        if version != FORMAT_VERSION {
+            return Err(invalid(format!(
+                "unsupported state format version {version}"
+            )));
+        }
+        let tag = r.get_u8()?;
+        if tag != Self::TAG {
+            return Err(invalid(format!(
+                "state is for sketcher tag {tag}, expected {}",
+                Self::TAG
+            )));
+        }
+        let sketcher = Self::read_body(&mut r)?;
+        r.finish()?;
+        Ok(sketcher)
+    }
+
+    fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        darthash::DartHash,
+        dartminhash::DartMinHash,
+        hash_utils::{DoubleTab, FastMix, HashFamily, MixedTab, SimpleTab, TwistedTab},
+        persist::{FORMAT_VERSION, MAGIC, SketcherState},
+        rejsmp::{CapPolicy, Densification, ErsWmh},
+        rng_utils::SeedDeriver,
+        treeminhash::TreeMinHash,
+    };
+
+    fn sets() -> [Vec<(u64, f64)>; 2] {
+        let x = (0..300).map(|i| (i * 7, 0.5 + (i % 11) as f64)).collect();
+        let y = (150..450).map(|i| (i * 7, 0.25 + (i % 5) as f64)).collect();
+        [x, y]
+    }
+
+    fn round_trip<S: SketcherState>(s: &S) -> S {
+        let bytes = s.to_bytes();
+        let loaded = S::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.to_bytes(), bytes);
+        loaded
+    }
+
+    fn check_round_trip<F: HashFamily>(family: F) {
+        let seeds = SeedDeriver::new(50);
+        let [x, y] = sets();
+        let k = 128;
+
+        let dh = DartHash::with_family(&mut seeds.rng("dh"), 512, family.clone());
+        let dh2 = round_trip(&dh);
+        assert_eq!(dh2.darts(&x, 1.0), dh.darts(&x, 1.0));
+        assert_eq!(dh2.minhash(&y, k), dh.minhash(&y, k));
+
+        let dmh = DartMinHash::with_family(&mut seeds.rng("dmh"), k, family.clone());
+        let dmh2 = round_trip(&dmh);
+        assert_eq!(dmh2.sketch(&x), dmh.sketch(&x));
+        assert_eq!(dmh2.sketch(&y), dmh.sketch(&y));
+
+        let tmh = TreeMinHash::with_family(&mut seeds.rng("tmh"), k, family.clone());
+        let tmh2 = round_trip(&tmh);
+        assert_eq!(tmh2.sketch(&x), tmh.sketch(&x));
+
+        // Caps of x and y only; z exceeds them and goes to the fallback.
+        let mut caps: Vec<(u64, f64)> = x.iter().chain(&y).copied().collect();
+        caps.sort_by_key(|p| p.0);
+        caps.dedup_by(|b, a| {
+            let same = a.0 == b.0;
+            if same {
+                a.1 = a.1.max(b.1);
+            }
+            same
+        });
+        let z: Vec<(u64, f64)> = x.iter().map(|&(id, w)| (id, 2.0 * w)).collect();
+        let fallback = TreeMinHash::with_family(&mut seeds.rng("fallback"), k, family.clone());
+        let ers = ErsWmh::new_sparse_with_family(&mut seeds.rng("ers"), &caps, k, family)
+            .with_densification(Densification::Optimal)
+            .with_cap_policy(CapPolicy::Fallback(Box::new(fallback)));
+        let ers2 = round_trip(&ers);
+        for v in [&x, &y, &z] {
+            let (sk, stats) = ers.sketch_auto_with_stats(v, 1e-3);
+            let (sk2, stats2) = ers2.sketch_auto_with_stats(v, 1e-3);
+            assert_eq!(sk2, sk);
+            assert_eq!(stats2.fallback, stats.fallback);
+        }
+        assert!(ers.sketch_auto_with_stats(&z, 1e-3).1.fallback);
+    }
+
+    #[test]
+    fn state_round_trip_is_bit_identical() {
+        check_round_trip(SimpleTab);
+        check_round_trip(MixedTab);
+        check_round_trip(TwistedTab);
+        check_round_trip(DoubleTab);
+        check_round_trip(FastMix);
+    }
+
+    #[test]
+    fn state_file_save_and_load() {
+        let [x, _] = sets();
+        let dmh = DartMinHash::from_seed(5_000, 256);
+        let path = std::env::temp_dir().join(format!("dmh-state-{}.bin", std::process::id()));
+        dmh.save(&path).unwrap();
+        let loaded: DartMinHash = DartMinHash::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.sketch(&x), dmh.sketch(&x));
+    }
+
+    #[test]
+    fn state_rejects_mismatched_or_corrupt_input() {
+        let dmh = DartMinHash::with_family(&mut SeedDeriver::new(1).rng("dmh"), 64, FastMix);
+        let bytes = dmh.to_bytes();
+        assert_eq!(bytes[..4], MAGIC);
+        assert_eq!(bytes[4..8], FORMAT_VERSION.to_le_bytes());
+        assert_eq!(bytes[8], <DartMinHash<FastMix> as SketcherState>::TAG);
+
+        // Another sketcher type or hash family.
+        assert!(TreeMinHash::<FastMix>::from_bytes(&bytes).is_err());
+        assert!(DartMinHash::<SimpleTab>::from_bytes(&bytes).is_err());
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] ^= 1;
+        let mut bad_version = bytes.clone();
+        bad_version[4] += 1;
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        for bad in [
+            &bad_magic,
+            &bad_version,
+            &trailing,
+            &bytes[..bytes.len() - 1].to_vec(),
+        ] {
+            let err = DartMinHash::<FastMix>::from_bytes(bad).err().unwrap();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
+        // The Poisson CDF closes the DartHash state; corrupt entries are
+        // rejected rather than skewing the darts.
+        let dh = DartHash::with_family(&mut SeedDeriver::new(3).rng("dh"), 64, FastMix);
+        let dh_bytes = dh.to_bytes();
+        for bad in [f64::NAN, f64::INFINITY, 2.0, 0.0, -0.5, 1.0e-9] {
+            let mut corrupt = dh_bytes.clone();
+            let n = corrupt.len();
+            corrupt[n - 8..].copy_from_slice(&bad.to_le_bytes());
+            assert!(DartHash::<FastMix>::from_bytes(&corrupt).is_err(), "{bad}");
+        }
+
+        // `from_caps(&[])` is a valid index, so it must load again.
+        let empty = ErsWmh::with_family(&mut SeedDeriver::new(2).rng("ers"), &[], 4, FastMix);
+        let loaded = ErsWmh::<FastMix>::from_bytes(&empty.to_bytes()).unwrap();
+        assert_eq!(loaded.to_bytes(), empty.to_bytes());
+        assert_eq!(loaded.sketch(&[], Some(64)), empty.sketch(&[], Some(64)));
+
+        // Every truncation is an error, never a panic.
+        for len in (0..bytes.len()).step_by(97) {
+            assert!(DartMinHash::<FastMix>::from_bytes(&bytes[..len]).is_err());
+        }
+    }
+
+    /// Flip bytes all over the body of `s`: every flip either fails to load
+    /// or gives a sketcher that `sketch` can use without panicking.
+    fn check_byte_flips<S: SketcherState>(s: &S, sketch: impl Fn(&S)) {
+        let bytes = s.to_bytes();
+        let (start, end) = (9, bytes.len());
+        // Every byte of the leading fields and the tail, a sample in between.
+        let step = (end / 1_500).max(1);
+        for i in (start..end).filter(|&i| i < start + 300 || i + 100 >= end || i % step == 0) {
+            for flip in [0x01, 0x80, 0xff] {
+                let mut bad = bytes.clone();
+                bad[i] ^= flip;
+                if let Ok(loaded) = S::from_bytes(&bad) {
+                    sketch(&loaded);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn state_survives_byte_flips() {
+        let seeds = SeedDeriver::new(51);
+        let [x, _] = sets();
+        let x = &x[..20];
+        let k = 16;
+
+        // A flipped t or k is valid but makes sketching arbitrarily expensive.
+        let dh = DartHash::with_family(&mut seeds.rng("dh"), 64, FastMix);
+        check_byte_flips(&dh, |s| {
+            if s.t() <= 1 << 16 {
+                s.minhash(x, k);
+            }
+        });
+        let dmh = DartMinHash::with_family(&mut seeds.rng("dmh"), k, FastMix);
+        check_byte_flips(&dmh, |s| {
+            if s.t() <= 1 << 16 && s.k() <= 1 << 16 {
+                s.sketch(x);
+            }
+        });
+        let tmh = TreeMinHash::with_family(&mut seeds.rng("tmh"), k, FastMix);
+        check_byte_flips(&tmh, |s| {
+            s.sketch(x);
+        });
+        let caps: Vec<(u64, f64)> = x.iter().map(|&(id, w)| (id, 1.5 * w)).collect();
+        let fallback = TreeMinHash::with_family(&mut seeds.rng("fallback"), k, FastMix);
+        let ers = ErsWmh::new_sparse_with_family(&mut seeds.rng("ers"), &caps, k, FastMix)
+            .with_cap_policy(CapPolicy::Fallback(Box::new(fallback)));
+        let z: Vec<(u64, f64)> = x.iter().map(|&(id, w)| (id, 2.0 * w)).collect();
+        check_byte_flips(&ers, |s| {
+            s.sketch_with_stats(x, Some(64));
+            s.sketch_with_stats(&z, Some(64));
+        });
+    }
+}
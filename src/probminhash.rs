@@ -0,0 +1,189 @@
+//! ProbMinHash for probability Jaccard sketching.
+//!
+//! Rust port of Otmar Ertl's ProbMinHash (IEEE TKDE 2020). Register `j` keeps
+//! the feature minimizing `E_{ij} / w_i` with `E_{ij} ~ Exp(1)`, so two
+//! sketches collide with probability equal to the probability Jaccard
+//!
+//!   J_P(x, y) = Σ_{i: x_i>0, y_i>0} 1 / Σ_j max(x_j/x_i, y_j/y_i),
+//!
+//! see [`crate::similarity::probability_jaccard`]. J_P is invariant to scaling
+//! either vector, so inputs do not need to be normalized.
+//!
+//! Per feature the k values are generated in increasing order (exponential
+//! spacings, registers taken from a lazy random permutation), and generation
+//! stops as soon as the next value exceeds the current maximum over all
+//! registers. Randomness uses the tabulation streams of [`crate::treeminhash`].
+
+use rand_core::RngCore;
+
+use crate::dartminhash::MinHashSketch;
+use crate::hash_utils::*;
+use crate::rng_utils::mt_from_seed_full;
+use crate::scratch::{Scratch, reset_sketch};
+use crate::treeminhash::TabStream;
+
+/// ProbMinHash sketcher for probability Jaccard similarity.
+pub struct ProbMinHash<F: HashFamily = DefaultFamily> {
+    k: u32,
+    family: F,
+    h0: F::H64,
+    h1: F::H64,
+    id_hasher: F::H64,
+    // factors[i] = k / (k - i - 1): scale of the spacing after i+1 values
+    factors: Vec<f64>,
+}
+
+impl ProbMinHash {
+    pub fn new<R: RngCore + ?Sized>(rng: &mut R, k: u64) -> Self {
+        Self::with_family(rng, k, DefaultFamily::default())
+    }
+
+    /// Like `new`; sketches of equal seeds are comparable.
+    pub fn from_seed(seed: u64, k: u64) -> Self {
+        Self::new(&mut mt_from_seed_full(seed), k)
+    }
+}
+
+impl<F: HashFamily> ProbMinHash<F> {
+    /// Like `new`, drawing the hash functions from `family`.
+    pub fn with_family<R: RngCore + ?Sized>(rng: &mut R, k: u64, family: F) -> Self {
+        assert!(k > 0 && k <= (u32::MAX as u64), "k must fit into u32");
+        let k_f = k as f64;
+        let factors = (0..(k as u32).saturating_sub(1))
+            .map(|i| k_f / (k_f - (i as f64) - 1.0))
+            .collect();
+
+        let h0 = family.hash64_from_rng(rng);
+        let h1 = family.hash64_from_rng(rng);
+        let id_hasher = family.hash64_from_rng(rng);
+        Self {
+            k: k as u32,
+            family,
+            h0,
+            h1,
+            id_hasher,
+            factors,
+        }
+    }
+
+    #[inline]
+    pub fn hash_family(&self) -> HashKind {
+        self.family.kind()
+    }
+
+    /// Return k slots of `(fingerprint(id), value)`.
+    ///
+    /// Counting fingerprint collisions with `jaccard_estimate_from_minhashes`
+    /// estimates J_P (not the weighted Jaccard J_W).
+    pub fn sketch(&self, x: &[(u64, f64)]) -> MinHashSketch {
+        let mut result = Vec::with_capacity(self.k as usize);
+        self.sketch_into(x, &mut Scratch::new(), &mut result);
+        result
+    }
+
+    /// Like `sketch`, writing into `result` and reusing the buffers of
+    /// `scratch`, see [`crate::scratch`].
+    pub fn sketch_into(&self, x: &[(u64, f64)], scratch: &mut Scratch, result: &mut MinHashSketch) {
+        let k = self.k as usize;
+        reset_sketch(result, k);
+        let tracker = &mut scratch.tracker;
+        tracker.reset(k);
+        let permutation_stream = &mut scratch.permutation;
+        permutation_stream.reset_to(self.k);
+
+        for &(id, w) in x {
+            if w <= 0.0 || !w.is_finite() {
+                continue;
+            }
+
+            let inv_w = 1.0 / w;
+            let mut rng = TabStream::new(&self.h0, &self.h1, id, 0);
+            let mut point = rng.exponential1() * inv_w;
+            if point >= tracker.max() {
+                continue;
+            }
+
+            let fingerprint = self.id_hasher.hash(id);
+            permutation_stream.reset();
+            for kk in 0..k {
+                let idx = permutation_stream.next(&mut rng) as usize;
+                if point < result[idx].1 {
+                    result[idx] = (fingerprint, point);
+                    tracker.update(idx, point);
+                }
+
+                if kk == self.factors.len() {
+                    break;
+                }
+                point += rng.exponential1() * inv_w * self.factors[kk];
+                if point >= tracker.max() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{
+        probminhash::ProbMinHash,
+        rng_utils::mt_from_seed,
+        similarity::{jaccard_estimate_from_minhashes, probability_jaccard},
+        test_utils::{
+            assert_estimate_close, generate_perturbed_distribution, generate_weighted_set,
+        },
+    };
+
+    #[test]
+    fn probminhash_approximates_probability_jaccard() {
+        let mut data_rng = mt_from_seed(1337);
+        let mut hash_rng = mt_from_seed(0x9b0b_0001);
+
+        // J_P is computed exactly in O(n^2), so keep the sets moderate.
+        let l0 = 2_000;
+        let l1 = 1.0;
+        let k = 4096;
+
+        let pmh = ProbMinHash::new(&mut hash_rng, k);
+        let x = generate_weighted_set(l0, l1, &mut data_rng);
+        let sk_x = pmh.sketch(&x);
+        assert_eq!(sk_x.len(), k as usize);
+
+        let cases = [
+            (0.0, 0.0),
+            (0.1, 0.0),
+            (0.5, 0.1),
+            (0.9, 0.3),
+            (0.9, 0.6),
+            (0.9, 0.9),
+        ];
+        for &(spread, drop) in &cases {
+            let y = generate_perturbed_distribution(&x, spread, drop, &mut data_rng);
+            let j_true = probability_jaccard(&x, &y);
+            let sk_y = pmh.sketch(&y);
+            let j_est = jaccard_estimate_from_minhashes(&sk_x, &sk_y);
+            assert_estimate_close(
+                j_true,
+                j_est,
+                k,
+                format!("PMH: spread={spread}, drop={drop}"),
+            );
+        }
+    }
+
+    #[test]
+    fn probminhash_is_scale_invariant() {
+        let mut rng = mt_from_seed(3);
+        let pmh = ProbMinHash::new(&mut rng, 256);
+        let x = vec![(1, 0.25), (3, 0.75), (10, 1.5), (42, 0.125)];
+        let x_scaled: Vec<(u64, f64)> = x.iter().map(|&(id, w)| (id, 1.0e6 * w)).collect();
+        let sk = pmh.sketch(&x);
+        let sk_scaled = pmh.sketch(&x_scaled);
+        assert_eq!(jaccard_estimate_from_minhashes(&sk, &sk_scaled), 1.0);
+
+        let empty = pmh.sketch(&[(1, 0.0)]);
+        assert!(empty.iter().all(|&(id, v)| id == 0 && v.is_infinite()));
+    }
+}
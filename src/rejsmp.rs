@@ -5,16 +5,22 @@
 //!     r_{j,1..L} per hash position j; take the first green if any, otherwise mark
 //!     empty; then densify empties by rotating to a non-empty bucket with a
 //!     per-j random offset (data-independent).
+//!   - RS (Shrivastava 2016): the same sequences without a length limit; each
+//!     hash position keeps drawing until the first green, so no bucket is empty,
+//!     no densification is needed and the estimate is unbiased. The expected
+//!     number of draws per position is M / mass(x), where mass(x) is the
+//!     covered mass Σ_i min(x_i, m_i).
 //!
 //! Inputs: sparse weighted vector `&[(u64, f64)]` where id ∈ [0, D) and weight ≥ 0.
-//! Randomness: purely via Tab32/Tab64 tabulation hashing (no stateful RNG required).
-//! By default this uses simple tabulation; with the `mixed_tab` feature it uses
-//! mixed tabulation.
+//! Randomness: purely via 32/64-bit hashing (no stateful RNG required), with
+//! the `HashFamily` of the sketcher. By default this uses simple tabulation;
+//! with the `mixed_tab` feature it uses mixed tabulation.
 //!
 //! IMPORTANT: Caps `m_i` are **real-valued** (`f64`) and should be set to the
 //! *tight* per-dimension maxima across the dataset: `m_i = max_s x_i(s)`.
 //! Using tight caps reduces total M = sum_i m_i, increases acceptance probability,
-//! and lets you use much smaller L in ERS.
+//! and lets you use much smaller L in ERS. Inputs above their caps are detected
+//! while sketching and handled according to [`CapPolicy`].
 //!
 //! PERFORMANCE NOTE (this version):
 //! - Removes the hot O(log D) binary search over prefix-sums for each draw by using
@@ -22,40 +28,19 @@
 //! - Keeps your original semantics for ID hashing: id = hash(r.to_bits()) where
 //!   r = base[i] + off, off ~ Uniform(0, m_i).
 
+use rand_core::RngCore;
+
 use crate::hash_utils::*;
-use crate::rng_utils::MtRng;
+use crate::persist::{
+    SketcherState, StateReader, StateWriter, TableState, invalid, read_family, write_family,
+};
+use crate::rng_utils::{MtRng, mt_from_seed_full};
+use crate::scratch::Scratch;
+use crate::treeminhash::TreeMinHash;
 
 use std::cell::RefCell;
-
-#[cfg(feature = "mixed_tab")]
-type Tab32Ers = tab_hash::Tab32Mixed;
-#[cfg(not(feature = "mixed_tab"))]
-type Tab32Ers = tab_hash::Tab32Simple;
-
-#[cfg(feature = "mixed_tab")]
-type Tab64Ers = tab_hash::Tab64Mixed;
-#[cfg(not(feature = "mixed_tab"))]
-type Tab64Ers = tab_hash::Tab64Simple;
-
-#[cfg(feature = "mixed_tab")]
-fn tab32_ers_from_rng(rng: &mut MtRng) -> Tab32Ers {
-    mixed_tab32_from_rng(rng)
-}
-
-#[cfg(not(feature = "mixed_tab"))]
-fn tab32_ers_from_rng(rng: &mut MtRng) -> Tab32Ers {
-    tab32_from_rng(rng)
-}
-
-#[cfg(feature = "mixed_tab")]
-fn tab64_ers_from_rng(rng: &mut MtRng) -> Tab64Ers {
-    mixed_tab64_from_rng(rng)
-}
-
-#[cfg(not(feature = "mixed_tab"))]
-fn tab64_ers_from_rng(rng: &mut MtRng) -> Tab64Ers {
-    tab64_from_rng(rng)
-}
+use std::fmt;
+use std::io;
 
 /// A single (id, rank) pair compatible with your DartMinHash plumbing.
 pub type Dart = (u64, f64);
@@ -65,7 +50,11 @@ pub type Dart = (u64, f64);
 /// We store:
 /// - base[i] = sum_{h<i} m_h  (left boundary)
 /// - cap[i]  = m_i
+///
 /// And a Walker alias table to sample i with P(i)=m_i/M in O(1).
+///
+/// With `from_sparse_caps` the intervals are indexed by the compact position
+/// of each capped id, and `ids` maps positions back to feature ids.
 #[derive(Clone)]
 pub struct RedGreenIndex {
     base: Vec<f64>,
@@ -76,6 +65,9 @@ pub struct RedGreenIndex {
     // Walker alias table for discrete distribution p_i = cap[i]/m_total
     prob: Vec<f64>,  // in [0,1]
     alias: Vec<u32>, // in [0,d)
+
+    // sorted feature ids of the compact positions (sparse caps only)
+    ids: Option<Vec<u64>>,
 }
 
 impl RedGreenIndex {
@@ -108,6 +100,7 @@ impl RedGreenIndex {
                 m_total,
                 prob,
                 alias,
+                ids: None,
             };
         }
 
@@ -138,7 +131,7 @@ impl RedGreenIndex {
         }
 
         // leftovers
-        for i in small.into_iter().chain(large.into_iter()) {
+        for i in small.into_iter().chain(large) {
             prob[i] = 1.0;
             alias[i] = i as u32;
         }
@@ -150,14 +143,52 @@ impl RedGreenIndex {
             m_total,
             prob,
             alias,
+            ids: None,
         }
     }
 
+    /// Build from caps over arbitrary `u64` feature ids, e.g. hashed ids.
+    /// Duplicate ids keep the largest cap; ids without a cap have cap 0.
+    /// Memory is O(number of capped ids), independent of the id range.
+    pub fn from_sparse_caps(caps: &[(u64, f64)]) -> Self {
+        let mut caps = caps.to_vec();
+        caps.sort_by_key(|&(id, _)| id);
+        caps.dedup_by(|next, kept| {
+            if next.0 == kept.0 {
+                kept.1 = kept.1.max(next.1);
+                true
+            } else {
+                false
+            }
+        });
+        let (ids, m_per_dim): (Vec<u64>, Vec<f64>) = caps.into_iter().unzip();
+        let mut index = Self::from_caps(&m_per_dim);
+        index.ids = Some(ids);
+        index
+    }
+
+    /// Number of intervals: D for dense caps, the number of distinct capped
+    /// ids for sparse caps.
     #[inline]
     pub fn d(&self) -> usize {
         self.d
     }
 
+    #[inline]
+    pub fn is_sparse(&self) -> bool {
+        self.ids.is_some()
+    }
+
+    /// Interval of feature `id`: the id itself for dense caps, its compact
+    /// position for sparse caps (None if the id has no cap).
+    #[inline]
+    pub fn position_of(&self, id: u64) -> Option<usize> {
+        match &self.ids {
+            Some(ids) => ids.binary_search(&id).ok(),
+            None => ((id as usize) < self.d).then_some(id as usize),
+        }
+    }
+
     #[inline]
     pub fn m_total(&self) -> f64 {
         self.m_total
@@ -174,27 +205,9 @@ impl RedGreenIndex {
     }
 
     /// Sample an interval i with P(i)=cap[i]/M, plus offset off ∈ [0,cap[i]).
-    /// Uses only tab-hash-derived uniforms (stateless).
-    #[cfg(not(feature = "mixed_tab"))]
+    /// Uses only hash-derived uniforms (stateless).
     #[inline]
-    pub fn sample_interval_and_offset(
-        &self,
-        t_u: &tab_hash::Tab64Simple,
-        key: u64,
-    ) -> (usize, f64) {
-        self.sample_interval_and_offset_impl(t_u, key)
-    }
-
-    /// Sample an interval i with P(i)=cap[i]/M, plus offset off ∈ [0,cap[i]).
-    /// Uses only tab-hash-derived uniforms (stateless).
-    #[cfg(feature = "mixed_tab")]
-    #[inline]
-    pub fn sample_interval_and_offset(&self, t_u: &tab_hash::Tab64Mixed, key: u64) -> (usize, f64) {
-        self.sample_interval_and_offset_impl(t_u, key)
-    }
-
-    #[inline]
-    fn sample_interval_and_offset_impl(&self, t_u: &Tab64Ers, key: u64) -> (usize, f64) {
+    pub fn sample_interval_and_offset<H: Hash64>(&self, t_u: &H, key: u64) -> (usize, f64) {
         debug_assert!(self.d > 0);
 
         // u0 chooses the column in [0,d)
@@ -228,15 +241,181 @@ impl RedGreenIndex {
     }
 }
 
-/// Per-thread dense scratch to avoid allocating/zeroing a length-D vector per sample.
-/// We only touch indices present in x, and only clear those indices afterward.
+impl RedGreenIndex {
+    /// Smallest L such that a hash position of a vector with total weight
+    /// `mass` stays empty with probability at most `empty_prob`:
+    /// L = ceil(ln p / ln(1 - mass / M)), at least 1. `mass` is the covered
+    /// mass Σ_i min(x_i, m_i): weights above their caps only count up to it.
+    pub fn l_for_mass(&self, mass: f64, empty_prob: f64) -> u32 {
+        assert!(
+            empty_prob > 0.0 && empty_prob < 1.0,
+            "empty_prob must be in (0, 1)"
+        );
+        if self.m_total <= 0.0 || mass <= 0.0 {
+            return 1;
+        }
+        let accept = mass / self.m_total;
+        if accept >= 1.0 {
+            return 1;
+        }
+        let l = (empty_prob.ln() / (-accept).ln_1p()).ceil();
+        l.clamp(1.0, u32::MAX as f64) as u32
+    }
+}
+
+/// Per-sketch diagnostics of `ErsWmh`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ErsSketchStats {
+    /// Sequence length L used for every hash position.
+    pub l: u32,
+    /// Hash positions without a green within L, filled by densification
+    /// (all k if the input has no mass).
+    pub densified_buckets: usize,
+    /// Samples drawn over all k hash positions; `attempts / k` is the mean
+    /// number of attempts per bucket. An empty bucket counts L attempts.
+    pub attempts: u64,
+    /// Most samples drawn by a single hash position.
+    pub max_bucket_attempts: u32,
+    /// Input weights above their cap (including ids without a cap).
+    pub cap_violations: usize,
+    /// The sketch comes from the `CapPolicy::Fallback` sketcher.
+    pub fallback: bool,
+}
+
+/// What `ErsWmh` does when an input weight exceeds its cap.
+///
+/// A weight above its cap is effectively clipped to the cap (the whole
+/// interval is green), which silently biases the estimate.
 #[derive(Default)]
-struct DenseScratch {
+pub enum CapPolicy<F: HashFamily = DefaultFamily> {
+    /// Sketch with the clipped weights and count the violations in
+    /// `ErsSketchStats::cap_violations` (the original behavior).
+    #[default]
+    Clip,
+    /// Refuse to sketch: `try_*` methods return a `CapViolationError` (wrapped
+    /// in `RsError` for RS), the other sketch methods panic.
+    Error,
+    /// Sketch violating inputs with a cap-free sketcher instead, on every
+    /// sketching path. These sketches are only comparable with other fallback
+    /// sketches; the methods returning `ErsSketchStats` flag them in
+    /// `ErsSketchStats::fallback`.
+    Fallback(Box<TreeMinHash<F>>),
+}
+
+/// An input weight exceeded its ERS cap under `CapPolicy::Error`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CapViolationError {
+    /// Number of violating ids in the input.
+    pub violations: usize,
+    /// First violating id, with its weight and cap.
+    pub id: u64,
+    pub weight: f64,
+    pub cap: f64,
+}
+
+impl fmt::Display for CapViolationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} input weight(s) exceed their ERS caps, e.g. id {}: weight {} > cap {}",
+            self.violations, self.id, self.weight, self.cap
+        )
+    }
+}
+
+impl std::error::Error for CapViolationError {}
+
+/// Default of [`ErsWmh::with_attempt_budget`].
+pub const DEFAULT_ATTEMPT_BUDGET: u64 = 1 << 30;
+
+/// `ErsWmh::try_sketch_rs` could not sketch an input.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RsError {
+    /// See `CapPolicy::Error`.
+    CapViolation(CapViolationError),
+    /// The expected number of draws k M / mass exceeds the attempt budget of
+    /// the sketcher, see `ErsWmh::with_attempt_budget`.
+    AttemptBudget { expected_attempts: f64, budget: u64 },
+}
+
+impl fmt::Display for RsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RsError::CapViolation(e) => e.fmt(f),
+            RsError::AttemptBudget {
+                expected_attempts,
+                budget,
+            } => write!(
+                f,
+                "RS expects {expected_attempts:.3e} draws, more than the budget of {budget}; \
+                 use a bounded sequence length, e.g. sketch_auto"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RsError {}
+
+impl From<CapViolationError> for RsError {
+    fn from(e: CapViolationError) -> Self {
+        RsError::CapViolation(e)
+    }
+}
+
+/// Only RS has an unbounded number of draws, so only it fails the budget.
+fn cap_violation(e: RsError) -> CapViolationError {
+    match e {
+        RsError::CapViolation(e) => e,
+        RsError::AttemptBudget { .. } => unreachable!("bounded L cannot exceed the budget"),
+    }
+}
+
+/// How `ErsWmh` picks L for an input.
+#[derive(Clone, Copy, Debug)]
+enum SequenceLength {
+    Fixed(u32),
+    /// Target empty-bucket probability, L from the input's mass.
+    Auto(f64),
+    /// Plain RS: draw until the first green, no densification.
+    Unbounded,
+}
+
+/// Weights of the current input as seen by the green test.
+#[derive(Clone, Copy)]
+enum Weights<'a> {
+    /// x_i at index i (dense caps).
+    Dense(&'a [f64]),
+    /// `(position, x_i)` sorted by position (sparse caps).
+    Sparse(&'a [(usize, f64)]),
+}
+
+impl Weights<'_> {
+    #[inline]
+    fn get(&self, i: usize) -> f64 {
+        match self {
+            Weights::Dense(w) => unsafe { *w.get_unchecked(i) },
+            Weights::Sparse(w) => match w.binary_search_by_key(&i, |&(p, _)| p) {
+                Ok(k) => w[k].1,
+                Err(_) => 0.0,
+            },
+        }
+    }
+}
+
+/// Per-thread scratch to avoid allocating/zeroing a length-D vector per sample.
+/// For dense caps we only touch indices present in x, and only clear those
+/// indices afterward. For sparse caps the input is kept as a sorted list of
+/// compact positions, so nothing of size D is ever allocated.
+#[derive(Default)]
+pub(crate) struct WeightScratch {
     w: Vec<f64>,
     touched: Vec<usize>,
+    // (id, input position, weight)
+    sparse: Vec<(u64, usize, f64)>,
+    positions: Vec<(usize, f64)>,
 }
 
-impl DenseScratch {
+impl WeightScratch {
     #[inline]
     fn ensure_len(&mut self, d: usize) {
         if self.w.len() < d {
@@ -244,208 +423,876 @@ impl DenseScratch {
         }
     }
 
+    /// Load `x` for `index` and return its covered mass Σ_i min(x_i, m_i),
+    /// the part of the input that can turn green.
+    #[inline]
+    fn fill(&mut self, index: &RedGreenIndex, x: &[(u64, f64)]) -> f64 {
+        if index.is_sparse() {
+            self.fill_sparse_and_mass(index, x)
+        } else {
+            self.fill_from_sparse_and_mass(index, x)
+        }
+    }
+
+    #[inline]
+    fn weights(&self, index: &RedGreenIndex) -> Weights<'_> {
+        if index.is_sparse() {
+            Weights::Sparse(&self.positions)
+        } else {
+            Weights::Dense(&self.w)
+        }
+    }
+
     /// Populate dense weights from sparse `x` and return `mass` computed with the same
     /// semantics as a dense vector sum (handles duplicate ids by overwrite).
+    /// Ids outside `[0, D)` have no cap: they are kept aside for
+    /// `cap_violations`, never written into `w` (which may be longer than D)
+    /// and do not count towards the mass.
     #[inline]
-    fn fill_from_sparse_and_mass(&mut self, d: usize, x: &[(u64, f64)]) -> f64 {
-        self.ensure_len(d);
+    fn fill_from_sparse_and_mass(&mut self, index: &RedGreenIndex, x: &[(u64, f64)]) -> f64 {
+        self.ensure_len(index.d());
         self.touched.clear();
+        self.sparse.clear();
 
         let mut mass = 0.0f64;
 
-        for &(i_u64, xi) in x {
-            let idx = i_u64 as usize;
-            debug_assert!(idx < d);
-
+        for (n, &(i_u64, xi)) in x.iter().enumerate() {
             if xi <= 0.0 {
                 continue;
             }
+            let Some(idx) = index.position_of(i_u64) else {
+                self.sparse.push((i_u64, n, xi));
+                continue;
+            };
 
+            let cap = index.cap_of(idx);
             let old = self.w[idx];
             if old == 0.0 {
                 self.touched.push(idx);
                 self.w[idx] = xi;
-                mass += xi;
+                mass += xi.min(cap);
             } else {
                 // duplicate id: overwrite semantics
                 self.w[idx] = xi;
-                mass += xi - old;
+                mass += xi.min(cap) - old.min(cap);
             }
         }
+        self.dedup_sparse();
 
         mass
     }
 
+    /// Sort `sparse` by (id, input position) and keep the last entry of each
+    /// id: duplicate ids overwrite. Unlike a stable sort this needs no buffer.
+    /// The result is in descending id order.
+    #[inline]
+    fn dedup_sparse(&mut self) {
+        self.sparse.sort_unstable_by_key(|&(id, n, _)| (id, n));
+        self.sparse.reverse();
+        self.sparse.dedup_by_key(|p| p.0);
+    }
+
+    /// Map `x` to sorted compact positions of a sparse index and return `mass`
+    /// with the same semantics as the dense fill. Ids without a cap can never
+    /// be green and do not count towards the mass.
+    #[inline]
+    fn fill_sparse_and_mass(&mut self, index: &RedGreenIndex, x: &[(u64, f64)]) -> f64 {
+        self.sparse.clear();
+        self.sparse.extend(
+            x.iter()
+                .enumerate()
+                .filter(|&(_, &(_, xi))| xi > 0.0)
+                .map(|(n, &(id, xi))| (id, n, xi)),
+        );
+        self.dedup_sparse();
+
+        self.positions.clear();
+        let mut mass = 0.0f64;
+        for &(id, _, xi) in self.sparse.iter().rev() {
+            if let Some(pos) = index.position_of(id) {
+                mass += xi.min(index.cap_of(pos));
+                self.positions.push((pos, xi));
+            }
+        }
+        mass
+    }
+
+    /// Count the loaded weights above their cap and report the first one as
+    /// `(id, weight, cap)`.
+    fn cap_violations(&self, index: &RedGreenIndex) -> (usize, Option<(u64, f64, f64)>) {
+        let mut count = 0usize;
+        let mut first = None;
+        let mut check = |id: u64, xi: f64, cap: f64| {
+            if xi > cap {
+                count += 1;
+                first.get_or_insert((id, xi, cap));
+            }
+        };
+        if index.is_sparse() {
+            for &(id, _, xi) in self.sparse.iter().rev() {
+                let cap = index.position_of(id).map_or(0.0, |p| index.cap_of(p));
+                check(id, xi, cap);
+            }
+        } else {
+            for &idx in &self.touched {
+                check(idx as u64, self.w[idx], index.cap_of(idx));
+            }
+            // ids >= D, see `fill_from_sparse_and_mass`
+            for &(id, _, xi) in self.sparse.iter().rev() {
+                check(id, xi, 0.0);
+            }
+        }
+        (count, first)
+    }
+
     #[inline]
     fn clear_touched(&mut self) {
         for &idx in &self.touched {
             self.w[idx] = 0.0;
         }
         self.touched.clear();
+        self.sparse.clear();
+        self.positions.clear();
     }
 }
 
 thread_local! {
-    static ERS_SCRATCH: RefCell<DenseScratch> = RefCell::new(DenseScratch::default());
+    static ERS_SCRATCH: RefCell<Scratch> = RefCell::new(Scratch::default());
+}
+
+/// How `ErsWmh` fills buckets that found no green within L.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Densification {
+    /// Scan from a per-j random offset to the next non-empty bucket,
+    /// including buckets densified before.
+    #[default]
+    Rotation,
+    /// Optimal densification (Shrivastava 2017): probe buckets
+    /// h_j(1), h_j(2), ... with a 2-universal hash until an originally
+    /// non-empty one is found. Lower variance when many buckets are empty.
+    Optimal,
 }
 
+/// Mersenne prime 2^61 - 1 for the 2-universal probing hash.
+const MERSENNE_61: u64 = (1u64 << 61) - 1;
+
 /// ERS (AAAI Algorithm 2): k independent fixed-length random sequences.
 /// For each j in 0..k, scan r_{j,1},...,r_{j,L}; take first green. If none, mark empty.
-/// Then densify empties by rotating to a non-empty bucket with a per-j random offset.
-pub struct ErsWmh {
+/// Then densify empties, see [`Densification`].
+pub struct ErsWmh<F: HashFamily = DefaultFamily> {
     index: RedGreenIndex,
-    // tabulation generators
-    t_u: Tab64Ers,   // U(0,1) for draws
-    t_id: Tab64Ers,  // ID from accepted draw r (via r.to_bits())
-    t_rot: Tab32Ers, // offset for densification
+    family: F,
+    // hash generators
+    t_u: F::H64,   // U(0,1) for draws
+    t_id: F::H64,  // ID from accepted draw r (via r.to_bits())
+    t_rot: F::H32, // offset for densification
     k: usize,
+    densification: Densification,
+    cap_policy: CapPolicy<F>,
+    attempt_budget: u64,
+    // (a, b) of the 2-universal hash ((a x + b) mod p) mod k, a in [1, p)
+    probe_a: u64,
+    probe_b: u64,
 }
 
 impl ErsWmh {
     /// `caps`: real-valued caps (tight upper bounds). `k`: number of hashes.
+    pub fn new<R: RngCore + ?Sized>(rng: &mut R, caps: &[f64], k: u64) -> Self {
+        Self::from_index(rng, RedGreenIndex::from_caps(caps), k)
+    }
+
+    /// [`Self::new`] with an [`MtRng`].
     pub fn new_mt(rng: &mut MtRng, caps: &[f64], k: u64) -> Self {
-        let index = RedGreenIndex::from_caps(caps);
-        let t_u = tab64_ers_from_rng(rng);
-        let t_id = tab64_ers_from_rng(rng);
-        let t_rot = tab32_ers_from_rng(rng);
+        Self::new(rng, caps, k)
+    }
+
+    /// Like `new`, with the rotation and sequence hashes drawn from
+    /// `mt_from_seed_full(seed)`.
+    pub fn from_seed(seed: u64, caps: &[f64], k: u64) -> Self {
+        Self::new(&mut mt_from_seed_full(seed), caps, k)
+    }
+
+    /// `caps`: `(id, cap)` pairs over arbitrary `u64` ids, see
+    /// [`RedGreenIndex::from_sparse_caps`]. Sketching then never allocates
+    /// anything of the size of the id range.
+    pub fn new_sparse<R: RngCore + ?Sized>(rng: &mut R, caps: &[(u64, f64)], k: u64) -> Self {
+        Self::from_index(rng, RedGreenIndex::from_sparse_caps(caps), k)
+    }
+
+    /// ERS over a prebuilt index.
+    pub fn from_index<R: RngCore + ?Sized>(rng: &mut R, index: RedGreenIndex, k: u64) -> Self {
+        Self::from_index_with_family(rng, index, k, DefaultFamily::default())
+    }
+}
+
+impl<F: HashFamily> ErsWmh<F> {
+    /// Like `new`, drawing the hash functions from `family`.
+    pub fn with_family<R: RngCore + ?Sized>(rng: &mut R, caps: &[f64], k: u64, family: F) -> Self {
+        Self::from_index_with_family(rng, RedGreenIndex::from_caps(caps), k, family)
+    }
+
+    /// Like `new_sparse`, drawing the hash functions from `family`.
+    pub fn new_sparse_with_family<R: RngCore + ?Sized>(
+        rng: &mut R,
+        caps: &[(u64, f64)],
+        k: u64,
+        family: F,
+    ) -> Self {
+        Self::from_index_with_family(rng, RedGreenIndex::from_sparse_caps(caps), k, family)
+    }
+
+    /// Like `from_index`, drawing the hash functions from `family`.
+    pub fn from_index_with_family<R: RngCore + ?Sized>(
+        rng: &mut R,
+        index: RedGreenIndex,
+        k: u64,
+        family: F,
+    ) -> Self {
+        let t_u = family.hash64_from_rng(rng);
+        let t_id = family.hash64_from_rng(rng);
+        let t_rot = family.hash32_from_rng(rng);
+        // Derived from t_rot (at keys no bucket index uses in practice) so the
+        // RNG consumption, and with it every existing sketch, is unchanged.
+        let word = |key: u32| ((t_rot.hash(key) as u64) << 32) | (t_rot.hash(key - 1) as u64);
+        let probe_a = word(u32::MAX) % (MERSENNE_61 - 1) + 1;
+        let probe_b = word(u32::MAX - 2) % MERSENNE_61;
         Self {
             index,
+            family,
             t_u,
             t_id,
             t_rot,
             k: k as usize,
+            densification: Densification::default(),
+            cap_policy: CapPolicy::default(),
+            attempt_budget: DEFAULT_ATTEMPT_BUDGET,
+            probe_a,
+            probe_b,
         }
     }
 
     #[inline]
-    fn is_green_offset(&self, w_dense: &[f64], i: usize, off: f64) -> bool {
+    pub fn hash_family(&self) -> HashKind {
+        self.family.kind()
+    }
+
+    /// Select the densification strategy for empty buckets.
+    pub fn with_densification(mut self, densification: Densification) -> Self {
+        self.densification = densification;
+        self
+    }
+
+    #[inline]
+    pub fn densification(&self) -> Densification {
+        self.densification
+    }
+
+    /// Select how inputs exceeding their caps are handled.
+    pub fn with_cap_policy(mut self, cap_policy: CapPolicy<F>) -> Self {
+        self.cap_policy = cap_policy;
+        self
+    }
+
+    #[inline]
+    pub fn cap_policy(&self) -> &CapPolicy<F> {
+        &self.cap_policy
+    }
+
+    /// Bound the draws of one sketch over all k hash positions. RS refuses
+    /// inputs whose expected number of draws k M / mass exceeds it, and the
+    /// L of `sketch_auto` is at most `budget / k`. A low-mass input against a
+    /// large M would otherwise take up to 2^32 draws per hash position.
+    pub fn with_attempt_budget(mut self, budget: u64) -> Self {
+        assert!(budget > 0, "attempt budget must be positive");
+        self.attempt_budget = budget;
+        self
+    }
+
+    #[inline]
+    pub fn attempt_budget(&self) -> u64 {
+        self.attempt_budget
+    }
+
+    #[inline]
+    fn is_green_offset(&self, w: Weights<'_>, i: usize, off: f64) -> bool {
         // green iff off <= x_i (since r = base[i] + off and green region is [base, base + x_i])
-        let xi = unsafe { *w_dense.get_unchecked(i) };
-        off <= xi
+        off <= w.get(i)
+    }
+
+    /// First green `(id, time)` of the sequence of hash position j, scanning
+    /// times 1..=l_per_hash.
+    #[inline]
+    fn first_green(&self, w: Weights<'_>, j: usize, l_per_hash: u32) -> Option<(u64, u32)> {
+        for t in 1..=l_per_hash {
+            // key = (j, t)
+            let key = ((j as u64) << 32) ^ (t as u64);
+
+            // O(1) interval sample + offset
+            let (i, off) = self.index.sample_interval_and_offset(&self.t_u, key);
+
+            if self.is_green_offset(w, i, off) {
+                // Reconstruct r so ID hashing matches the previous definition.
+                let r = self.index.base_of(i) + off;
+                let id = self.t_id.hash(r.to_bits());
+                return Some((id, t));
+            }
+        }
+        None
+    }
+
+    /// Deterministic data-independent sketch for inputs without mass.
+    fn fallback_sketch_into(&self, out: &mut Vec<Dart>) {
+        out.clear();
+        for j in 0..self.k {
+            let fake = (self.t_rot.hash(j as u32) as u64) << 32 | (j as u64);
+            out.push((fake, f64::INFINITY));
+        }
     }
 
     /// `max_attempts` is interpreted as L (sequence length per hash position).
     /// If None, uses a moderate default (1024).
+    ///
+    /// Panics on a cap violation under `CapPolicy::Error`, see `try_sketch`.
+    /// Under `CapPolicy::Fallback` a violating input gets the fallback
+    /// sketch; `sketch_with_stats` tells which sketcher produced it.
     pub fn sketch(&self, x: &[(u64, f64)], max_attempts: Option<u64>) -> Vec<Dart> {
+        self.try_sketch(x, max_attempts)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Like `sketch`, tagged with the hash family.
+    pub fn sketch_tagged(&self, x: &[(u64, f64)], max_attempts: Option<u64>) -> FamilySketch {
+        FamilySketch {
+            hash_family: self.hash_family(),
+            minhashes: self.sketch(x, max_attempts),
+        }
+    }
+
+    /// Like `sketch`, also reporting L, the attempts per bucket and the
+    /// number of densified buckets. Under `CapPolicy::Fallback` a violating
+    /// input gets the fallback sketch, flagged in `ErsSketchStats::fallback`.
+    pub fn sketch_with_stats(
+        &self,
+        x: &[(u64, f64)],
+        max_attempts: Option<u64>,
+    ) -> (Vec<Dart>, ErsSketchStats) {
+        self.try_sketch_with_stats(x, max_attempts)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Like `sketch`, returning an error on a cap violation under
+    /// `CapPolicy::Error`.
+    pub fn try_sketch(
+        &self,
+        x: &[(u64, f64)],
+        max_attempts: Option<u64>,
+    ) -> Result<Vec<Dart>, CapViolationError> {
+        self.sketch_alloc(x, Self::fixed_length(max_attempts))
+            .map(|(sketch, _)| sketch)
+            .map_err(cap_violation)
+    }
+
+    pub fn try_sketch_with_stats(
+        &self,
+        x: &[(u64, f64)],
+        max_attempts: Option<u64>,
+    ) -> Result<(Vec<Dart>, ErsSketchStats), CapViolationError> {
+        self.sketch_alloc(x, Self::fixed_length(max_attempts))
+            .map_err(cap_violation)
+    }
+
+    /// Like `sketch`, writing into `out` and reusing the buffers of `scratch`,
+    /// see [`crate::scratch`].
+    pub fn sketch_into(
+        &self,
+        x: &[(u64, f64)],
+        max_attempts: Option<u64>,
+        scratch: &mut Scratch,
+        out: &mut Vec<Dart>,
+    ) -> ErsSketchStats {
+        self.sketch_impl(x, Self::fixed_length(max_attempts), scratch, out)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    #[inline]
+    fn fixed_length(max_attempts: Option<u64>) -> SequenceLength {
         const L_DEFAULT: u32 = 1024;
-        let l_per_hash: u32 = max_attempts.map(|v| v as u32).unwrap_or(L_DEFAULT);
+        SequenceLength::Fixed(max_attempts.map(|v| v as u32).unwrap_or(L_DEFAULT))
+    }
 
-        let d = self.index.d();
-        let m = self.index.m_total();
+    /// ERS with L chosen per input from its mass, see
+    /// [`RedGreenIndex::l_for_mass`], so that each bucket is empty (and
+    /// densified) with probability at most `empty_prob`. Panics like `sketch`.
+    pub fn sketch_auto(&self, x: &[(u64, f64)], empty_prob: f64) -> Vec<Dart> {
+        self.try_sketch_auto(x, empty_prob)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
 
-        // One slot per hash position j
-        let mut buckets: Vec<Option<(u64 /*id*/, u32 /*time*/)>> = vec![None; self.k];
+    /// Like `sketch_auto`, returning an error on a cap violation under
+    /// `CapPolicy::Error`.
+    pub fn try_sketch_auto(
+        &self,
+        x: &[(u64, f64)],
+        empty_prob: f64,
+    ) -> Result<Vec<Dart>, CapViolationError> {
+        self.sketch_alloc(x, Self::auto_length(empty_prob))
+            .map(|(sketch, _)| sketch)
+            .map_err(cap_violation)
+    }
 
-        // Use per-thread scratch to avoid O(D) alloc/zero and O(D) mass sum.
-        let mut out: Option<Vec<Dart>> = None;
+    /// Like `sketch_auto`, also reporting L, the attempts per bucket and the
+    /// number of densified buckets.
+    pub fn sketch_auto_with_stats(
+        &self,
+        x: &[(u64, f64)],
+        empty_prob: f64,
+    ) -> (Vec<Dart>, ErsSketchStats) {
+        self.try_sketch_auto_with_stats(x, empty_prob)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
 
-        ERS_SCRATCH.with(|cell| {
-            let mut scratch = cell.borrow_mut();
+    pub fn try_sketch_auto_with_stats(
+        &self,
+        x: &[(u64, f64)],
+        empty_prob: f64,
+    ) -> Result<(Vec<Dart>, ErsSketchStats), CapViolationError> {
+        self.sketch_alloc(x, Self::auto_length(empty_prob))
+            .map_err(cap_violation)
+    }
 
-            // Fill dense vector (only touched indices) and compute mass with dense semantics.
-            let mass = scratch.fill_from_sparse_and_mass(d, x);
+    /// Like `sketch_auto`, writing into `out` and reusing the buffers of
+    /// `scratch`.
+    pub fn sketch_auto_into(
+        &self,
+        x: &[(u64, f64)],
+        empty_prob: f64,
+        scratch: &mut Scratch,
+        out: &mut Vec<Dart>,
+    ) -> ErsSketchStats {
+        self.sketch_impl(x, Self::auto_length(empty_prob), scratch, out)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
 
-            // Degenerate: no mass or M==0 → deterministic fallback
-            if m == 0.0 || mass == 0.0 || d == 0 {
-                let mut fallback = Vec::with_capacity(self.k);
-                for j in 0..self.k {
-                    let fake = (self.t_rot.hash(j as u32) as u64) << 32 | (j as u64);
-                    fallback.push((fake, f64::INFINITY));
+    #[inline]
+    fn auto_length(empty_prob: f64) -> SequenceLength {
+        assert!(
+            empty_prob > 0.0 && empty_prob < 1.0,
+            "empty_prob must be in (0, 1)"
+        );
+        SequenceLength::Auto(empty_prob)
+    }
+
+    #[inline]
+    pub fn index(&self) -> &RedGreenIndex {
+        &self.index
+    }
+
+    /// Allocate the output and sketch with the per-thread scratch.
+    fn sketch_alloc(
+        &self,
+        x: &[(u64, f64)],
+        sequence_length: SequenceLength,
+    ) -> Result<(Vec<Dart>, ErsSketchStats), RsError> {
+        let mut out = Vec::with_capacity(self.k);
+        let stats = ERS_SCRATCH.with(|cell| {
+            let scratch = &mut cell.borrow_mut();
+            self.sketch_impl(x, sequence_length, scratch, &mut out)
+        })?;
+        Ok((out, stats))
+    }
+
+    fn sketch_impl(
+        &self,
+        x: &[(u64, f64)],
+        sequence_length: SequenceLength,
+        scratch: &mut Scratch,
+        out: &mut Vec<Dart>,
+    ) -> Result<ErsSketchStats, RsError> {
+        let d = self.index.d();
+        let m = self.index.m_total();
+        let weights = &mut scratch.weights;
+
+        // Fill dense vector (only touched indices) or sorted sparse
+        // positions, and compute the covered mass with dense semantics.
+        let mass = weights.fill(&self.index, x);
+        let l_per_hash = match sequence_length {
+            SequenceLength::Fixed(l) => l,
+            SequenceLength::Auto(empty_prob) => {
+                let l_max = (self.attempt_budget / self.k as u64).clamp(1, u32::MAX as u64);
+                self.index.l_for_mass(mass, empty_prob).min(l_max as u32)
+            }
+            SequenceLength::Unbounded => u32::MAX,
+        };
+        let (cap_violations, first_violation) = weights.cap_violations(&self.index);
+        let mut stats = ErsSketchStats {
+            l: l_per_hash,
+            densified_buckets: self.k,
+            attempts: 0,
+            max_bucket_attempts: 0,
+            cap_violations,
+            fallback: false,
+        };
+
+        if let Some((id, weight, cap)) = first_violation {
+            match &self.cap_policy {
+                CapPolicy::Clip => {}
+                CapPolicy::Fallback(sketcher) => {
+                    weights.clear_touched();
+                    stats.densified_buckets = 0;
+                    stats.fallback = true;
+                    sketcher.sketch_into(x, scratch, out);
+                    return Ok(stats);
+                }
+                CapPolicy::Error => {
+                    weights.clear_touched();
+                    return Err(RsError::CapViolation(CapViolationError {
+                        violations: cap_violations,
+                        id,
+                        weight,
+                        cap,
+                    }));
                 }
-                scratch.clear_touched();
-                out = Some(fallback);
-                return;
             }
+        }
 
-            let w = &scratch.w;
+        // Degenerate: no mass or M==0 → deterministic fallback
+        if m == 0.0 || mass == 0.0 || d == 0 {
+            weights.clear_touched();
+            self.fallback_sketch_into(out);
+            return Ok(stats);
+        }
 
-            // Fixed-length sequences; accept first green per j
-            for j in 0..self.k {
-                for t in 1..=l_per_hash {
-                    // key = (j, t)
-                    let key = ((j as u64) << 32) ^ (t as u64);
+        if let SequenceLength::Unbounded = sequence_length {
+            let expected_attempts = self.k as f64 * m / mass;
+            if expected_attempts > self.attempt_budget as f64 {
+                weights.clear_touched();
+                return Err(RsError::AttemptBudget {
+                    expected_attempts,
+                    budget: self.attempt_budget,
+                });
+            }
+        }
 
-                    // O(1) interval sample + offset
-                    let (i, off) = self.index.sample_interval_and_offset(&self.t_u, key);
+        // One slot per hash position j: (id, time).
+        // Fixed-length sequences; accept first green per j
+        let w = weights.weights(&self.index);
+        let buckets = &mut scratch.buckets;
+        buckets.clear();
+        buckets.extend((0..self.k).map(|j| self.first_green(w, j, l_per_hash)));
+        weights.clear_touched();
+        for bucket in buckets.iter() {
+            let attempts = bucket.map_or(l_per_hash, |(_, t)| t);
+            stats.attempts += attempts as u64;
+            stats.max_bucket_attempts = stats.max_bucket_attempts.max(attempts);
+        }
 
-                    if self.is_green_offset(w, i, off) {
-                        // Reconstruct r so ID hashing matches the previous definition.
-                        let r = self.index.base_of(i) + off;
-                        let id = self.t_id.hash(r.to_bits());
-                        buckets[j] = Some((id, t));
-                        break;
-                    }
-                }
+        // If *all* buckets empty (very rare with decent L), fallback
+        if buckets.iter().all(|b| b.is_none()) {
+            self.fallback_sketch_into(out);
+            return Ok(stats);
+        }
+        stats.densified_buckets = buckets.iter().filter(|b| b.is_none()).count();
+
+        // Plain RS does not densify: a bucket can only stay empty if
+        // mass / M is below ~1e-9.
+        if !matches!(sequence_length, SequenceLength::Unbounded) {
+            match self.densification {
+                Densification::Rotation => self.densify_rotation(buckets),
+                Densification::Optimal => self.densify_optimal(buckets, &mut scratch.filled),
             }
+        }
 
-            // If *all* buckets empty (very rare with decent L), fallback
-            if buckets.iter().all(|b| b.is_none()) {
-                let mut fallback = Vec::with_capacity(self.k);
-                for j in 0..self.k {
+        // Convert to (id, rank) = (hash_id, time as f64)
+        out.clear();
+        for (j, bucket) in buckets.iter().enumerate() {
+            out.push(match *bucket {
+                Some((id, t)) => (id, t as f64),
+                None => {
                     let fake = (self.t_rot.hash(j as u32) as u64) << 32 | (j as u64);
-                    fallback.push((fake, f64::INFINITY));
+                    (fake, f64::INFINITY)
                 }
-                scratch.clear_touched();
-                out = Some(fallback);
-                return;
-            }
+            });
+        }
+        Ok(stats)
+    }
 
-            // Rotation densification
-            for j in 0..self.k {
-                if buckets[j].is_none() {
-                    // offset in {1,..,k-1}
-                    let offset = (self.t_rot.hash(j as u32) as usize
-                        % (self.k.saturating_sub(1)).max(1))
-                        + 1;
-                    let mut idx = (j + offset) % self.k;
-
-                    for _ in 0..(self.k - 1) {
-                        if let Some(val) = buckets[idx] {
-                            buckets[j] = Some(val);
-                            break;
-                        }
-                        idx += 1;
-                        if idx == self.k {
-                            idx = 0;
-                        }
+    /// Fill empty buckets by rotating from a per-j random offset.
+    fn densify_rotation(&self, buckets: &mut [Option<(u64, u32)>]) {
+        for j in 0..self.k {
+            if buckets[j].is_none() {
+                // offset in {1,..,k-1}
+                let offset =
+                    (self.t_rot.hash(j as u32) as usize % (self.k.saturating_sub(1)).max(1)) + 1;
+                let mut idx = (j + offset) % self.k;
+
+                for _ in 0..(self.k - 1) {
+                    if let Some(val) = buckets[idx] {
+                        buckets[j] = Some(val);
+                        break;
                     }
-
-                    // ultra-rare guard
-                    if buckets[j].is_none() {
-                        let fake = (self.t_rot.hash(j as u32) as u64) << 32 | (j as u64);
-                        buckets[j] = Some((fake, u32::MAX));
+                    idx += 1;
+                    if idx == self.k {
+                        idx = 0;
                     }
                 }
-            }
 
-            // Convert to (id, rank) = (hash_id, time as f64)
-            let mut result = Vec::with_capacity(self.k);
-            for j in 0..self.k {
-                let (id, t) = buckets[j].unwrap();
-                result.push((id, t as f64));
+                // ultra-rare guard
+                if buckets[j].is_none() {
+                    let fake = (self.t_rot.hash(j as u32) as u64) << 32 | (j as u64);
+                    buckets[j] = Some((fake, u32::MAX));
+                }
             }
+        }
+    }
 
-            scratch.clear_touched();
-            out = Some(result);
-        });
+    /// Fill empty buckets by 2-universal probing among the buckets that were
+    /// non-empty before densification.
+    fn densify_optimal(&self, buckets: &mut [Option<(u64, u32)>], filled: &mut Vec<bool>) {
+        filled.clear();
+        filled.extend(buckets.iter().map(|b| b.is_some()));
+        for j in 0..self.k {
+            if filled[j] {
+                continue;
+            }
+            // At least one bucket is filled, so this ends after about
+            // k / #filled probes; the bound only guards pathological hashes.
+            for attempt in 1..=(64 * self.k as u64) {
+                let idx = self.probe(j, attempt);
+                if filled[idx] {
+                    buckets[j] = buckets[idx];
+                    break;
+                }
+            }
+            if buckets[j].is_none() {
+                let idx = (j..self.k).chain(0..j).find(|&i| filled[i]).unwrap();
+                buckets[j] = buckets[idx];
+            }
+        }
+    }
 
-        out.expect("ERS_SCRATCH closure must set out")
+    /// 2-universal hash of (j, attempt) into [0, k).
+    #[inline]
+    fn probe(&self, j: usize, attempt: u64) -> usize {
+        let x = (attempt << 32) | (j as u64 & 0xffff_ffff);
+        let v = (self.probe_a as u128) * (x as u128) + (self.probe_b as u128);
+        // reduce mod 2^61 - 1
+        let lo = (v as u64) & MERSENNE_61;
+        let hi = (v >> 61) as u64;
+        let mut r = lo + (hi & MERSENNE_61) + (hi >> 61);
+        while r >= MERSENNE_61 {
+            r -= MERSENNE_61;
+        }
+        (r % self.k as u64) as usize
     }
 
-    /// Uses default L (L_DEFAULT).
+    /// Uses default L (L_DEFAULT). Panics like `sketch`.
     #[inline]
     pub fn sketch_early_stop(&self, x: &[(u64, f64)]) -> Vec<Dart> {
         self.sketch(x, None)
     }
+
+    /// Plain rejection sampling (RS): every hash position draws until its first
+    /// green, so the sketch is unbiased and needs no densification. Shares the
+    /// sequences of `sketch`: whenever a bucket of `sketch` is non-empty, both
+    /// modes agree on it. Inputs without mass get the same fallback sketch.
+    ///
+    /// Panics on a cap violation like `sketch`, and if the expected number of
+    /// draws exceeds the attempt budget, see `try_sketch_rs`.
+    pub fn sketch_rs(&self, x: &[(u64, f64)]) -> Vec<Dart> {
+        self.try_sketch_rs(x).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Like `sketch_rs`, also reporting the attempts per bucket.
+    pub fn sketch_rs_with_stats(&self, x: &[(u64, f64)]) -> (Vec<Dart>, ErsSketchStats) {
+        self.try_sketch_rs_with_stats(x)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Like `sketch_rs`, returning an error on a cap violation under
+    /// `CapPolicy::Error`, or if k M / mass exceeds the attempt budget.
+    pub fn try_sketch_rs(&self, x: &[(u64, f64)]) -> Result<Vec<Dart>, RsError> {
+        Ok(self.sketch_alloc(x, SequenceLength::Unbounded)?.0)
+    }
+
+    pub fn try_sketch_rs_with_stats(
+        &self,
+        x: &[(u64, f64)],
+    ) -> Result<(Vec<Dart>, ErsSketchStats), RsError> {
+        self.sketch_alloc(x, SequenceLength::Unbounded)
+    }
+
+    /// Like `sketch_rs`, writing into `out` and reusing the buffers of
+    /// `scratch`.
+    pub fn sketch_rs_into(
+        &self,
+        x: &[(u64, f64)],
+        scratch: &mut Scratch,
+        out: &mut Vec<Dart>,
+    ) -> ErsSketchStats {
+        self.sketch_impl(x, SequenceLength::Unbounded, scratch, out)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+}
+
+impl RedGreenIndex {
+    /// Caps, prefix sums and the alias table, stored as they are.
+    pub(crate) fn write_state(&self, w: &mut StateWriter) {
+        w.put_len(self.d);
+        w.put_f64(self.m_total);
+        w.put_f64s(&self.base);
+        w.put_f64s(&self.cap);
+        w.put_f64s(&self.prob);
+        w.put_u32s(&self.alias);
+        match &self.ids {
+            None => w.put_u8(0),
+            Some(ids) => {
+                w.put_u8(1);
+                w.put_u64s(ids);
+            }
+        }
+    }
+
+    pub(crate) fn read_state(r: &mut StateReader) -> io::Result<Self> {
+        let d = r.get_u64()? as usize;
+        let m_total = r.get_f64()?;
+        let base = r.get_f64s()?;
+        let cap = r.get_f64s()?;
+        let prob = r.get_f64s()?;
+        let alias = r.get_u32s()?;
+        let ids = match r.get_u8()? {
+            0 => None,
+            1 => Some(r.get_u64s()?),
+            tag => return Err(invalid(format!("RedGreenIndex: bad ids tag {tag}"))),
+        };
+        let consistent = [base.len(), cap.len(), prob.len(), alias.len()]
+            .iter()
+            .all(|&len| len == d)
+            && alias.iter().all(|&a| (a as usize) < d)
+            && ids.as_ref().is_none_or(|ids| ids.len() == d);
+        if !consistent {
+            return Err(invalid("RedGreenIndex: inconsistent lengths"));
+        }
+        // The prefix sums and M are recomputed in the order of `from_caps`,
+        // so a valid state matches them bit for bit.
+        let mut acc = 0.0f64;
+        let sums_match = cap.iter().zip(&base).all(|(&mi, &b)| {
+            let matches = b == acc;
+            acc += mi;
+            matches
+        });
+        if !cap.iter().all(|&mi| mi >= 0.0 && mi.is_finite())
+            || !sums_match
+            || m_total != acc
+            || !prob.iter().all(|p| (0.0..=1.0).contains(p))
+        {
+            return Err(invalid(
+                "RedGreenIndex: invalid caps, prefix sums or alias table",
+            ));
+        }
+        if ids
+            .as_ref()
+            .is_some_and(|ids| ids.windows(2).any(|w| w[0] >= w[1]))
+        {
+            return Err(invalid("RedGreenIndex: ids are not sorted and unique"));
+        }
+        Ok(Self {
+            base,
+            cap,
+            d,
+            m_total,
+            prob,
+            alias,
+            ids,
+        })
+    }
+}
+
+/// Caps index, hash tables, densification and cap policy, including the
+/// state of a `CapPolicy::Fallback` sketcher.
+impl<F: HashFamily> SketcherState for ErsWmh<F> {
+    const TAG: u8 = 4;
+
+    fn write_body(&self, w: &mut StateWriter) {
+        write_family(w, self.family.kind());
+        self.index.write_state(w);
+        self.t_u.write_state(w);
+        self.t_id.write_state(w);
+        self.t_rot.write_state(w);
+        w.put_u64(self.k as u64);
+        w.put_u8(match self.densification {
+            Densification::Rotation => 0,
+            Densification::Optimal => 1,
+        });
+        match &self.cap_policy {
+            CapPolicy::Clip => w.put_u8(0),
+            CapPolicy::Error => w.put_u8(1),
+            CapPolicy::Fallback(tmh) => {
+                w.put_u8(2);
+                tmh.write_body(w);
+            }
+        }
+        w.put_u64(self.probe_a);
+        w.put_u64(self.probe_b);
+        w.put_u64(self.attempt_budget);
+    }
+
+    fn read_body(r: &mut StateReader) -> io::Result<Self> {
+        let family = read_family::<F>(r)?;
+        let index = RedGreenIndex::read_state(r)?;
+        let t_u = F::H64::read_state(r)?;
+        let t_id = F::H64::read_state(r)?;
+        let t_rot = F::H32::read_state(r)?;
+        let k = r.get_u64()? as usize;
+        let densification = match r.get_u8()? {
+            0 => Densification::Rotation,
+            1 => Densification::Optimal,
+            tag => return Err(invalid(format!("ErsWmh: bad densification tag {tag}"))),
+        };
+        let cap_policy = match r.get_u8()? {
+            0 => CapPolicy::Clip,
+            1 => CapPolicy::Error,
+            2 => CapPolicy::Fallback(Box::new(TreeMinHash::read_body(r)?)),
+            tag => return Err(invalid(format!("ErsWmh: bad cap policy tag {tag}"))),
+        };
+        let probe_a = r.get_u64()?;
+        let probe_b = r.get_u64()?;
+        let attempt_budget = r.get_u64()?;
+        if k == 0 || !(1..MERSENNE_61).contains(&probe_a) || probe_b >= MERSENNE_61 {
+            return Err(invalid("ErsWmh: invalid k or probing hash"));
+        }
+        if attempt_budget == 0 {
+            return Err(invalid("ErsWmh: zero attempt budget"));
+        }
+        if let CapPolicy::Fallback(tmh) = &cap_policy
+            && tmh.k() != k as u64
+        {
+            return Err(invalid("ErsWmh: fallback sketcher has a different k"));
+        }
+        Ok(Self {
+            index,
+            family,
+            t_u,
+            t_id,
+            t_rot,
+            k,
+            densification,
+            cap_policy,
+            attempt_budget,
+            probe_a,
+            probe_b,
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::rng_utils::{MtRng, mt_from_seed};
+    use crate::test_utils::assert_estimate_close;
     use rand_core::RngCore;
 
     /// Generate a random weighted set with ids in [0, d)
+    #[allow(clippy::explicit_counter_loop)]
     fn generate_weighted_set(d: usize, l0: u64, l1: f64, rng: &mut MtRng) -> Vec<(u64, f64)> {
         use std::collections::HashSet;
         let mut elements = HashSet::with_capacity(l0 as usize);
@@ -541,6 +1388,313 @@ mod tests {
         let sk = ers.sketch(&x, Some(l));
 
         assert_eq!(sk.len(), k);
+
+        // RS never leaves a bucket empty; where ERS found a green within L,
+        // both modes agree.
+        let sk_rs = ers.sketch_rs(&x);
+        assert_eq!(sk_rs.len(), k);
+        assert!(sk_rs.iter().all(|&(_, t)| t.is_finite() && t >= 1.0));
+        let sk_short = ers.sketch(&x, Some(1));
+        for (a, b) in sk_short.iter().zip(&sk_rs) {
+            if a.1 == 1.0 {
+                assert_eq!(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn ers_sparse_caps_over_hashed_ids() {
+        use crate::similarity::jaccard_similarity;
+
+        let mut data_rng = mt_from_seed(777);
+        let mut hash_rng = mt_from_seed(0xe255_0003);
+        let k = 2048;
+
+        // Full 64-bit ids: a dense cap vector is impossible here.
+        let x = generate_weighted_set(usize::MAX, 10_000, 1_000.0, &mut data_rng);
+        let y = generate_similar_weighted_set(usize::MAX, &x, 0.6, &mut data_rng);
+        let mut caps: Vec<(u64, f64)> = x.iter().chain(&y).copied().collect();
+        caps.push((x[0].0, 0.5 * x[0].1)); // duplicates keep the max
+
+        let ers = ErsWmh::new_sparse(&mut hash_rng, &caps, k as u64);
+        assert!(ers.index().is_sparse());
+        assert_eq!(ers.index().d(), x.len() + 1);
+        assert_eq!(ers.index().position_of(x[0].0), Some(0));
+        assert_eq!(ers.index().position_of(x[0].0 ^ 1), None);
+
+        let j_true = jaccard_similarity(&x, &y);
+        for (mode, sk_x, sk_y) in [
+            ("ERS", ers.sketch(&x, Some(64)), ers.sketch(&y, Some(64))),
+            ("RS", ers.sketch_rs(&x), ers.sketch_rs(&y)),
+        ] {
+            let hits = sk_x.iter().zip(&sk_y).filter(|(a, b)| a.0 == b.0).count();
+            let j_est = hits as f64 / k as f64;
+            assert_estimate_close(j_true, j_est, k as u64, mode);
+        }
+
+        // Uncapped ids never turn green, so they do not change the sketch.
+        let mut x_extra = x.clone();
+        x_extra.push((x[0].0 ^ 1, 1.0));
+        assert_eq!(ers.sketch_rs(&x), ers.sketch_rs(&x_extra));
+    }
+
+    #[test]
+    fn ers_sparse_caps_match_dense_caps() {
+        let mut data_rng = mt_from_seed(778);
+        let d = 5_000usize;
+        let x = generate_weighted_set(d, 500, 100.0, &mut data_rng);
+        // Every dimension capped, so compact positions equal the ids.
+        let caps: Vec<f64> = (0..d)
+            .map(|_| 0.5 + mt19937::gen_res53(&mut data_rng))
+            .collect();
+        let sparse_caps: Vec<(u64, f64)> = caps
+            .iter()
+            .enumerate()
+            .map(|(i, &m)| (i as u64, m))
+            .rev()
+            .collect();
+
+        let dense = ErsWmh::new_mt(&mut mt_from_seed(0xe255_0004), &caps, 256);
+        let sparse = ErsWmh::new_sparse(&mut mt_from_seed(0xe255_0004), &sparse_caps, 256);
+        assert_eq!(dense.sketch(&x, Some(32)), sparse.sketch(&x, Some(32)));
+        assert_eq!(dense.sketch_rs(&x), sparse.sketch_rs(&x));
+    }
+
+    #[test]
+    fn ers_cap_policies() {
+        let mut data_rng = mt_from_seed(4343);
+        let d = 10_000usize;
+        let k = 512;
+
+        let x = generate_weighted_set(d, 1_000, 100.0, &mut data_rng);
+        let caps = caps_from_sets(d, &[&x]);
+        // Drift: two weights above their caps and one id without a cap.
+        let mut y = x.clone();
+        y[3].1 *= 2.0;
+        y[7].1 *= 1.5;
+        let free_id = (0..d as u64).find(|id| caps[*id as usize] == 0.0).unwrap();
+        y.push((free_id, 1.0));
+        y.sort_by_key(|p| p.0);
+
+        let clip = ErsWmh::new_mt(&mut mt_from_seed(0xe255_0005), &caps, k as u64);
+        let (sk_x, stats_x) = clip.sketch_with_stats(&x, Some(256));
+        assert_eq!(stats_x.cap_violations, 0);
+        let (sk_y_clip, stats_y) = clip.sketch_with_stats(&y, Some(256));
+        assert_eq!(stats_y.cap_violations, 3);
+        assert!(!stats_y.fallback);
+
+        // Error: inputs within their caps still sketch identically.
+        let strict = ErsWmh::new_mt(&mut mt_from_seed(0xe255_0005), &caps, k as u64)
+            .with_cap_policy(CapPolicy::Error);
+        assert_eq!(strict.try_sketch(&x, Some(256)), Ok(sk_x.clone()));
+        let err = strict.try_sketch(&y, Some(256)).unwrap_err();
+        assert_eq!(err.violations, 3);
+        assert!(err.weight > err.cap);
+        assert!(strict.try_sketch_rs(&y).is_err());
+
+        // Fallback: violating inputs get a cap-free TreeMinHash sketch.
+        let mut tmh_rng = mt_from_seed(0x7eee_0005);
+        let tmh = TreeMinHash::new_mt(&mut tmh_rng, k as u64);
+        let expected = tmh.sketch(&y);
+        let fallback = ErsWmh::new_mt(&mut mt_from_seed(0xe255_0005), &caps, k as u64)
+            .with_cap_policy(CapPolicy::Fallback(Box::new(tmh)));
+        let (sk_y, stats) = fallback.sketch_with_stats(&y, Some(256));
+        assert!(stats.fallback);
+        assert_eq!(stats.cap_violations, 3);
+        assert_eq!(sk_y, expected);
+        assert_ne!(sk_y, sk_y_clip);
+        assert_eq!(fallback.sketch(&x, Some(256)), sk_x);
+        // Every sketching path falls back, with or without stats.
+        assert_eq!(fallback.sketch(&y, Some(256)), expected);
+        assert_eq!(fallback.try_sketch(&y, Some(256)), Ok(expected.clone()));
+        assert_eq!(fallback.sketch_auto(&y, 1e-3), expected);
+        assert_eq!(fallback.sketch_rs(&y), expected);
+        let (sk_rs, stats) = fallback.sketch_rs_with_stats(&y);
+        assert!(stats.fallback);
+        assert_eq!(sk_rs, expected);
+
+        // L follows the clipped mass 1 + 0.5, not the input mass.
+        let ers = ErsWmh::new_mt(&mut mt_from_seed(0xe255_0009), &[1.0; 100], 64);
+        let (_, stats) = ers.sketch_auto_with_stats(&[(0, 50.0), (1, 0.5)], 1e-3);
+        assert_eq!(stats.l, ers.index().l_for_mass(1.5, 1e-3));
+        assert!(stats.l > ers.index().l_for_mass(50.5, 1e-3));
+    }
+
+    #[test]
+    fn ers_dense_ids_beyond_d_have_no_cap() {
+        let mut data_rng = mt_from_seed(4344);
+        let x = generate_weighted_set(1_000, 100, 100.0, &mut data_rng);
+        let k = 64;
+
+        // The larger index leaves the shared weight buffer longer than 10.
+        let mut scratch = Scratch::new();
+        let mut out = Vec::new();
+        let large = ErsWmh::new_mt(&mut mt_from_seed(0xe255_0006), &[1.0; 1_000], k);
+        large.sketch_into(&x, Some(64), &mut scratch, &mut out);
+
+        let small = ErsWmh::new_mt(&mut mt_from_seed(0xe255_0007), &[1.0; 10], k);
+        let y = [(3, 0.5), (500, 2.0), (u64::MAX, 1.0), (500, 3.0)];
+        let stats = small.sketch_into(&y, Some(64), &mut scratch, &mut out);
+        assert_eq!(stats.cap_violations, 2);
+        assert_eq!(out, small.sketch(&[(3, 0.5)], Some(64)));
+        // The thread-local scratch was sized by the large index as well.
+        assert_eq!(small.sketch(&y, Some(64)), out);
+
+        let strict = ErsWmh::new_mt(&mut mt_from_seed(0xe255_0007), &[1.0; 10], k)
+            .with_cap_policy(CapPolicy::Error);
+        let err = strict.try_sketch(&y, Some(64)).unwrap_err();
+        assert_eq!(err.violations, 2);
+        assert_eq!((err.id, err.weight, err.cap), (500, 3.0, 0.0));
+    }
+
+    #[test]
+    fn ers_uncapped_inputs_have_no_mass() {
+        let k = 256;
+        let sparse_caps: Vec<(u64, f64)> = (0..10).map(|i| (i, 1.0)).collect();
+        let sparse = ErsWmh::new_sparse(&mut mt_from_seed(0xe255_0008), &sparse_caps, k);
+        let dense = ErsWmh::new_mt(&mut mt_from_seed(0xe255_0008), &[1.0; 10], k);
+        // Not a single id has a cap: RS would never find a green.
+        let x: Vec<(u64, f64)> = (1_000..1_100).map(|i| (i, 5.0)).collect();
+        for ers in [&sparse, &dense] {
+            let (sk, stats) = ers.sketch_rs_with_stats(&x);
+            assert_eq!(stats.cap_violations, x.len());
+            assert_eq!(stats.attempts, 0);
+            assert_eq!(stats.densified_buckets, k as usize);
+            assert_eq!(sk, ers.sketch_rs(&[]));
+            assert_eq!(ers.sketch_auto_with_stats(&x, 1e-3).1.l, 1);
+        }
+    }
+
+    #[test]
+    fn ers_attempt_budget_bounds_low_acceptance() {
+        let k = 64;
+        // One small weight against M ~ 1e9: RS expects ~1e10 draws per bucket.
+        let caps = [(0, 1.0), (1, 1e9)];
+        let ers = ErsWmh::new_sparse(&mut mt_from_seed(0xe255_000a), &caps, k)
+            .with_attempt_budget(1 << 20);
+        let x = [(0, 0.1)];
+        match ers.try_sketch_rs(&x) {
+            Err(RsError::AttemptBudget {
+                expected_attempts,
+                budget,
+            }) => {
+                assert_eq!(budget, 1 << 20);
+                assert!((expected_attempts / (k as f64 * 1e10) - 1.0).abs() < 1e-6);
+            }
+            other => panic!("{other:?}"),
+        }
+
+        // ERS stays within the budget and densifies instead.
+        let (sk, stats) = ers.sketch_auto_with_stats(&x, 1e-3);
+        assert_eq!(stats.l, (1 << 20) / k as u32);
+        assert!(stats.attempts <= 1 << 20);
+        assert_eq!(sk.len(), k as usize);
+
+        // Inputs within the budget are unaffected.
+        let y = [(0, 0.1), (1, 1e8)];
+        let budgeted = ers.sketch_rs(&y);
+        let unbudgeted = ErsWmh::new_sparse(&mut mt_from_seed(0xe255_000a), &caps, k);
+        assert_eq!(budgeted, unbudgeted.sketch_rs(&y));
+    }
+
+    #[test]
+    fn ers_auto_l_from_mass() {
+        let mut data_rng = mt_from_seed(4242);
+        let mut hash_rng = mt_from_seed(0xe255_0002);
+        let d = 100_000usize;
+        let k = 2048;
+
+        let x = generate_weighted_set(d, 20_000, 1_000.0, &mut data_rng);
+        // Loose caps: every dimension allows up to the largest weight of x.
+        let w_max = x.iter().map(|&(_, w)| w).fold(0.0, f64::max);
+        let ers = ErsWmh::new_mt(&mut hash_rng, &vec![w_max; d], k as u64);
+
+        let index = ers.index();
+        let mass: f64 = x.iter().map(|&(_, w)| w).sum();
+        let q = mass / index.m_total();
+        let l = index.l_for_mass(mass, 1e-3);
+        assert!((1.0 - q).powi(l as i32) <= 1e-3);
+        assert!((1.0 - q).powi(l as i32 - 1) > 1e-3);
+        assert_eq!(index.l_for_mass(index.m_total(), 1e-3), 1);
+
+        let (sk, stats) = ers.sketch_auto_with_stats(&x, 1e-3);
+        assert_eq!(stats.l, l);
+        assert_eq!(sk, ers.sketch(&x, Some(l as u64)));
+        // ~k * 1e-3 = 2 expected empties
+        assert!(stats.densified_buckets <= 12, "{stats:?}");
+
+        // RS attempts per bucket are geometric with success probability q.
+        let (_, rs) = ers.sketch_rs_with_stats(&x);
+        let mean = rs.attempts as f64 / k as f64;
+        let sd = ((1.0 - q) / (q * q * k as f64)).sqrt();
+        assert!(
+            (mean - 1.0 / q).abs() <= 4.0 * sd,
+            "{rs:?}, 1/q = {}",
+            1.0 / q
+        );
+        assert_eq!(rs.densified_buckets, 0);
+
+        // A too-short L leaves (and densifies) about k (1 - q)^L buckets.
+        let (_, short) = ers.sketch_with_stats(&x, Some((l / 4) as u64));
+        assert_eq!(short.max_bucket_attempts, l / 4);
+        assert!(short.attempts < rs.attempts);
+        let expected = k as f64 * (1.0 - q).powi((l / 4) as i32);
+        assert!(
+            (short.densified_buckets as f64 - expected).abs() < 5.0 * expected.sqrt() + 5.0,
+            "{short:?}, expected {expected}"
+        );
+
+        let (_, empty) = ers.sketch_auto_with_stats(&[], 1e-3);
+        assert_eq!(empty.densified_buckets, k);
+    }
+
+    #[test]
+    fn ers_optimal_densification_reduces_variance() {
+        use crate::similarity::{jaccard_similarity, weight};
+
+        let mut data_rng = mt_from_seed(31337);
+        let d = 50_000usize;
+        let k = 1024;
+
+        let x = generate_weighted_set(d, 5_000, 1_000.0, &mut data_rng);
+        let y = generate_similar_weighted_set(d, &x, 0.5, &mut data_rng);
+        let j_true = jaccard_similarity(&x, &y);
+        // Inflated caps so that a short L leaves most buckets empty.
+        let caps: Vec<f64> = caps_from_sets(d, &[&x, &y])
+            .into_iter()
+            .map(|m| 20.0 * m)
+            .collect();
+
+        let runs = 40;
+        let mut sq_err = [0.0f64; 2];
+        let mut empty_frac = 0.0;
+        for run in 0..runs {
+            let mut hash_rng = mt_from_seed(0xe255_2000 ^ run);
+            let ers = ErsWmh::new_mt(&mut hash_rng, &caps, k as u64);
+            let l = ers.index().l_for_mass(weight(&x), 0.85);
+            let (sk_x, stats) = ers.sketch_with_stats(&x, Some(l as u64));
+            let sk_y = ers.sketch(&y, Some(l as u64));
+            empty_frac += stats.densified_buckets as f64 / (k * runs as usize) as f64;
+
+            let ers = ers.with_densification(Densification::Optimal);
+            let sk_x_opt = ers.sketch(&x, Some(l as u64));
+            let sk_y_opt = ers.sketch(&y, Some(l as u64));
+
+            for (m, (a, b)) in [(&sk_x, &sk_y), (&sk_x_opt, &sk_y_opt)].iter().enumerate() {
+                let hits = a.iter().zip(b.iter()).filter(|(p, q)| p.0 == q.0).count();
+                let j_est = hits as f64 / k as f64;
+                sq_err[m] += (j_est - j_true).powi(2) / runs as f64;
+            }
+        }
+        let [rmse_rot, rmse_opt] = sq_err.map(f64::sqrt);
+        assert!(
+            empty_frac > 0.5,
+            "L should leave most buckets empty: {empty_frac}"
+        );
+        assert!(
+            rmse_opt < rmse_rot,
+            "rotation={rmse_rot:.5}, optimal={rmse_opt:.5}"
+        );
     }
 
     #[test]
@@ -574,22 +1728,25 @@ mod tests {
             let mut hash_rng = mt_from_seed(0xe255_1000 ^ (target_idx as u64));
             let ers = ErsWmh::new_mt(&mut hash_rng, &m_per_dim, k as u64);
 
-            // ERS (Alg.2): collision rate of per-bucket IDs
-            let sk_x = ers.sketch(&x, Some(l));
-            let sk_y = ers.sketch(&y, Some(l));
-
-            let hits = sk_x.iter().zip(&sk_y).filter(|(a, b)| a.0 == b.0).count();
-            let j_est = hits as f64 / k as f64;
-            println!("estimated weighted Jaccard: {:?}", j_est);
-
-            // σ-aware tolerance
-            let sd = (j_true * (1.0 - j_true) / (k as f64)).sqrt();
-            let tol = (3.2 * sd).max(1.25 / (k as f64).sqrt());
-            let err = (j_true - j_est).abs();
-            assert!(
-                err <= tol,
-                "rel={rel:.3}, true={j_true:.6}, est={j_est:.6}, err={err:.6}, tol={tol:.6}"
-            );
+            // ERS (Alg.2) and plain RS: collision rate of per-bucket IDs
+            let modes = [
+                ("ERS", ers.sketch(&x, Some(l)), ers.sketch(&y, Some(l))),
+                ("RS", ers.sketch_rs(&x), ers.sketch_rs(&y)),
+            ];
+            for (mode, sk_x, sk_y) in &modes {
+                let hits = sk_x.iter().zip(sk_y).filter(|(a, b)| a.0 == b.0).count();
+                let j_est = hits as f64 / k as f64;
+                println!("{mode} estimated weighted Jaccard: {:?}", j_est);
+
+                // σ-aware tolerance
+                let sd = (j_true * (1.0 - j_true) / (k as f64)).sqrt();
+                let tol = (3.2 * sd).max(1.25 / (k as f64).sqrt());
+                let err = (j_true - j_est).abs();
+                assert!(
+                    err <= tol,
+                    "{mode}: rel={rel:.3}, true={j_true:.6}, est={j_est:.6}, err={err:.6}, tol={tol:.6}"
+                );
+            }
         }
     }
 }
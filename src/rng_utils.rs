@@ -1,4 +1,5 @@
-//! Thin wrapper to build an MT19937 rng that implements `RngCore`.
+//! Thin wrapper to build an MT19937 rng that implements `RngCore`, plus
+//! full-entropy seeding and per-label seed derivation (`SeedDeriver`).
 
 use mt19937::{MT19937, Seed};
 use rand_core::SeedableRng;
@@ -15,4 +16,270 @@ pub fn mt_from_seed(seed64: u64) -> MT19937 {
 }
 
 // Allow MT19937 to be used as a mutable RNGCore directly
-pub type MtRng = MT19937;
\ No newline at end of file
+pub type MtRng = MT19937;
+
+// Golden-ratio increment of SplitMix64.
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+// SplitMix64 finalizer: a bijective mix of all 64 bits.
+#[inline]
+fn mix64(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// One SplitMix64 step: advance `state` and return the next output.
+#[inline]
+pub fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(GOLDEN_GAMMA);
+    mix64(*state)
+}
+
+// Build MT19937 with all 624 state words drawn from a SplitMix64 stream, so
+// nearby seeds give unrelated generators. `mt_from_seed` keeps its two-word
+// seeding for existing sketches.
+pub fn mt_from_seed_full(seed64: u64) -> MtRng {
+    let mut state = seed64;
+    let mut seed_arr = [0u32; mt19937::N];
+    for pair in seed_arr.chunks_mut(2) {
+        let z = splitmix64(&mut state);
+        pair[0] = z as u32;
+        pair[1] = (z >> 32) as u32;
+    }
+    MT19937::from_seed(Seed(seed_arr))
+}
+
+// Seed of the stream `label` under `master`: the label is absorbed 8 bytes at
+// a time through the SplitMix64 mix, after its length. Distinct labels give
+// independent-looking seeds, but anyone who knows `master` can predict them.
+pub fn derive_seed(master: u64, label: &str) -> u64 {
+    let bytes = label.as_bytes();
+    let mut h = mix64(master ^ mix64((bytes.len() as u64).wrapping_add(GOLDEN_GAMMA)));
+    for chunk in bytes.chunks(8) {
+        let mut word = [0u8; 8];
+        word[..chunk.len()].copy_from_slice(chunk);
+        h = mix64(h.wrapping_add(GOLDEN_GAMMA) ^ u64::from_le_bytes(word));
+    }
+    h
+}
+
+#[inline]
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+// SipHash-2-4 of `msg` under a 128-bit key (little-endian k0 || k1).
+pub fn siphash24(key: &[u8; 16], msg: &[u8]) -> u64 {
+    let k0 = u64::from_le_bytes(key[..8].try_into().unwrap());
+    let k1 = u64::from_le_bytes(key[8..].try_into().unwrap());
+    let mut v = [
+        k0 ^ 0x736f_6d65_7073_6575,
+        k1 ^ 0x646f_7261_6e64_6f6d,
+        k0 ^ 0x6c79_6765_6e65_7261,
+        k1 ^ 0x7465_6462_7974_6573,
+    ];
+    let chunks = msg.chunks_exact(8);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let m = u64::from_le_bytes(chunk.try_into().unwrap());
+        v[3] ^= m;
+        sip_round(&mut v);
+        sip_round(&mut v);
+        v[0] ^= m;
+    }
+    let mut last = (msg.len() as u64) << 56;
+    for (i, &b) in tail.iter().enumerate() {
+        last |= (b as u64) << (8 * i);
+    }
+    v[3] ^= last;
+    sip_round(&mut v);
+    sip_round(&mut v);
+    v[0] ^= last;
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+#[derive(Clone, PartialEq, Eq)]
+enum SeedSource {
+    Master(u64),
+    Keyed([u8; 16]),
+}
+
+// Derives independent MT19937 streams, one per label, from a master seed or
+// from a secret key. Build every sketcher of an application from its own
+// label, e.g. `DartMinHash::new(&mut seeds.rng("dmh/k=256"), 256)`.
+//
+// With `keyed`, each of the 624 state words comes from SipHash-2-4 under the
+// key, so the hash tables, and with them the sketches, cannot be predicted
+// without the key: adversaries cannot craft colliding inputs.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SeedDeriver {
+    source: SeedSource,
+}
+
+impl SeedDeriver {
+    pub fn new(master: u64) -> Self {
+        Self {
+            source: SeedSource::Master(master),
+        }
+    }
+
+    pub fn keyed(key: [u8; 16]) -> Self {
+        Self {
+            source: SeedSource::Keyed(key),
+        }
+    }
+
+    #[inline]
+    pub fn is_keyed(&self) -> bool {
+        matches!(self.source, SeedSource::Keyed(_))
+    }
+
+    // Deriver of the sub-streams of `label`, for nested components.
+    pub fn child(&self, label: &str) -> Self {
+        match &self.source {
+            SeedSource::Master(master) => Self::new(derive_seed(*master, label)),
+            SeedSource::Keyed(key) => {
+                let mut child = [0u8; 16];
+                let mut msg = label.as_bytes().to_vec();
+                msg.extend_from_slice(b"\xffchild0");
+                child[..8].copy_from_slice(&siphash24(key, &msg).to_le_bytes());
+                *msg.last_mut().unwrap() = b'1';
+                child[8..].copy_from_slice(&siphash24(key, &msg).to_le_bytes());
+                Self::keyed(child)
+            }
+        }
+    }
+
+    // Fully seeded MT19937 stream of `label`. The same deriver and label
+    // always give the same stream.
+    pub fn rng(&self, label: &str) -> MtRng {
+        match &self.source {
+            SeedSource::Master(master) => mt_from_seed_full(derive_seed(*master, label)),
+            SeedSource::Keyed(key) => {
+                // label || counter: the fixed-width counter keeps messages of
+                // different labels distinct.
+                let mut msg = label.as_bytes().to_vec();
+                let n = msg.len();
+                msg.extend_from_slice(&[0u8; 8]);
+                let mut seed_arr = [0u32; mt19937::N];
+                for (i, pair) in seed_arr.chunks_mut(2).enumerate() {
+                    msg[n..].copy_from_slice(&(i as u64).to_le_bytes());
+                    let z = siphash24(key, &msg);
+                    pair[0] = z as u32;
+                    pair[1] = (z >> 32) as u32;
+                }
+                MT19937::from_seed(Seed(seed_arr))
+            }
+        }
+    }
+}
+
+// Never prints the key.
+impl std::fmt::Debug for SeedDeriver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.source {
+            SeedSource::Master(master) => {
+                f.debug_tuple("SeedDeriver::Master").field(master).finish()
+            }
+            SeedSource::Keyed(_) => f.write_str("SeedDeriver::Keyed(..)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand_core::RngCore;
+
+    use crate::{
+        dartminhash::DartMinHash,
+        rng_utils::{SeedDeriver, derive_seed, mt_from_seed, mt_from_seed_full, siphash24},
+        similarity::{jaccard_estimate_from_minhashes, jaccard_similarity},
+        test_utils::assert_estimate_close,
+    };
+
+    #[test]
+    fn siphash24_reference_vectors() {
+        let key: [u8; 16] = std::array::from_fn(|i| i as u8);
+        let msg: Vec<u8> = (0..15).collect();
+        assert_eq!(siphash24(&key, &[]), 0x726f_db47_dd0e_0e31);
+        assert_eq!(siphash24(&key, &msg[..8]), 0x93f5_f579_9a93_2462);
+        assert_eq!(siphash24(&key, &msg), 0xa129_ca61_49be_45e5);
+    }
+
+    #[test]
+    fn derived_streams_are_reproducible_and_distinct() {
+        let first = |mut rng: crate::rng_utils::MtRng| rng.next_u64();
+
+        // Full-entropy seeding spreads nearby seeds over the whole state.
+        assert_ne!(first(mt_from_seed_full(1)), first(mt_from_seed_full(2)));
+        assert_ne!(first(mt_from_seed_full(1)), first(mt_from_seed(1)));
+
+        let seeds = SeedDeriver::new(2_024);
+        assert_eq!(first(seeds.rng("dmh")), first(seeds.rng("dmh")));
+        let labels = [
+            "",
+            "dmh",
+            "tmh",
+            "ers",
+            "dmh\0",
+            "a label longer than eight bytes",
+        ];
+        let mut outputs: Vec<u64> = labels.iter().map(|l| first(seeds.rng(l))).collect();
+        outputs.push(first(SeedDeriver::new(2_025).rng("dmh")));
+        outputs.push(first(seeds.child("dmh").rng("")));
+        let mut distinct = outputs.clone();
+        distinct.sort_unstable();
+        distinct.dedup();
+        assert_eq!(distinct.len(), outputs.len(), "{outputs:x?}");
+        assert_ne!(derive_seed(0, ""), derive_seed(0, "\0"));
+
+        let keyed = SeedDeriver::keyed([7; 16]);
+        assert!(keyed.is_keyed() && !seeds.is_keyed());
+        assert_eq!(first(keyed.rng("dmh")), first(keyed.rng("dmh")));
+        assert_ne!(first(keyed.rng("dmh")), first(keyed.rng("tmh")));
+        assert_ne!(
+            first(keyed.rng("dmh")),
+            first(SeedDeriver::keyed([8; 16]).rng("dmh"))
+        );
+        assert_ne!(keyed.child("a"), keyed.child("b"));
+        assert_eq!(format!("{keyed:?}"), "SeedDeriver::Keyed(..)");
+    }
+
+    #[test]
+    fn keyed_sketches_depend_on_the_key() {
+        let k = 512;
+        let x: Vec<(u64, f64)> = (0..300).map(|i| (i, 1.0 + (i % 7) as f64)).collect();
+        let y: Vec<(u64, f64)> = (150..450).map(|i| (i, 1.0 + (i % 5) as f64)).collect();
+
+        let key_a = SeedDeriver::keyed(*b"0123456789abcdef");
+        let key_b = SeedDeriver::keyed(*b"0123456789abcdeg");
+        let a = DartMinHash::new(&mut key_a.rng("dmh"), k);
+        let a2 = DartMinHash::new(&mut key_a.rng("dmh"), k);
+        let b = DartMinHash::new(&mut key_b.rng("dmh"), k);
+        assert_eq!(a.sketch(&x), a2.sketch(&x));
+
+        // Without the key the sketch is unrelated to the keyed one.
+        let cross = jaccard_estimate_from_minhashes(&a.sketch(&x), &b.sketch(&x));
+        assert!(cross < 0.05, "{cross}");
+
+        let j = jaccard_similarity(&x, &y);
+        for dm in [&a, &b] {
+            let est = jaccard_estimate_from_minhashes(&dm.sketch(&x), &dm.sketch(&y));
+            assert_estimate_close(j, est, k, "keyed");
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! Reusable working memory for the `sketch_into` methods.
+//!
+//! Every sketcher needs some temporary buffers per call: DartMinHash its darts
+//! and filled flags, TreeMinHash its node buffer and permutation, ERS its
+//! weight lookup and buckets, and so on. The plain `sketch` methods allocate
+//! them on each call. `sketch_into(&x, &mut scratch, &mut out)` borrows them
+//! from a `Scratch` instead and writes the sketch into `out`, so a worker that
+//! keeps one `Scratch` and one output vector performs no heap allocations once
+//! the buffers have grown to the size of its inputs.
+//!
+//! One `Scratch` can be shared by all sketchers, but not by two threads; keep
+//! one per worker.
+
+use std::collections::BinaryHeap;
+
+use crate::bagminhash::{MaxValueTracker, PendingPoint};
+use crate::darthash::Dart;
+use crate::fastgm::FeatureArrivals;
+use crate::rejsmp::WeightScratch;
+use crate::treeminhash::PermutationStream;
+
+#[derive(Default)]
+pub struct Scratch {
+    // DartMinHash
+    pub(crate) darts: Vec<Dart>,
+    pub(crate) filled: Vec<bool>,
+    // TreeMinHash node buffer
+    pub(crate) nodes: Vec<(f64, u32)>,
+    // TreeMinHash, ProbMinHash and SuperMinHash
+    pub(crate) permutation: PermutationStream,
+    // BagMinHash, ProbMinHash and FastGM
+    pub(crate) tracker: MaxValueTracker,
+    pub(crate) pending: BinaryHeap<PendingPoint>,
+    pub(crate) features: Vec<FeatureArrivals>,
+    // SuperMinHash
+    pub(crate) levels: Vec<u32>,
+    pub(crate) ids: Vec<u64>,
+    // ErsWmh
+    pub(crate) weights: WeightScratch,
+    pub(crate) buckets: Vec<Option<(u64, u32)>>,
+    // GrowableErs
+    pub(crate) sorted: Vec<(u64, f64)>,
+    pub(crate) arrivals: Vec<(f64, u64)>,
+    // SimHash
+    pub(crate) projections: Vec<f64>,
+}
+
+impl Scratch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Reset `out` to `k` empty slots without giving up its allocation.
+#[inline]
+pub(crate) fn reset_sketch(out: &mut Vec<Dart>, k: usize) {
+    out.clear();
+    out.resize(k, (0, f64::INFINITY));
+}
@@ -0,0 +1,449 @@
+//! SetSketch-style compressed registers for weighted MinHash sketches.
+//!
+//! A `(fingerprint, rank)` slot costs 16 bytes. Following SetSketch (Ertl, VLDB
+//! 2021) and HyperMinHash (Yu & Weber), a register here keeps only a
+//! discretized rank
+//!
+//!   K = clamp(floor(1 - log_b(a * r)), 0, 2^q - 1)
+//!
+//! together with the lowest `f` bits of the fingerprint, packed into `q + f`
+//! bits. Empty registers (r = inf) store K = 0. A smaller rank gives a larger
+//! K, so the register-wise max of two compressed sketches is the compressed
+//! sketch of the union (the max of the weights).
+//!
+//! The compression works on any sketch whose register values are
+//! Exp(W/k)-distributed for a set of total weight W, up to a known factor
+//! `rank_scale`: 1 for `TreeMinHash` (and `BagMinHash`), `t` for
+//! `DartMinHash`/`DartHash` whose darts arrive at rate `t` per unit of weight.
+//!
+//! From a single sketch the total weight is estimated with the SetSketch
+//! estimator. For two sketches the weighted Jaccard similarity is estimated
+//! from the fraction of equal registers, corrected for the chance that two
+//! different samples share both K and the fingerprint bits. Union weight comes
+//! from the merged sketch, intersection weight is `J * union`.
+
+use std::io;
+
+use crate::dartminhash::{DartMinHash, MinHashSketch};
+use crate::persist::invalid;
+
+/// Parameters of the register discretization.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SetSketchParams {
+    /// Base b > 1 of the rank discretization; smaller is more accurate but
+    /// needs more rank bits for the same range.
+    pub base: f64,
+    /// Bits q of the discretized rank, 1..=16.
+    pub rank_bits: u32,
+    /// Bits f of the kept fingerprint, 0..=16.
+    pub fingerprint_bits: u32,
+    /// Smallest total weight the registers resolve without clipping.
+    pub min_weight: f64,
+}
+
+impl Default for SetSketchParams {
+    /// b = 2^(1/16) with 10 rank bits covers 64 binary orders of magnitude of
+    /// total weight above `min_weight`, 16 bits per register in total.
+    fn default() -> Self {
+        Self {
+            base: 2f64.powf(1.0 / 16.0),
+            rank_bits: 10,
+            fingerprint_bits: 6,
+            min_weight: 1.0e-6,
+        }
+    }
+}
+
+/// Turns `(fingerprint, rank)` sketches into compressed sketches.
+#[derive(Clone, Debug)]
+pub struct SetSketcher {
+    params: SetSketchParams,
+    k: usize,
+    rank_scale: f64,
+    // a = min_weight / k: a * rank_scale * r is Exp(W / (k a)), ~1 at min_weight
+    a: f64,
+    ln_b: f64,
+}
+
+impl SetSketcher {
+    /// Compressor for k-register sketches whose ranks, multiplied by
+    /// `rank_scale`, are Exp(W/k)-distributed.
+    pub fn new(params: SetSketchParams, k: usize, rank_scale: f64) -> Self {
+        assert!(k > 0, "k must be positive");
+        assert!(
+            params.base > 1.0 && params.base.is_finite(),
+            "base must be finite and > 1"
+        );
+        assert!(
+            (1..=16).contains(&params.rank_bits),
+            "rank_bits must be in 1..=16"
+        );
+        assert!(
+            params.fingerprint_bits <= 16,
+            "fingerprint_bits must be <= 16"
+        );
+        assert!(
+            params.min_weight > 0.0 && params.min_weight.is_finite(),
+            "min_weight must be finite and positive"
+        );
+        assert!(
+            rank_scale > 0.0 && rank_scale.is_finite(),
+            "rank_scale must be finite and positive"
+        );
+        Self {
+            params,
+            k,
+            rank_scale,
+            a: params.min_weight / (k as f64),
+            ln_b: params.base.ln(),
+        }
+    }
+
+    /// Compressor for `TreeMinHash` sketches with k registers.
+    pub fn for_treeminhash(params: SetSketchParams, k: usize) -> Self {
+        Self::new(params, k, 1.0)
+    }
+
+    /// Compressor for the sketches of `dmh`.
+    pub fn for_dartminhash(params: SetSketchParams, dmh: &DartMinHash) -> Self {
+        Self::new(params, dmh.k() as usize, dmh.t() as f64)
+    }
+
+    pub fn params(&self) -> SetSketchParams {
+        self.params
+    }
+
+    pub fn compress(&self, sketch: &MinHashSketch) -> CompressedSketch {
+        assert_eq!(sketch.len(), self.k, "sketch length must equal k");
+        let mut out = CompressedSketch::empty(self.params, self.k);
+        let max_rank = (1u64 << self.params.rank_bits) - 1;
+        let fp_mask = (1u64 << self.params.fingerprint_bits) - 1;
+        for (j, &(fingerprint, r)) in sketch.iter().enumerate() {
+            if !r.is_finite() {
+                continue;
+            }
+            let x = self.a * self.rank_scale * r;
+            let level = if x <= 0.0 {
+                max_rank
+            } else {
+                // Ranks above 1/a fall below level 1 and are dropped like empties.
+                (1.0 - x.ln() / self.ln_b)
+                    .floor()
+                    .clamp(0.0, max_rank as f64) as u64
+            };
+            if level > 0 {
+                out.set(
+                    j,
+                    (level << self.params.fingerprint_bits) | (fingerprint & fp_mask),
+                );
+            }
+        }
+        out
+    }
+}
+
+/// Bit-packed registers of `rank_bits + fingerprint_bits` bits each.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompressedSketch {
+    params: SetSketchParams,
+    k: usize,
+    words: Vec<u64>,
+}
+
+impl CompressedSketch {
+    fn empty(params: SetSketchParams, k: usize) -> Self {
+        let width = (params.rank_bits + params.fingerprint_bits) as usize;
+        Self {
+            params,
+            k,
+            words: vec![0u64; (k * width).div_ceil(64)],
+        }
+    }
+
+    #[inline]
+    fn width(&self) -> usize {
+        (self.params.rank_bits + self.params.fingerprint_bits) as usize
+    }
+
+    #[inline]
+    fn get(&self, j: usize) -> u64 {
+        let width = self.width();
+        let mask = (1u64 << width) - 1;
+        let bit = j * width;
+        let (w, o) = (bit / 64, bit % 64);
+        let mut v = self.words[w] >> o;
+        if o + width > 64 {
+            v |= self.words[w + 1] << (64 - o);
+        }
+        v & mask
+    }
+
+    #[inline]
+    fn set(&mut self, j: usize, value: u64) {
+        let width = self.width();
+        let mask = (1u64 << width) - 1;
+        let bit = j * width;
+        let (w, o) = (bit / 64, bit % 64);
+        self.words[w] = (self.words[w] & !(mask << o)) | (value << o);
+        if o + width > 64 {
+            let hi = width - (64 - o);
+            let hi_mask = (1u64 << hi) - 1;
+            self.words[w + 1] = (self.words[w + 1] & !hi_mask) | (value >> (64 - o));
+        }
+    }
+
+    /// Rebuild a sketch from the packed registers returned by `words`. The
+    /// parameters and `k` are not part of the words and must be stored by the
+    /// caller. Fails if they do not describe `words`.
+    pub fn from_words(params: SetSketchParams, k: usize, words: Vec<u64>) -> io::Result<Self> {
+        let valid = k > 0
+            && params.base > 1.0
+            && params.base.is_finite()
+            && (1..=16).contains(&params.rank_bits)
+            && params.fingerprint_bits <= 16
+            && params.min_weight > 0.0
+            && params.min_weight.is_finite();
+        if !valid {
+            return Err(invalid("invalid SetSketch parameters"));
+        }
+        let sketch = Self::empty(params, k);
+        if words.len() != sketch.words.len() {
+            return Err(invalid(format!(
+                "expected {} words for {k} registers, got {}",
+                sketch.words.len(),
+                words.len()
+            )));
+        }
+        let used = k * sketch.width() % 64;
+        if used > 0 && words[words.len() - 1] >> used != 0 {
+            return Err(invalid("bits set beyond the last register"));
+        }
+        Ok(Self { words, ..sketch })
+    }
+
+    /// The packed registers, `byte_size() / 8` words.
+    pub fn words(&self) -> &[u64] {
+        &self.words
+    }
+
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    pub fn params(&self) -> SetSketchParams {
+        self.params
+    }
+
+    /// Size of the packed registers in bytes.
+    pub fn byte_size(&self) -> usize {
+        self.words.len() * 8
+    }
+
+    /// Register j as `(discretized rank, fingerprint bits)`; rank 0 is empty.
+    pub fn register(&self, j: usize) -> (u32, u32) {
+        let v = self.get(j);
+        let f = self.params.fingerprint_bits;
+        ((v >> f) as u32, (v & ((1u64 << f) - 1)) as u32)
+    }
+
+    /// Register-wise max: afterwards `self` summarizes the union (max weights).
+    pub fn merge(&mut self, other: &CompressedSketch) {
+        assert_eq!(self.k, other.k, "sketches must have the same k");
+        assert_eq!(self.params, other.params, "sketches must share parameters");
+        for j in 0..self.k {
+            let v = other.get(j);
+            if v > self.get(j) {
+                self.set(j, v);
+            }
+        }
+    }
+
+    pub fn merged(&self, other: &CompressedSketch) -> CompressedSketch {
+        let mut out = self.clone();
+        out.merge(other);
+        out
+    }
+
+    /// Estimate the total weight W of the sketched set.
+    pub fn weight_estimate(&self) -> f64 {
+        let b = self.params.base;
+        let f = self.params.fingerprint_bits;
+        if self.words.iter().all(|&w| w == 0) {
+            return 0.0;
+        }
+        let sum: f64 = (0..self.k)
+            .map(|j| b.powi(-((self.get(j) >> f) as i32)))
+            .sum();
+        let k = self.k as f64;
+        // The registers are SetSketch registers of Exp(W / (k a)) values.
+        let a = self.params.min_weight / k;
+        k * a * k * (1.0 - 1.0 / b) / (b.ln() * sum)
+    }
+
+    /// Estimate the weighted Jaccard similarity with `other`.
+    pub fn jaccard_estimate(&self, other: &CompressedSketch) -> f64 {
+        assert_eq!(self.k, other.k, "sketches must have the same k");
+        assert_eq!(self.params, other.params, "sketches must share parameters");
+        let f = self.params.fingerprint_bits;
+        let equal = (0..self.k)
+            .filter(|&j| {
+                let v = self.get(j);
+                (v >> f) > 0 && v == other.get(j)
+            })
+            .count();
+
+        // Probability that two different samples land on the same rank level
+        // (~ ln(b) w1 w2 / (w1 + w2)^2) and share the fingerprint bits.
+        let w1 = self.weight_estimate();
+        let w2 = other.weight_estimate();
+        let eps = if w1 > 0.0 && w2 > 0.0 {
+            self.params.base.ln() * w1 * w2 / ((w1 + w2) * (w1 + w2)) / (1u64 << f) as f64
+        } else {
+            0.0
+        };
+        let c = equal as f64 / self.k as f64;
+        ((c - eps) / (1.0 - eps)).clamp(0.0, 1.0)
+    }
+
+    /// Estimate the weight of the union (sum of max weights).
+    pub fn union_weight_estimate(&self, other: &CompressedSketch) -> f64 {
+        self.merged(other).weight_estimate()
+    }
+
+    /// Estimate the weight of the intersection (sum of min weights).
+    pub fn intersection_weight_estimate(&self, other: &CompressedSketch) -> f64 {
+        self.jaccard_estimate(other) * self.union_weight_estimate(other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{
+        dartminhash::DartMinHash,
+        rng_utils::mt_from_seed,
+        setsketch::{CompressedSketch, SetSketchParams, SetSketcher},
+        similarity::{intersection, jaccard_similarity, weight},
+        test_utils::{assert_estimate_close, generate_similar_weighted_set, generate_weighted_set},
+        treeminhash::TreeMinHash,
+    };
+
+    fn union_weight(x: &[(u64, f64)], y: &[(u64, f64)]) -> f64 {
+        weight(x) + weight(y) - intersection(x, y)
+    }
+
+    #[test]
+    fn setsketch_estimates_from_treeminhash() {
+        let mut data_rng = mt_from_seed(1337);
+        let mut hash_rng = mt_from_seed(0x5e75_0001);
+
+        let l0 = 5_000;
+        let l1 = 1_000.0;
+        let k = 4096;
+
+        let tmh = TreeMinHash::new_mt(&mut hash_rng, k);
+        let setsketch = SetSketcher::for_treeminhash(SetSketchParams::default(), k as usize);
+        let x = generate_weighted_set(l0, l1, &mut data_rng);
+        let cx = setsketch.compress(&tmh.sketch(&x));
+        // 16 bits per register instead of 128
+        assert_eq!(cx.byte_size(), 2 * k as usize);
+
+        let w_err = (cx.weight_estimate() / weight(&x) - 1.0).abs();
+        assert!(w_err <= 0.06, "weight: rel err {w_err:.4}");
+
+        for &alpha in &[0.9, 0.5, 0.1] {
+            let y = generate_similar_weighted_set(&x, alpha, &mut data_rng);
+            let cy = setsketch.compress(&tmh.sketch(&y));
+
+            let j_true = jaccard_similarity(&x, &y);
+            let j_est = cx.jaccard_estimate(&cy);
+            assert_estimate_close(j_true, j_est, k, format!("alpha={alpha}"));
+
+            let u_true = union_weight(&x, &y);
+            let u_err = (cx.union_weight_estimate(&cy) / u_true - 1.0).abs();
+            assert!(u_err <= 0.06, "alpha={alpha}: union rel err {u_err:.4}");
+
+            let i_true = intersection(&x, &y);
+            let i_err = (cx.intersection_weight_estimate(&cy) - i_true).abs() / u_true;
+            assert!(i_err <= 0.06, "alpha={alpha}: intersection err {i_err:.4}");
+        }
+    }
+
+    #[test]
+    fn setsketch_estimates_from_dartminhash() {
+        let mut data_rng = mt_from_seed(42);
+        let mut hash_rng = mt_from_seed(0x5e75_0002);
+
+        let k = 1024;
+        let dmh = DartMinHash::new_mt(&mut hash_rng, k);
+        let setsketch = SetSketcher::for_dartminhash(SetSketchParams::default(), &dmh);
+        let x = generate_weighted_set(2_000, 50.0, &mut data_rng);
+        let y = generate_similar_weighted_set(&x, 0.6, &mut data_rng);
+        let cx = setsketch.compress(&dmh.sketch(&x));
+        let cy = setsketch.compress(&dmh.sketch(&y));
+
+        let w_err = (cx.weight_estimate() / weight(&x) - 1.0).abs();
+        assert!(w_err <= 0.12, "weight: rel err {w_err:.4}");
+
+        let j_true = jaccard_similarity(&x, &y);
+        assert_estimate_close(j_true, cx.jaccard_estimate(&cy), k, "DartMinHash");
+    }
+
+    #[test]
+    fn setsketch_merge_is_register_max() {
+        let mut data_rng = mt_from_seed(7);
+        let mut hash_rng = mt_from_seed(0x5e75_0003);
+
+        let k = 512;
+        let tmh = TreeMinHash::new_mt(&mut hash_rng, k);
+        let params = SetSketchParams {
+            rank_bits: 12,
+            fingerprint_bits: 9,
+            ..SetSketchParams::default()
+        };
+        let setsketch = SetSketcher::for_treeminhash(params, k as usize);
+        let x = generate_weighted_set(500, 10.0, &mut data_rng);
+        let y = generate_weighted_set(500, 3.0, &mut data_rng);
+        let cx = setsketch.compress(&tmh.sketch(&x));
+        let cy = setsketch.compress(&tmh.sketch(&y));
+
+        // Disjoint supports: the sketch of the union is the register max.
+        let mut xy: Vec<(u64, f64)> = x.iter().chain(y.iter()).copied().collect();
+        xy.sort_by_key(|&(i, _)| i);
+        let cxy = setsketch.compress(&tmh.sketch(&xy));
+        let merged = cx.merged(&cy);
+        for j in 0..k as usize {
+            let (rx, ry) = (cx.register(j), cy.register(j));
+            assert_eq!(merged.register(j), rx.max(ry));
+            // The union's rank level is always the max; its fingerprint is
+            // only ambiguous when both land on the same level.
+            assert_eq!(cxy.register(j).0, rx.0.max(ry.0));
+            if rx.0 != ry.0 {
+                assert_eq!(cxy.register(j), merged.register(j));
+            }
+        }
+        assert!(cx.jaccard_estimate(&cy) < 0.02);
+
+        // The packed words round-trip; wrong lengths and stray bits do not.
+        let words = merged.words().to_vec();
+        assert_eq!(words.len() * 8, merged.byte_size());
+        let restored = CompressedSketch::from_words(params, k as usize, words.clone()).unwrap();
+        assert_eq!(restored, merged);
+        assert!(CompressedSketch::from_words(params, k as usize + 7, words.clone()).is_err());
+        assert!(CompressedSketch::from_words(params, k as usize, words[1..].to_vec()).is_err());
+        let odd = SetSketcher::for_treeminhash(params, 3).compress(&tmh.sketch(&x)[..3].to_vec());
+        let mut stray = odd.words().to_vec();
+        stray[0] |= 1 << 63;
+        assert!(CompressedSketch::from_words(params, 3, stray).is_err());
+        let bad = SetSketchParams {
+            rank_bits: 0,
+            ..params
+        };
+        assert!(CompressedSketch::from_words(bad, k as usize, merged.words().to_vec()).is_err());
+
+        // Empty registers stay empty and estimate zero weight.
+        let empty = setsketch.compress(&tmh.sketch(&[]));
+        assert_eq!(empty.weight_estimate(), 0.0);
+        assert_eq!(cx.merged(&empty), cx);
+    }
+}
@@ -0,0 +1,222 @@
+//! SimHash (sign random projections) for cosine similarity.
+//!
+//! Bit `b` of the sketch is the sign of `<x, r_b>` for a random projection
+//! vector `r_b`. For Gaussian projections two sketches differ in a bit with
+//! probability θ/π, where θ is the angle between the inputs (Charikar 2002),
+//! so the cosine similarity is estimated as cos(π H / n) from the Hamming
+//! distance H of n bits, see [`crate::similarity::simhash_cosine_estimate`].
+//! Rademacher (±1) projections give the same estimate up to a small bias on
+//! very sparse inputs and are cheaper to generate.
+//!
+//! Projection entries are derived from the tabulation streams of
+//! [`crate::treeminhash`] keyed by feature id, so the same sparse `(u64, f64)`
+//! vectors used for weighted Jaccard can be sketched without a dense matrix.
+//! The work is O(nnz * n_bits).
+
+use rand_core::RngCore;
+
+use crate::hash_utils::*;
+use crate::rng_utils::mt_from_seed_full;
+use crate::scratch::Scratch;
+use crate::treeminhash::TabStream;
+
+/// Packed sign bits, bit `b` in word `b / 64` at position `b % 64`.
+pub type SimHashSketch = Vec<u64>;
+
+/// Distribution of the projection entries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Projection {
+    /// Uniform ±1 entries.
+    Rademacher,
+    /// Standard normal entries (Box-Muller).
+    Gaussian,
+}
+
+/// SimHash sketcher for cosine similarity.
+pub struct SimHash<F: HashFamily = DefaultFamily> {
+    n_bits: u32,
+    family: F,
+    projection: Projection,
+    h0: F::H64,
+    h1: F::H64,
+}
+
+impl SimHash {
+    /// Sketcher with `n_bits` Rademacher projections.
+    pub fn new<R: RngCore + ?Sized>(rng: &mut R, n_bits: u64) -> Self {
+        Self::with_projection(rng, n_bits, Projection::Rademacher)
+    }
+
+    /// Like `new`; the same seed gives the same hyperplanes.
+    pub fn from_seed(seed: u64, n_bits: u64) -> Self {
+        Self::new(&mut mt_from_seed_full(seed), n_bits)
+    }
+
+    pub fn with_projection<R: RngCore + ?Sized>(
+        rng: &mut R,
+        n_bits: u64,
+        projection: Projection,
+    ) -> Self {
+        Self::with_projection_and_family(rng, n_bits, projection, DefaultFamily::default())
+    }
+}
+
+impl<F: HashFamily> SimHash<F> {
+    /// Like `with_projection`, drawing the hash functions from `family`.
+    pub fn with_projection_and_family<R: RngCore + ?Sized>(
+        rng: &mut R,
+        n_bits: u64,
+        projection: Projection,
+        family: F,
+    ) -> Self {
+        assert!(
+            n_bits > 0 && n_bits <= (u32::MAX as u64),
+            "n_bits must fit into u32"
+        );
+        let h0 = family.hash64_from_rng(rng);
+        let h1 = family.hash64_from_rng(rng);
+        Self {
+            n_bits: n_bits as u32,
+            family,
+            projection,
+            h0,
+            h1,
+        }
+    }
+
+    #[inline]
+    pub fn hash_family(&self) -> HashKind {
+        self.family.kind()
+    }
+
+    #[inline]
+    pub fn n_bits(&self) -> usize {
+        self.n_bits as usize
+    }
+
+    /// Return the packed signs of `n_bits` random projections of x.
+    pub fn sketch(&self, x: &[(u64, f64)]) -> SimHashSketch {
+        let mut bits = Vec::with_capacity((self.n_bits as usize).div_ceil(64));
+        self.sketch_into(x, &mut Scratch::new(), &mut bits);
+        bits
+    }
+
+    /// Like `sketch`, writing into `bits` and reusing the projection
+    /// accumulators of `scratch`.
+    pub fn sketch_into(&self, x: &[(u64, f64)], scratch: &mut Scratch, bits: &mut SimHashSketch) {
+        let n = self.n_bits as usize;
+        let acc = &mut scratch.projections;
+        acc.clear();
+        acc.resize(n, 0.0);
+
+        for &(id, w) in x {
+            if w == 0.0 || !w.is_finite() {
+                continue;
+            }
+            let mut rng = TabStream::new(&self.h0, &self.h1, id, 0);
+            match self.projection {
+                Projection::Rademacher => {
+                    for chunk in acc.chunks_mut(64) {
+                        let signs = rng.next_u64();
+                        for (b, a) in chunk.iter_mut().enumerate() {
+                            if (signs >> b) & 1 == 1 {
+                                *a += w;
+                            } else {
+                                *a -= w;
+                            }
+                        }
+                    }
+                }
+                Projection::Gaussian => {
+                    for pair in acc.chunks_mut(2) {
+                        // Box-Muller: two independent normals per two uniforms.
+                        let radius = (-2.0 * rng.uniform_open01().ln()).sqrt();
+                        let angle = 2.0 * std::f64::consts::PI * rng.uniform_open01();
+                        pair[0] += w * radius * angle.cos();
+                        if let Some(a) = pair.get_mut(1) {
+                            *a += w * radius * angle.sin();
+                        }
+                    }
+                }
+            }
+        }
+
+        bits.clear();
+        bits.resize(n.div_ceil(64), 0);
+        for (b, &a) in acc.iter().enumerate() {
+            if a > 0.0 {
+                bits[b / 64] |= 1u64 << (b % 64);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{
+        rng_utils::mt_from_seed,
+        simhash::{Projection, SimHash},
+        similarity::{cosine_similarity, packed_hamming_distance, simhash_cosine_estimate},
+        test_utils::{
+            assert_estimate_close, generate_perturbed_distribution, generate_weighted_set,
+        },
+    };
+
+    fn check_simhash_accuracy(projection: Projection, seed: u64) {
+        let mut data_rng = mt_from_seed(1337);
+        let mut hash_rng = mt_from_seed(seed);
+
+        let n_bits = 2048;
+        let sh = SimHash::with_projection(&mut hash_rng, n_bits, projection);
+        let x = generate_weighted_set(2_000, 1.0, &mut data_rng);
+        let sk_x = sh.sketch(&x);
+        assert_eq!(sk_x.len(), 32);
+
+        let cases = [(0.0, 0.0), (0.5, 0.1), (1.0, 0.3), (1.0, 0.6), (1.0, 0.9)];
+        for &(spread, drop) in &cases {
+            let y = generate_perturbed_distribution(&x, spread, drop, &mut data_rng);
+            let sk_y = sh.sketch(&y);
+            let cos_true = cosine_similarity(&x, &y);
+            let cos_est = simhash_cosine_estimate(&sk_x, &sk_y, n_bits as usize);
+
+            // Compare the bit disagreement rate against θ/π.
+            let p_true = cos_true.clamp(-1.0, 1.0).acos() / std::f64::consts::PI;
+            let p_est = packed_hamming_distance(&sk_x, &sk_y) as f64 / n_bits as f64;
+            assert_estimate_close(
+                p_true,
+                p_est,
+                n_bits,
+                format!(
+                    "{projection:?}: spread={spread}, drop={drop}, cos={cos_true:.6}, est={cos_est:.6}"
+                ),
+            );
+        }
+    }
+
+    #[test]
+    fn simhash_rademacher_approximates_cosine() {
+        check_simhash_accuracy(Projection::Rademacher, 0x51ba_0001);
+    }
+
+    #[test]
+    fn simhash_gaussian_approximates_cosine() {
+        check_simhash_accuracy(Projection::Gaussian, 0x51ba_0002);
+    }
+
+    #[test]
+    fn simhash_scale_and_sign() {
+        let mut rng = mt_from_seed(3);
+        let sh = SimHash::new(&mut rng, 100);
+        let x = vec![(1, 0.25), (3, 0.75), (10, 1.5), (42, 0.125)];
+        let x_scaled: Vec<(u64, f64)> = x.iter().map(|&(id, w)| (id, 3.0 * w)).collect();
+        let x_neg: Vec<(u64, f64)> = x.iter().map(|&(id, w)| (id, -w)).collect();
+
+        let sk = sh.sketch(&x);
+        assert_eq!(sk.len(), 2);
+        assert_eq!(sk, sh.sketch(&x_scaled));
+        // Negating the vector flips every bit (no projection is exactly 0 here).
+        assert_eq!(packed_hamming_distance(&sk, &sh.sketch(&x_neg)), 100);
+        assert_eq!(sh.sketch(&[]), vec![0, 0]);
+    }
+}
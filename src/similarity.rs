@@ -45,6 +45,70 @@ pub fn l1_similarity(x: &[(u64, f64)], y: &[(u64, f64)]) -> f64 {
     s / wx.min(wy)
 }
 
+// Probability Jaccard:  J_P = Σ_{i: x_i>0, y_i>0} 1 / Σ_j max(x_j/x_i, y_j/y_i).
+// Scale invariant in each argument, so it compares normalized distributions
+// (e.g. relative abundances). Requires both x and y sorted by id.
+// Cost is O(|x ∩ y| * |x ∪ y|).
+pub fn probability_jaccard(x: &[(u64, f64)], y: &[(u64, f64)]) -> f64 {
+    // Merge into the union (x_j, y_j), zero where absent.
+    let (mut i, mut j) = (0usize, 0usize);
+    let mut union = Vec::with_capacity(x.len() + y.len());
+    while i < x.len() || j < y.len() {
+        let ord = match (x.get(i), y.get(j)) {
+            (Some(a), Some(b)) => a.0.cmp(&b.0),
+            (Some(_), None) => Ordering::Less,
+            _ => Ordering::Greater,
+        };
+        match ord {
+            Ordering::Equal => {
+                union.push((x[i].1.max(0.0), y[j].1.max(0.0)));
+                i += 1;
+                j += 1;
+            }
+            Ordering::Less => {
+                union.push((x[i].1.max(0.0), 0.0));
+                i += 1;
+            }
+            Ordering::Greater => {
+                union.push((0.0, y[j].1.max(0.0)));
+                j += 1;
+            }
+        }
+    }
+
+    let mut s = 0.0;
+    for &(xi, yi) in &union {
+        if xi > 0.0 && yi > 0.0 {
+            let denom: f64 = union.iter().map(|&(xj, yj)| (xj / xi).max(yj / yi)).sum();
+            s += 1.0 / denom;
+        }
+    }
+    s
+}
+
+// Cosine similarity:  <x, y> / (|x|_2 |y|_2). Requires both x and y sorted by
+// id. Returns 0 if either vector is zero.
+pub fn cosine_similarity(x: &[(u64, f64)], y: &[(u64, f64)]) -> f64 {
+    let (mut i, mut j, mut dot) = (0usize, 0usize, 0.0);
+    while i < x.len() && j < y.len() {
+        match x[i].0.cmp(&y[j].0) {
+            Ordering::Equal => {
+                dot += x[i].1 * y[j].1;
+                i += 1;
+                j += 1;
+            }
+            Ordering::Less => i += 1,
+            Ordering::Greater => j += 1,
+        }
+    }
+    let nx = x.iter().map(|&(_, w)| w * w).sum::<f64>().sqrt();
+    let ny = y.iter().map(|&(_, w)| w * w).sum::<f64>().sqrt();
+    if nx == 0.0 || ny == 0.0 {
+        return 0.0;
+    }
+    dot / (nx * ny)
+}
+
 /// Hamming distance between two 1-bit sketches.
 #[inline]
 pub fn hamming_distance(x: &[bool], y: &[bool]) -> f64 {
@@ -67,6 +131,24 @@ pub fn onebit_minhash_jaccard_estimate(x: &[bool], y: &[bool]) -> f64 {
     (2.0 * (1.0 - h / t) - 1.0).max(0.0)
 }
 
+/// Hamming distance between two bit-packed sketches.
+#[inline]
+pub fn packed_hamming_distance(x: &[u64], y: &[u64]) -> u64 {
+    assert_eq!(x.len(), y.len(), "bit vectors must be same length");
+    x.iter()
+        .zip(y.iter())
+        .map(|(a, b)| (a ^ b).count_ones() as u64)
+        .sum()
+}
+
+// SimHash cosine estimate: cos(π H / n), where H is the Hamming distance of
+// the packed sign bits and n the number of bits (unused tail bits must be 0).
+#[inline]
+pub fn simhash_cosine_estimate(x: &[u64], y: &[u64], n_bits: usize) -> f64 {
+    let h = packed_hamming_distance(x, y) as f64;
+    (std::f64::consts::PI * h / n_bits as f64).cos()
+}
+
 // Convert L1 similarity → Jaccard similarity.
 #[inline]
 pub fn jaccard_from_l1(x_weight: f64, y_weight: f64, l1_sim: f64) -> f64 {
@@ -126,7 +208,39 @@ mod tests {
         assert_eq!(h, 2.0);
         let est = onebit_minhash_jaccard_estimate(&x, &y);
         // Just sanity: within [0,1]
-        assert!(est >= 0.0 && est <= 1.0);
+        assert!((0.0..=1.0).contains(&est));
+    }
+
+    #[test]
+    fn test_probability_jaccard() {
+        let a = vec![(1, 1.0), (2, 3.0)];
+        let b = vec![(1, 2.0), (2, 6.0)];
+        // Same distribution up to scale.
+        assert!((probability_jaccard(&a, &b) - 1.0).abs() < 1e-12);
+        assert_eq!(probability_jaccard(&a, &[(3, 1.0)]), 0.0);
+
+        // x = (1/2, 1/2), y = (1/4, 3/4):
+        // i=1: 1 / (1 + max(1, 3)) = 1/4, i=2: 1 / (max(1, 1/3) + 1) = 1/2.
+        let x = vec![(1, 0.5), (2, 0.5)];
+        let y = vec![(1, 0.25), (2, 0.75)];
+        assert!((probability_jaccard(&x, &y) - 0.75).abs() < 1e-12);
+        assert!((probability_jaccard(&x, &y) - probability_jaccard(&y, &x)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        let a = vec![(1, 1.0), (2, 2.0), (5, 2.0)];
+        let b = vec![(2, 3.0), (5, 4.0), (9, 12.0)];
+        // <a, b> = 6 + 8 = 14, |a| = 3, |b| = 13
+        assert!((cosine_similarity(&a, &b) - 14.0 / 39.0).abs() < 1e-12);
+        assert!((cosine_similarity(&a, &a) - 1.0).abs() < 1e-12);
+        assert_eq!(cosine_similarity(&a, &[]), 0.0);
+
+        let x = [0b1011u64, u64::MAX];
+        let y = [0b0001u64, 0];
+        assert_eq!(packed_hamming_distance(&x, &y), 66);
+        assert!((simhash_cosine_estimate(&x, &x, 128) - 1.0).abs() < 1e-12);
+        assert!((simhash_cosine_estimate(&[0, 0], &[u64::MAX, u64::MAX], 128) + 1.0).abs() < 1e-12);
     }
 
     #[test]
@@ -138,4 +252,4 @@ mod tests {
         let j_back = jaccard_from_l1(wx, wy, l1);
         assert!((j - j_back).abs() < 1e-12);
     }
-}
\ No newline at end of file
+}
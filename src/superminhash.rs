@@ -0,0 +1,238 @@
+//! SuperMinHash for plain (unweighted) sets.
+//!
+//! Port of Otmar Ertl's SuperMinHash (arXiv:1706.05698). For sets, where every
+//! weight is 1, the weighted machinery of `DartHash` or `TreeMinHash` is not
+//! needed: element `d` contributes the values `j + r_j` for `j = 0, 1, ...` to
+//! the registers given by a random permutation, and generation stops once `j`
+//! exceeds the largest register that can still change. Two sketches collide
+//! with probability equal to the unweighted Jaccard similarity, with lower
+//! variance than plain MinHash for small sets. Expected work is O(n + k log^2 k).
+//!
+//! Sketches use the same `(fingerprint, value)` layout as DartMinHash, with the
+//! fingerprint a hash of the element, so `jaccard_estimate_from_minhashes` and
+//! the one-bit estimators apply unchanged to two SuperMinHash sketches. They do
+//! not apply across sketchers, whatever the fingerprints: two slots agree with
+//! probability J only because both inputs ran the same random process for that
+//! slot, and DartMinHash fills its slots with darts over the weight area, not
+//! with this permutation scheme. A SuperMinHash slot and a DartMinHash slot hold
+//! the same element only by chance. To compare a set with DartMinHash sketches,
+//! sketch it with DartMinHash as a 0/1 vector instead.
+
+use rand_core::RngCore;
+
+use crate::dartminhash::MinHashSketch;
+use crate::hash_utils::*;
+use crate::rng_utils::mt_from_seed_full;
+use crate::scratch::{Scratch, reset_sketch};
+use crate::treeminhash::TabStream;
+
+/// SuperMinHash sketcher for unweighted Jaccard similarity.
+pub struct SuperMinHash<F: HashFamily = DefaultFamily> {
+    k: u32,
+    family: F,
+    h0: F::H64,
+    h1: F::H64,
+    id_hasher: F::H64,
+}
+
+impl SuperMinHash {
+    pub fn new<R: RngCore + ?Sized>(rng: &mut R, k: u64) -> Self {
+        Self::with_family(rng, k, DefaultFamily::default())
+    }
+
+    /// Like `new`, seeded with `mt_from_seed_full(seed)`.
+    pub fn from_seed(seed: u64, k: u64) -> Self {
+        Self::new(&mut mt_from_seed_full(seed), k)
+    }
+}
+
+impl<F: HashFamily> SuperMinHash<F> {
+    /// Like `new`, drawing the hash functions from `family`.
+    pub fn with_family<R: RngCore + ?Sized>(rng: &mut R, k: u64, family: F) -> Self {
+        assert!(k > 0 && k <= (u32::MAX as u64), "k must fit into u32");
+        let h0 = family.hash64_from_rng(rng);
+        let h1 = family.hash64_from_rng(rng);
+        let id_hasher = family.hash64_from_rng(rng);
+        Self {
+            k: k as u32,
+            family,
+            h0,
+            h1,
+            id_hasher,
+        }
+    }
+
+    #[inline]
+    pub fn hash_family(&self) -> HashKind {
+        self.family.kind()
+    }
+
+    /// Return k slots of `(fingerprint(id), value)` for the set `ids`.
+    ///
+    /// Duplicate ids do not change the sketch.
+    pub fn sketch(&self, ids: &[u64]) -> MinHashSketch {
+        let mut result = Vec::with_capacity(self.k as usize);
+        self.sketch_into(ids, &mut Scratch::new(), &mut result);
+        result
+    }
+
+    /// Like `sketch`, writing into `result` and reusing the buffers of
+    /// `scratch`, see [`crate::scratch`].
+    pub fn sketch_into(&self, ids: &[u64], scratch: &mut Scratch, result: &mut MinHashSketch) {
+        let k = self.k as usize;
+        reset_sketch(result, k);
+        let permutation_stream = &mut scratch.permutation;
+        permutation_stream.reset_to(self.k);
+        // levels[l] = number of registers with floor(value) == l (l = k - 1 for
+        // empty registers); `a` is the largest level still occupied.
+        let levels = &mut scratch.levels;
+        levels.clear();
+        levels.resize(k, 0);
+        levels[k - 1] = self.k;
+        let mut a = k - 1;
+
+        for &id in ids {
+            let fingerprint = self.id_hasher.hash(id);
+            let mut rng = TabStream::new(&self.h0, &self.h1, id, 0);
+            permutation_stream.reset();
+            let mut j = 0usize;
+            while j <= a {
+                let r = rng.uniform_open01();
+                let idx = permutation_stream.next(&mut rng) as usize;
+                let value = j as f64 + r;
+                if value < result[idx].1 {
+                    let old_level = (result[idx].1.floor() as usize).min(k - 1);
+                    result[idx] = (fingerprint, value);
+                    if j < old_level {
+                        levels[old_level] -= 1;
+                        levels[j] += 1;
+                        while levels[a] == 0 {
+                            a -= 1;
+                        }
+                    }
+                }
+                j += 1;
+            }
+        }
+    }
+
+    /// Sketch the support of a weighted vector, i.e. its 0/1 version.
+    pub fn sketch_support(&self, x: &[(u64, f64)]) -> MinHashSketch {
+        let mut result = Vec::with_capacity(self.k as usize);
+        self.sketch_support_into(x, &mut Scratch::new(), &mut result);
+        result
+    }
+
+    /// Like `sketch_support`, writing into `result`.
+    pub fn sketch_support_into(
+        &self,
+        x: &[(u64, f64)],
+        scratch: &mut Scratch,
+        result: &mut MinHashSketch,
+    ) {
+        let mut ids = std::mem::take(&mut scratch.ids);
+        ids.clear();
+        ids.extend(x.iter().filter(|&&(_, w)| w > 0.0).map(|&(id, _)| id));
+        self.sketch_into(&ids, scratch, result);
+        scratch.ids = ids;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand_core::RngCore;
+
+    use crate::{
+        dartminhash::DartMinHash,
+        rng_utils::mt_from_seed,
+        similarity::{jaccard_estimate_from_minhashes, jaccard_similarity},
+        superminhash::SuperMinHash,
+        test_utils::{
+            assert_estimate_close, generate_perturbed_distribution, generate_weighted_set,
+        },
+    };
+
+    #[test]
+    fn superminhash_approximates_jaccard() {
+        let mut data_rng = mt_from_seed(1337);
+        let mut hash_rng = mt_from_seed(0x5a9e_0001);
+
+        let k = 1024;
+        let smh = SuperMinHash::new(&mut hash_rng, k);
+
+        // |x| = n, |y| = n, |x ∩ y| = s, so J = s / (2n - s).
+        let n = 20_000u64;
+        for &s in &[20_000u64, 15_000, 5_000, 1_000, 0] {
+            let shared: Vec<u64> = (0..s).map(|_| data_rng.next_u64()).collect();
+            let mut x = shared.clone();
+            let mut y = shared;
+            x.extend((s..n).map(|_| data_rng.next_u64()));
+            y.extend((s..n).map(|_| data_rng.next_u64()));
+
+            let j_true = s as f64 / (2 * n - s) as f64;
+            let j_est = jaccard_estimate_from_minhashes(&smh.sketch(&x), &smh.sketch(&y));
+            assert_estimate_close(j_true, j_est, k, format!("SuperMinHash: s={s}"));
+        }
+    }
+
+    #[test]
+    fn superminhash_support_and_order_invariance() {
+        let mut hash_rng = mt_from_seed(0x5a9e_0002);
+        let smh = SuperMinHash::new(&mut hash_rng, 256);
+
+        let ids = vec![3u64, 17, 42, 1_000_000, 7];
+        let sk = smh.sketch(&ids);
+        assert!(sk.iter().all(|&(_, v)| v.is_finite() && v < 256.0));
+
+        let mut reordered = ids.clone();
+        reordered.reverse();
+        reordered.push(42);
+        assert_eq!(sk, smh.sketch(&reordered));
+
+        let x: Vec<(u64, f64)> = ids.iter().map(|&id| (id, 1.0)).chain([(99, 0.0)]).collect();
+        assert_eq!(sk, smh.sketch_support(&x));
+
+        let empty = smh.sketch(&[]);
+        assert!(empty.iter().all(|&(id, v)| id == 0 && v.is_infinite()));
+    }
+    #[test]
+    fn superminhash_compares_only_with_itself() {
+        let mut data_rng = mt_from_seed(31);
+        let k = 1024;
+        let smh = SuperMinHash::new(&mut mt_from_seed(0x5a9e_0003), k);
+        let dmh = DartMinHash::new(&mut mt_from_seed(0x5a9e_0004), k);
+
+        let x = generate_weighted_set(2_000, 1.0, &mut data_rng);
+        let y = generate_perturbed_distribution(&x, 0.5, 0.5, &mut data_rng);
+        let support = |v: &[(u64, f64)]| -> Vec<(u64, f64)> {
+            v.iter()
+                .filter(|&&(_, w)| w > 0.0)
+                .map(|&(id, _)| (id, 1.0))
+                .collect()
+        };
+        let (x01, y01) = (support(&x), support(&y));
+        let j_sets = jaccard_similarity(&x01, &y01);
+        assert!(j_sets > 0.3 && j_sets < 0.7, "{j_sets}");
+
+        // Within one sketcher: SuperMinHash of the supports, or DartMinHash of
+        // the 0/1 vectors, both estimate the unweighted Jaccard.
+        let smh_x = smh.sketch_support(&x);
+        let smh_y = smh.sketch_support(&y);
+        let j_smh = jaccard_estimate_from_minhashes(&smh_x, &smh_y);
+        assert_estimate_close(j_sets, j_smh, k, "SuperMinHash");
+        let dmh_x = dmh.sketch(&x01);
+        let dmh_y = dmh.sketch(&y01);
+        let j_dmh = jaccard_estimate_from_minhashes(&dmh_x, &dmh_y);
+        assert_estimate_close(j_sets, j_dmh, k, "DartMinHash on 0/1 vectors");
+
+        // DartMinHash of the weighted vectors estimates the weighted Jaccard.
+        let j_weighted = jaccard_similarity(&x, &y);
+        let j_est = jaccard_estimate_from_minhashes(&dmh.sketch(&x), &dmh.sketch(&y));
+        assert_estimate_close(j_weighted, j_est, k, "DartMinHash");
+
+        // Across the sketchers the slots agree only by chance, even for the
+        // same set.
+        let cross = jaccard_estimate_from_minhashes(&smh_x, &dmh_x);
+        assert!(cross < 0.05, "cross-sketcher estimate {cross}");
+    }
+}
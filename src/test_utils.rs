@@ -0,0 +1,143 @@
+//! Random weighted sets shared by the unit tests of the sketchers.
+//!
+//! A set has L0 distinct random ids with weights given by the gaps of L0-1
+//! sorted uniforms times L1, so its total weight is L1. The similar set of x
+//! scales every weight of x by `relative_overlap` and moves the removed weight
+//! to one new id, which gives a weighted Jaccard of `rel / (2 - rel)`.
+//!
+//! An estimate of a probability p from k samples passes when it is within 3.2
+//! standard deviations of p, or within `1.25 / sqrt(k)` so that p near 0 or 1
+//! keeps some slack.
+
+use std::collections::HashSet;
+use std::fmt::Display;
+
+use rand_core::RngCore;
+
+use crate::rng_utils::MtRng;
+
+/// Uniform(0,1) from the MT19937 rng.
+pub(crate) fn uniform01(rng: &mut MtRng) -> f64 {
+    mt19937::gen_res53(rng)
+}
+
+/// Random weighted set over arbitrary u64 ids, sorted by id.
+pub(crate) fn generate_weighted_set(l0: u64, l1: f64, rng: &mut MtRng) -> Vec<(u64, f64)> {
+    weighted_set(l0, l1, rng, |rng| rng.next_u64())
+}
+
+/// Random weighted set with ids in [0, d), sorted by id.
+pub(crate) fn generate_weighted_set_in(
+    d: u64,
+    l0: u64,
+    l1: f64,
+    rng: &mut MtRng,
+) -> Vec<(u64, f64)> {
+    weighted_set(l0, l1, rng, |rng| rng.next_u64() % d)
+}
+
+/// y = relative_overlap * x plus the removed weight on a new u64 id.
+pub(crate) fn generate_similar_weighted_set(
+    x: &[(u64, f64)],
+    relative_overlap: f64,
+    rng: &mut MtRng,
+) -> Vec<(u64, f64)> {
+    similar_set(x, relative_overlap, rng, |rng| rng.next_u64())
+}
+
+/// Like `generate_similar_weighted_set`, with the new id drawn from [0, d).
+pub(crate) fn generate_similar_weighted_set_in(
+    d: u64,
+    x: &[(u64, f64)],
+    relative_overlap: f64,
+    rng: &mut MtRng,
+) -> Vec<(u64, f64)> {
+    similar_set(x, relative_overlap, rng, |rng| rng.next_u64() % d)
+}
+
+/// Multiply every weight of x by a random factor in [1 - spread, 1 + spread]
+/// and drop each feature with probability `drop`.
+pub(crate) fn generate_perturbed_distribution(
+    x: &[(u64, f64)],
+    spread: f64,
+    drop: f64,
+    rng: &mut MtRng,
+) -> Vec<(u64, f64)> {
+    let mut y = Vec::with_capacity(x.len());
+    for &(id, w) in x {
+        let keep = uniform01(rng) >= drop;
+        let factor = 1.0 + spread * (2.0 * uniform01(rng) - 1.0);
+        if keep {
+            y.push((id, w * factor));
+        }
+    }
+    y
+}
+
+/// Accepted error of an estimate of the probability `p` from `k` samples.
+pub(crate) fn estimate_tolerance(p: f64, k: u64) -> f64 {
+    let k = k as f64;
+    (3.2 * (p * (1.0 - p) / k).sqrt()).max(1.25 / k.sqrt())
+}
+
+/// Assert that `est` is within `estimate_tolerance(truth, k)` of `truth`.
+#[track_caller]
+pub(crate) fn assert_estimate_close(truth: f64, est: f64, k: u64, context: impl Display) {
+    let err = (truth - est).abs();
+    let tol = estimate_tolerance(truth, k);
+    assert!(
+        err <= tol,
+        "{context}: true={truth:.6}, est={est:.6}, err={err:.6}, tol={tol:.6}"
+    );
+}
+
+fn weighted_set(
+    l0: u64,
+    l1: f64,
+    rng: &mut MtRng,
+    mut draw_id: impl FnMut(&mut MtRng) -> u64,
+) -> Vec<(u64, f64)> {
+    let mut elements = HashSet::with_capacity(l0 as usize);
+    while elements.len() < l0 as usize {
+        elements.insert(draw_id(rng));
+    }
+
+    let mut z: Vec<f64> = (0..(l0 - 1)).map(|_| uniform01(rng)).collect();
+    z.push(1.0);
+    z.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let mut prev = 0.0;
+    let mut out = Vec::with_capacity(l0 as usize);
+    let mut ids: Vec<u64> = elements.into_iter().collect();
+    ids.sort_unstable();
+    for (j, idx) in ids.into_iter().enumerate() {
+        out.push((idx, l1 * (z[j] - prev)));
+        prev = z[j];
+    }
+    out
+}
+
+fn similar_set(
+    x: &[(u64, f64)],
+    relative_overlap: f64,
+    rng: &mut MtRng,
+    mut draw_id: impl FnMut(&mut MtRng) -> u64,
+) -> Vec<(u64, f64)> {
+    let free_id = loop {
+        let candidate = draw_id(rng);
+        if x.binary_search_by_key(&candidate, |p| p.0).is_err() {
+            break candidate;
+        }
+    };
+
+    let mut excess = 0.0;
+    let mut y = Vec::with_capacity(x.len() + 1);
+    for &(id, w) in x {
+        let w_scaled = w * relative_overlap;
+        excess += w - w_scaled;
+        y.push((id, w_scaled));
+    }
+    y.push((free_id, excess));
+    y.sort_by_key(|p| p.0);
+    y
+}
@@ -4,30 +4,22 @@
 //! `weighted_minwise_hashing.hpp`, adapted to the API style used by this crate:
 //! sparse weighted vectors are `&[(u64, f64)]`, and sketches are `Vec<(u64, f64)>`.
 //!
-//! Randomness is provided by tabulation hashing. By default this uses simple
-//! tabulation; with the `mixed_tab` feature it uses mixed tabulation. Each
+//! Randomness is provided by the `HashFamily` of the sketcher. By default this
+//! is simple tabulation; with the `mixed_tab` feature it is mixed tabulation,
+//! and `with_family` selects any family at runtime. Each
 //! logical random stream is addressed by `(feature_id, stream_id)` and then
 //! expanded with a counter.  This keeps sketching stateless and deterministic.
 
-use std::f64::INFINITY;
+use std::io;
 
-use crate::hash_utils::*;
-use crate::rng_utils::MtRng;
-
-#[cfg(feature = "mixed_tab")]
-type Tab64Tree = tab_hash::Tab64Mixed;
-#[cfg(not(feature = "mixed_tab"))]
-type Tab64Tree = tab_hash::Tab64Simple;
+use rand_core::RngCore;
 
-#[cfg(feature = "mixed_tab")]
-fn tab64_tree_from_rng(rng: &mut MtRng) -> Tab64Tree {
-    mixed_tab64_from_rng(rng)
-}
-
-#[cfg(not(feature = "mixed_tab"))]
-fn tab64_tree_from_rng(rng: &mut MtRng) -> Tab64Tree {
-    tab64_from_rng(rng)
-}
+use crate::hash_utils::*;
+use crate::persist::{
+    SketcherState, StateReader, StateWriter, TableState, invalid, read_family, write_family,
+};
+use crate::rng_utils::{MtRng, mt_from_seed_full};
+use crate::scratch::{Scratch, reset_sketch};
 
 /// Same sketch representation as DartMinHash: k slots of `(fingerprint, rank)`.
 ///
@@ -37,10 +29,10 @@ fn tab64_tree_from_rng(rng: &mut MtRng) -> Tab64Tree {
 pub type MinHashSketch = Vec<(u64, f64)>;
 
 #[derive(Clone, Copy, Debug)]
-struct Node {
-    lower_bound: f64,
-    inv_rate: f64,
-    ratio: f64,
+pub(crate) struct Node {
+    pub(crate) lower_bound: f64,
+    pub(crate) inv_rate: f64,
+    pub(crate) ratio: f64,
 }
 
 impl Node {
@@ -61,7 +53,7 @@ impl Node {
 /// Leaves correspond to exponentially spaced weight intervals. Internal nodes
 /// store the lower bound of their interval and the rate parameters needed for
 /// exponential thinning.
-fn pre_calculate_tree(factor: f64, max: f64) -> Vec<Node> {
+pub(crate) fn pre_calculate_tree(factor: f64, max: f64) -> Vec<Node> {
     assert!(
         max > 0.0 && max.is_finite() || max == f64::MAX,
         "max must be positive"
@@ -159,31 +151,42 @@ fn next_toward_zero(x: f64) -> f64 {
 ///
 /// The C++ reference uses a bit-stream RNG seeded by `(id, stream_id)`.  Here we
 /// generate each 64-bit word by tab-hashing a mixed key derived from
-/// `(id, stream_id, counter)`.  All hashers are tabulation tables seeded in
-/// `TreeMinHash::new_mt`.
+/// `(id, stream_id, counter)`.  Both hashers come from the sketcher's
+/// `HashFamily`, tabulation tables by default.
 #[derive(Clone)]
-struct TabStream<'a> {
-    h0: &'a Tab64Tree,
-    h1: &'a Tab64Tree,
+pub(crate) struct TabStream<'a, H: Hash64> {
+    h0: &'a H,
+    h1: &'a H,
     id: u64,
     stream_id: u64,
     counter: u64,
 }
 
-impl<'a> TabStream<'a> {
+impl<'a, H: Hash64> TabStream<'a, H> {
     #[inline]
-    fn new(h0: &'a Tab64Tree, h1: &'a Tab64Tree, id: u64, stream_id: u64) -> Self {
+    pub(crate) fn new(h0: &'a H, h1: &'a H, id: u64, stream_id: u64) -> Self {
+        Self::resume(h0, h1, id, stream_id, 0)
+    }
+
+    /// Re-open a stream at a counter previously returned by `counter()`.
+    #[inline]
+    pub(crate) fn resume(h0: &'a H, h1: &'a H, id: u64, stream_id: u64, counter: u64) -> Self {
         Self {
             h0,
             h1,
             id,
             stream_id,
-            counter: 0,
+            counter,
         }
     }
 
     #[inline]
-    fn next_u64(&mut self) -> u64 {
+    pub(crate) fn counter(&self) -> u64 {
+        self.counter
+    }
+
+    #[inline]
+    pub(crate) fn next_u64(&mut self) -> u64 {
         let c = self.counter;
         self.counter = self.counter.wrapping_add(1);
 
@@ -194,7 +197,7 @@ impl<'a> TabStream<'a> {
     }
 
     #[inline]
-    fn uniform_open01(&mut self) -> f64 {
+    pub(crate) fn uniform_open01(&mut self) -> f64 {
         // Use the top 53 bits. Add 0.5 so the result is strictly inside (0, 1).
         const DEN: f64 = (1u64 << 53) as f64;
         let v = self.next_u64() >> 11;
@@ -202,12 +205,12 @@ impl<'a> TabStream<'a> {
     }
 
     #[inline]
-    fn exponential1(&mut self) -> f64 {
+    pub(crate) fn exponential1(&mut self) -> f64 {
         -self.uniform_open01().ln()
     }
 
     #[inline]
-    fn bernoulli(&mut self, p: f64) -> bool {
+    pub(crate) fn bernoulli(&mut self, p: f64) -> bool {
         if p <= 0.0 {
             false
         } else if p >= 1.0 {
@@ -218,7 +221,7 @@ impl<'a> TabStream<'a> {
     }
 
     #[inline]
-    fn uniform_index(&mut self, upper_exclusive: u32) -> u32 {
+    pub(crate) fn uniform_index(&mut self, upper_exclusive: u32) -> u32 {
         debug_assert!(upper_exclusive > 0);
         // Rejection sampling avoids modulo bias. For unit tests and sketches this
         // branch almost always exits on the first iteration.
@@ -237,51 +240,74 @@ impl<'a> TabStream<'a> {
 /// This is the standard partial Fisher-Yates shuffle: the kth call samples one
 /// still-unused index uniformly.  It matches the role of `PermutationStream` in
 /// the C++ reference implementation.
-#[derive(Clone, Debug)]
-struct PermutationStream {
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PermutationStream {
     permutation: Vec<u32>,
+    // swap targets since the last reset, so reset only undoes what was touched
+    touched: Vec<u32>,
     pos: u32,
 }
 
 impl PermutationStream {
-    fn new(m: u32) -> Self {
-        let permutation = (0..m).collect();
-        Self {
-            permutation,
-            pos: 0,
+    /// Reset to the identity over `m` elements, reusing the allocation.
+    pub(crate) fn reset_to(&mut self, m: u32) {
+        if self.permutation.len() == m as usize {
+            self.reset();
+        } else {
+            self.permutation.clear();
+            self.permutation.extend(0..m);
+            self.touched.clear();
+            self.pos = 0;
         }
     }
 
     #[inline]
-    fn reset(&mut self) {
-        self.pos = 0;
-        // Restore only the prefix that may have been modified. Simpler and less
-        // bug-prone than keeping a touched list; O(m) is acceptable because reset
-        // happens only when a leaf interval produces candidates.
-        for (i, v) in self.permutation.iter_mut().enumerate() {
-            *v = i as u32;
+    pub(crate) fn reset(&mut self) {
+        // Only the consumed prefix and the swap targets can differ from the
+        // identity, so restoring them costs O(pos) instead of O(m). This matters
+        // for ProbMinHash, which resets once per input feature.
+        for i in 0..self.pos {
+            self.permutation[i as usize] = i;
         }
+        for &j in &self.touched {
+            self.permutation[j as usize] = j;
+        }
+        self.touched.clear();
+        self.pos = 0;
     }
 
     #[inline]
-    fn next(&mut self, rng: &mut TabStream<'_>) -> u32 {
+    pub(crate) fn next<H: Hash64>(&mut self, rng: &mut TabStream<'_, H>) -> u32 {
         let n = self.permutation.len() as u32;
         debug_assert!(self.pos < n);
         let j = self.pos + rng.uniform_index(n - self.pos);
         self.permutation.swap(self.pos as usize, j as usize);
+        self.touched.push(j);
         let out = self.permutation[self.pos as usize];
         self.pos += 1;
         out
     }
 }
 
+/// Per-sketch work counters of `TreeMinHash`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TreeMinHashStats {
+    /// Times the limit was raised because some slot was still empty after a
+    /// pass over the input (0 if the first pass filled all k slots).
+    pub limit_increases: u32,
+    /// Tree nodes whose point was below the limit and got expanded, summed
+    /// over all features and passes.
+    pub nodes_visited: u64,
+}
+
 /// TreeMinHash sketcher for weighted Jaccard similarity.
-pub struct TreeMinHash {
+pub struct TreeMinHash<F: HashFamily = DefaultFamily> {
     k: u32,
-    h0: Tab64Tree,
-    h1: Tab64Tree,
-    sample_id_hasher: Tab64Tree,
-    sample_point_hasher: Tab64Tree,
+    family: F,
+    h0: F::H64,
+    h1: F::H64,
+    sample_id_hasher: F::H64,
+    sample_point_hasher: F::H64,
     tree: Vec<Node>,
     num_non_leaf_nodes: u32,
     initial_limit_factor: f64,
@@ -289,26 +315,72 @@ pub struct TreeMinHash {
 }
 
 impl TreeMinHash {
-    /// Build with MT19937-seeded tabulation hash tables.
+    /// Build with tabulation hash tables drawn from `rng`.
     ///
     /// Defaults match the C++ constructor: `max = f64::MAX`, `factor = 0.5`,
     /// and first-run success probability `0.9`.
+    pub fn new<R: RngCore + ?Sized>(rng: &mut R, k: u64) -> Self {
+        Self::with_params(rng, k, f64::MAX, 0.5, 0.9)
+    }
+
+    /// [`Self::new`] with an [`MtRng`].
     pub fn new_mt(rng: &mut MtRng, k: u64) -> Self {
-        Self::with_params_mt(rng, k, f64::MAX, 0.5, 0.9)
+        Self::new(rng, k)
     }
 
+    /// Like `new`, with the tree hashes drawn from `mt_from_seed_full(seed)`.
+    pub fn from_seed(seed: u64, k: u64) -> Self {
+        Self::new(&mut mt_from_seed_full(seed), k)
+    }
+
+    pub fn with_params<R: RngCore + ?Sized>(
+        rng: &mut R,
+        k: u64,
+        max: f64,
+        factor: f64,
+        success_probability_first_run: f64,
+    ) -> Self {
+        Self::with_params_and_family(
+            rng,
+            k,
+            max,
+            factor,
+            success_probability_first_run,
+            DefaultFamily::default(),
+        )
+    }
+
+    /// [`Self::with_params`] with an [`MtRng`].
     pub fn with_params_mt(
         rng: &mut MtRng,
         k: u64,
         max: f64,
         factor: f64,
         success_probability_first_run: f64,
+    ) -> Self {
+        Self::with_params(rng, k, max, factor, success_probability_first_run)
+    }
+}
+
+impl<F: HashFamily> TreeMinHash<F> {
+    /// Like `new`, drawing the hash functions from `family`.
+    pub fn with_family<R: RngCore + ?Sized>(rng: &mut R, k: u64, family: F) -> Self {
+        Self::with_params_and_family(rng, k, f64::MAX, 0.5, 0.9, family)
+    }
+
+    pub fn with_params_and_family<R: RngCore + ?Sized>(
+        rng: &mut R,
+        k: u64,
+        max: f64,
+        factor: f64,
+        success_probability_first_run: f64,
+        family: F,
     ) -> Self {
         assert!(k > 0 && k <= (u32::MAX as u64), "k must fit into u32");
         assert!(success_probability_first_run > 0.0 && success_probability_first_run < 1.0);
 
         let tree = pre_calculate_tree(factor, max);
-        let num_non_leaf_nodes = (tree.len() - (tree.len() + 1) / 2) as u32;
+        let num_non_leaf_nodes = (tree.len() - tree.len().div_ceil(2)) as u32;
         let k_f = k as f64;
         // Equivalent to the C++ expression:
         // -log(-expm1(log(p) / m)) * m
@@ -320,13 +392,14 @@ impl TreeMinHash {
             factors.push(k_f / (k_f - (i as f64) - 1.0));
         }
 
-        let h0 = tab64_tree_from_rng(rng);
-        let h1 = tab64_tree_from_rng(rng);
-        let sample_id_hasher = tab64_tree_from_rng(rng);
-        let sample_point_hasher = tab64_tree_from_rng(rng);
+        let h0 = family.hash64_from_rng(rng);
+        let h1 = family.hash64_from_rng(rng);
+        let sample_id_hasher = family.hash64_from_rng(rng);
+        let sample_point_hasher = family.hash64_from_rng(rng);
 
         Self {
             k: k as u32,
+            family,
             h0,
             h1,
             sample_id_hasher,
@@ -338,6 +411,16 @@ impl TreeMinHash {
         }
     }
 
+    #[inline]
+    pub fn hash_family(&self) -> HashKind {
+        self.family.kind()
+    }
+
+    #[inline]
+    pub fn k(&self) -> u64 {
+        self.k as u64
+    }
+
     /// Return k weighted MinHash slots.
     ///
     /// The first component is a fingerprint of the accepted weighted sample
@@ -346,20 +429,55 @@ impl TreeMinHash {
     /// MinHash/Hamming sketches. The rank component is kept for debugging and
     /// compatibility.
     pub fn sketch(&self, x: &[(u64, f64)]) -> MinHashSketch {
+        let mut result = Vec::with_capacity(self.k as usize);
+        self.sketch_into(x, &mut Scratch::new(), &mut result);
+        result
+    }
+
+    /// Like `sketch`, tagged with the hash family.
+    pub fn sketch_tagged(&self, x: &[(u64, f64)]) -> FamilySketch {
+        FamilySketch {
+            hash_family: self.hash_family(),
+            minhashes: self.sketch(x),
+        }
+    }
+
+    /// Like `sketch`, also reporting how much work the sketch needed.
+    pub fn sketch_with_stats(&self, x: &[(u64, f64)]) -> (MinHashSketch, TreeMinHashStats) {
+        let mut result = Vec::with_capacity(self.k as usize);
+        let stats = self.sketch_into(x, &mut Scratch::new(), &mut result);
+        (result, stats)
+    }
+
+    /// Like `sketch_with_stats`, writing into `result` and reusing the
+    /// buffers of `scratch`, see [`crate::scratch`].
+    pub fn sketch_into(
+        &self,
+        x: &[(u64, f64)],
+        scratch: &mut Scratch,
+        result: &mut MinHashSketch,
+    ) -> TreeMinHashStats {
+        reset_sketch(result, self.k as usize);
+        let mut stats = TreeMinHashStats::default();
         let weight_sum: f64 = x.iter().filter(|(_, w)| *w > 0.0).map(|(_, w)| *w).sum();
-        if !(weight_sum > 0.0) || !weight_sum.is_finite() {
-            return vec![(0, INFINITY); self.k as usize];
+        if !(weight_sum > 0.0 && weight_sum.is_finite()) {
+            return stats;
         }
 
         let limit_increment = self.initial_limit_factor / weight_sum;
         let mut limit = limit_increment;
-        let mut result = vec![(0u64, limit); self.k as usize];
-        let mut buffer: Vec<(f64, u32)> = Vec::with_capacity(self.num_non_leaf_nodes as usize);
-        let mut permutation_stream = PermutationStream::new(self.k);
+        for slot in result.iter_mut() {
+            slot.1 = limit;
+        }
+        let buffer = &mut scratch.nodes;
+        buffer.clear();
+        buffer.reserve(self.num_non_leaf_nodes as usize);
+        let permutation_stream = &mut scratch.permutation;
+        permutation_stream.reset_to(self.k);
 
         loop {
             for &(id, w) in x {
-                if !(w > 0.0) || !w.is_finite() {
+                if !(w > 0.0 && w.is_finite()) {
                     continue;
                 }
 
@@ -367,9 +485,10 @@ impl TreeMinHash {
                 let mut node_idx = 0u32;
                 let mut rng = self.rng(id, node_idx as u64);
                 let mut point = rng.exponential1() * self.tree[node_idx as usize].inv_rate;
-                if !(point < limit) {
+                if point >= limit || point.is_nan() {
                     continue;
                 }
+                stats.nodes_visited += 1;
 
                 loop {
                     while node_idx < self.num_non_leaf_nodes
@@ -381,6 +500,7 @@ impl TreeMinHash {
                         node_idx <<= 1;
                         let sibling_idx = node_idx + 1 + (inherit_to_left as u32);
                         node_idx += 2 - (inherit_to_left as u32);
+                        stats.nodes_visited += 1;
 
                         let sibling_node = self.tree[sibling_idx as usize];
                         let sibling_point = point + rng.exponential1() * sibling_node.inv_rate;
@@ -399,12 +519,13 @@ impl TreeMinHash {
                             let next_point = if (kk as usize) < self.factors.len() {
                                 point + rng.exponential1() * inv_rate * self.factors[kk as usize]
                             } else {
-                                INFINITY
+                                f64::INFINITY
                             };
 
                             let idx = permutation_stream.next(&mut rng) as usize;
                             if point < result[idx].1 {
-                                if !(acceptance_probability < 1.0) {
+                                if acceptance_probability >= 1.0 || acceptance_probability.is_nan()
+                                {
                                     result[idx] = (self.sample_fingerprint(id, point), point);
                                 } else {
                                     let stream_id = ((node_idx as u64) << 32) | (idx as u64);
@@ -420,7 +541,7 @@ impl TreeMinHash {
                                 }
                             }
 
-                            if !(next_point < limit) {
+                            if next_point >= limit || next_point.is_nan() {
                                 break;
                             }
                             point = next_point;
@@ -431,6 +552,7 @@ impl TreeMinHash {
                         point = p;
                         node_idx = ni;
                         rng = self.rng(id, node_idx as u64);
+                        stats.nodes_visited += 1;
                     } else {
                         break;
                     }
@@ -438,12 +560,13 @@ impl TreeMinHash {
             }
 
             if result.iter().all(|&(_, r)| r != limit) {
-                return result;
+                return stats;
             }
 
             let old_limit = limit;
             limit += limit_increment;
-            for slot in &mut result {
+            stats.limit_increases += 1;
+            for slot in result.iter_mut() {
                 if slot.1 == old_limit {
                     slot.1 = limit;
                 }
@@ -466,11 +589,92 @@ impl TreeMinHash {
     }
 
     #[inline]
-    fn rng(&self, id: u64, stream_id: u64) -> TabStream<'_> {
+    fn rng(&self, id: u64, stream_id: u64) -> TabStream<'_, F::H64> {
         TabStream::new(&self.h0, &self.h1, id, stream_id)
     }
 }
 
+/// Hash tables, tree and limit factors. The tree and factors are stored
+/// rather than recomputed, since they come from `ln` and `exp`.
+impl<F: HashFamily> SketcherState for TreeMinHash<F> {
+    const TAG: u8 = 3;
+
+    fn write_body(&self, w: &mut StateWriter) {
+        write_family(w, self.family.kind());
+        w.put_u32(self.k);
+        for h in [
+            &self.h0,
+            &self.h1,
+            &self.sample_id_hasher,
+            &self.sample_point_hasher,
+        ] {
+            h.write_state(w);
+        }
+        w.put_len(self.tree.len());
+        for node in &self.tree {
+            w.put_f64(node.lower_bound);
+            w.put_f64(node.inv_rate);
+            w.put_f64(node.ratio);
+        }
+        w.put_u32(self.num_non_leaf_nodes);
+        w.put_f64(self.initial_limit_factor);
+        w.put_f64s(&self.factors);
+    }
+
+    fn read_body(r: &mut StateReader) -> io::Result<Self> {
+        let family = read_family::<F>(r)?;
+        let k = r.get_u32()?;
+        let h0 = F::H64::read_state(r)?;
+        let h1 = F::H64::read_state(r)?;
+        let sample_id_hasher = F::H64::read_state(r)?;
+        let sample_point_hasher = F::H64::read_state(r)?;
+        let n = r.get_len(24)?;
+        let tree = (0..n)
+            .map(|_| {
+                Ok(Node {
+                    lower_bound: r.get_f64()?,
+                    inv_rate: r.get_f64()?,
+                    ratio: r.get_f64()?,
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let num_non_leaf_nodes = r.get_u32()?;
+        let initial_limit_factor = r.get_f64()?;
+        let factors = r.get_f64s()?;
+        // A complete tree has an odd number of nodes, so every non-leaf node
+        // has both children.
+        if k == 0
+            || tree.len() % 2 != 1
+            || num_non_leaf_nodes as usize != tree.len() / 2
+            || factors.len() != (k - 1) as usize
+        {
+            return Err(invalid("TreeMinHash: inconsistent k, tree or factors"));
+        }
+        // Non-positive rates or limit steps would never advance the points.
+        let valid = tree
+            .iter()
+            .all(|n| n.lower_bound >= 0.0 && n.inv_rate > 0.0)
+            && initial_limit_factor > 0.0
+            && initial_limit_factor.is_finite()
+            && factors.iter().all(|&f| f > 0.0 && f.is_finite());
+        if !valid {
+            return Err(invalid("TreeMinHash: invalid tree rates or limit factors"));
+        }
+        Ok(Self {
+            k,
+            family,
+            h0,
+            h1,
+            sample_id_hasher,
+            sample_point_hasher,
+            tree,
+            num_non_leaf_nodes,
+            initial_limit_factor,
+            factors,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::HashSet;
@@ -481,7 +685,7 @@ mod tests {
         dartminhash::DartMinHash,
         rng_utils::{MtRng, mt_from_seed},
         similarity::{jaccard_estimate_from_minhashes, jaccard_similarity},
-        treeminhash::TreeMinHash,
+        treeminhash::{TreeMinHash, TreeMinHashStats},
     };
 
     /// Uniform(0,1) using the same MT19937 rng as the existing DartMinHash tests.
@@ -492,6 +696,7 @@ mod tests {
     /// Generate a random weighted set:
     /// Pick L0 distinct random indices, draw L0-1 splitters, sort them, and use
     /// the gaps times L1 as weights. Returns sorted by id.
+    #[allow(clippy::explicit_counter_loop)]
     fn generate_weighted_set(l0: u64, l1: f64, rng: &mut MtRng) -> Vec<(u64, f64)> {
         let mut elements = HashSet::with_capacity(l0 as usize);
         while elements.len() < l0 as usize {
@@ -707,6 +912,34 @@ mod tests {
         assert!(sk.iter().all(|&(id, rank)| id == 0 && rank.is_infinite()));
     }
 
+    #[test]
+    fn treeminhash_stats_count_limit_increases() {
+        let mut data_rng = mt_from_seed(404);
+        let mut rng = mt_from_seed(0x7eee_0044);
+        let k = 64;
+        let tmh = TreeMinHash::new_mt(&mut rng, k);
+
+        // The initial limit fills all k slots in the first pass with
+        // probability 0.9, independent of the input.
+        let runs = 400;
+        let mut first_pass = 0;
+        for _ in 0..runs {
+            let x = generate_weighted_set(50, 20.0, &mut data_rng);
+            let (sk, stats) = tmh.sketch_with_stats(&x);
+            assert_eq!(sk, tmh.sketch(&x));
+            assert!(stats.nodes_visited >= x.len() as u64, "{stats:?}");
+            if stats.limit_increases == 0 {
+                first_pass += 1;
+            }
+        }
+        let rate = first_pass as f64 / runs as f64;
+        let sd = (0.9 * 0.1 / runs as f64).sqrt();
+        assert!((rate - 0.9).abs() <= 4.0 * sd, "first-pass rate {rate}");
+
+        let (_, empty) = tmh.sketch_with_stats(&[]);
+        assert_eq!(empty, TreeMinHashStats::default());
+    }
+
     #[test]
     fn treeminhash_distinguishes_shared_id_different_weight_samples() {
         let mut rng = mt_from_seed(19);
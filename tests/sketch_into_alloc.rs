@@ -0,0 +1,232 @@
+//! `sketch_into` must match `sketch` and perform no heap allocations once
+//! its `Scratch` is warm. Allocations are counted by a global allocator, so
+//! this runs as its own test binary rather than inside the library tests.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::collections::HashSet;
+
+use rand_core::RngCore;
+
+use dartminhash::{
+    BagMinHash, Cws, CwsVariant, DartMinHash, Densification, ErsWmh, FastGm, GrowableErs,
+    ProbMinHash, Scratch, SimHash, SuperMinHash, TreeMinHash,
+    rng_utils::{MtRng, mt_from_seed},
+};
+
+/// Counts the allocations of the current thread, so tests running in
+/// parallel do not disturb each other.
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|a| a.set(a.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.with(|a| a.set(a.get() + 1));
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn allocations_during(f: impl FnOnce()) -> u64 {
+    let before = ALLOCATIONS.with(|a| a.get());
+    f();
+    ALLOCATIONS.with(|a| a.get()) - before
+}
+
+fn uniform01(rng: &mut MtRng) -> f64 {
+    mt19937::gen_res53(rng)
+}
+
+/// Random weighted set: L0 random ids in [0, d), weights are the gaps of
+/// L0-1 sorted uniforms times L1. Returns sorted by id.
+fn generate_weighted_set(d: u64, l0: u64, l1: f64, rng: &mut MtRng) -> Vec<(u64, f64)> {
+    let mut elements = HashSet::with_capacity(l0 as usize);
+    while elements.len() < l0 as usize {
+        elements.insert(rng.next_u64() % d);
+    }
+
+    let mut z: Vec<f64> = (0..(l0 - 1)).map(|_| uniform01(rng)).collect();
+    z.push(1.0);
+    z.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let mut prev = 0.0;
+    let mut out = Vec::with_capacity(l0 as usize);
+    let mut ids: Vec<u64> = elements.into_iter().collect();
+    ids.sort_unstable();
+    for (j, idx) in ids.into_iter().enumerate() {
+        out.push((idx, l1 * (z[j] - prev)));
+        prev = z[j];
+    }
+    out
+}
+
+/// `sketch_into` must match `sketch` and, once warmed up on `x` and `y`,
+/// must not allocate for either of them again.
+fn check_into<F, G>(name: &str, x: &[(u64, f64)], y: &[(u64, f64)], sketch: F, into: G)
+where
+    F: Fn(&[(u64, f64)]) -> Vec<(u64, f64)>,
+    G: Fn(&[(u64, f64)], &mut Scratch, &mut Vec<(u64, f64)>),
+{
+    let mut scratch = Scratch::new();
+    let mut out = Vec::new();
+    for v in [x, y] {
+        into(v, &mut scratch, &mut out);
+        assert_eq!(out, sketch(v), "{name}: sketch_into differs from sketch");
+    }
+
+    for v in [x, y] {
+        let n = allocations_during(|| into(v, &mut scratch, &mut out));
+        assert_eq!(n, 0, "{name}: {n} allocations in steady state");
+        assert_eq!(out, sketch(v), "{name}: sketch_into differs from sketch");
+    }
+}
+
+#[test]
+fn sketch_into_reuses_scratch_without_allocating() {
+    let mut data_rng = mt_from_seed(2024);
+    let mut hash_rng = mt_from_seed(0x5c7a_0001);
+    let k = 128;
+    let d = 4_096u64;
+
+    let x = generate_weighted_set(d, 1_000, 50.0, &mut data_rng);
+    let y = generate_weighted_set(d, 200, 5.0, &mut data_rng);
+    let caps: Vec<f64> = {
+        let mut caps = vec![0.0; d as usize];
+        for &(id, w) in x.iter().chain(&y) {
+            caps[id as usize] = f64::max(caps[id as usize], w);
+        }
+        caps
+    };
+    let sparse_caps: Vec<(u64, f64)> = caps
+        .iter()
+        .enumerate()
+        .filter(|&(_, &m)| m > 0.0)
+        .map(|(id, &m)| (id as u64, m))
+        .collect();
+
+    let dmh = DartMinHash::new_mt(&mut hash_rng, k);
+    check_into(
+        "DartMinHash",
+        &x,
+        &y,
+        |v| dmh.sketch(v),
+        |v, s, o| {
+            dmh.sketch_into(v, s, o);
+        },
+    );
+    let tmh = TreeMinHash::new_mt(&mut hash_rng, k);
+    check_into(
+        "TreeMinHash",
+        &x,
+        &y,
+        |v| tmh.sketch(v),
+        |v, s, o| {
+            tmh.sketch_into(v, s, o);
+        },
+    );
+    let bmh = BagMinHash::new(&mut hash_rng, k);
+    check_into(
+        "BagMinHash",
+        &x,
+        &y,
+        |v| bmh.sketch(v),
+        |v, s, o| bmh.sketch_into(v, s, o),
+    );
+    let pmh = ProbMinHash::new(&mut hash_rng, k);
+    check_into(
+        "ProbMinHash",
+        &x,
+        &y,
+        |v| pmh.sketch(v),
+        |v, s, o| pmh.sketch_into(v, s, o),
+    );
+    let gm = FastGm::new(&mut hash_rng, k);
+    check_into(
+        "FastGm",
+        &x,
+        &y,
+        |v| gm.sketch(v),
+        |v, s, o| gm.sketch_into(v, s, o),
+    );
+    let smh = SuperMinHash::new(&mut hash_rng, k);
+    check_into(
+        "SuperMinHash",
+        &x,
+        &y,
+        |v| smh.sketch_support(v),
+        |v, s, o| smh.sketch_support_into(v, s, o),
+    );
+    for variant in [CwsVariant::Icws, CwsVariant::ZeroBit, CwsVariant::Pcws] {
+        let cws = Cws::new(&mut hash_rng, k, variant);
+        check_into(
+            &format!("{variant:?}"),
+            &x,
+            &y,
+            |v| cws.sketch(v),
+            |v, s, o| cws.sketch_into(v, s, o),
+        );
+    }
+
+    let ers = ErsWmh::new_mt(&mut hash_rng, &caps, k);
+    check_into(
+        "ErsWmh",
+        &x,
+        &y,
+        |v| ers.sketch(v, None),
+        |v, s, o| {
+            ers.sketch_into(v, None, s, o);
+        },
+    );
+    check_into(
+        "ErsWmh RS",
+        &x,
+        &y,
+        |v| ers.sketch_rs(v),
+        |v, s, o| {
+            ers.sketch_rs_into(v, s, o);
+        },
+    );
+    let sparse_ers = ErsWmh::new_sparse(&mut hash_rng, &sparse_caps, k)
+        .with_densification(Densification::Optimal);
+    check_into(
+        "sparse ErsWmh",
+        &x,
+        &y,
+        |v| sparse_ers.sketch_auto(v, 1e-3),
+        |v, s, o| {
+            sparse_ers.sketch_auto_into(v, 1e-3, s, o);
+        },
+    );
+    let grow = GrowableErs::new_sparse(&mut hash_rng, &sparse_caps, k);
+    check_into(
+        "GrowableErs",
+        &x,
+        &y,
+        |v| grow.sketch(v),
+        |v, s, o| grow.sketch_into(v, s, o),
+    );
+
+    let sh = SimHash::new(&mut hash_rng, 256);
+    let mut scratch = Scratch::new();
+    let mut bits = Vec::new();
+    sh.sketch_into(&x, &mut scratch, &mut bits);
+    assert_eq!(bits, sh.sketch(&x));
+    // SimHash buffers only depend on n_bits, so any input is warm.
+    let n = allocations_during(|| sh.sketch_into(&y, &mut scratch, &mut bits));
+    assert_eq!(n, 0, "SimHash: {n} allocations in steady state");
+    assert_eq!(bits, sh.sketch(&y));
+}